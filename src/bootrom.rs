@@ -0,0 +1,52 @@
+use crate::constants::csrs;
+use crate::ins::*;
+
+/*
+ * Boot ROM, laid out like the reset vector of QEMU virt
+ *
+ *   0x00 auipc t0, 0
+ *   0x04 addi  a2, t0, 40     a2 = &fw_dynamic_info
+ *   0x08 csrr  a0, mhartid
+ *   0x0c lw    a1, 32(t0)     a1 = DTB address
+ *   0x10 lw    t0, 24(t0)
+ *   0x14 jr    t0             jump to the payload
+ *   0x18 start address (64-bit)
+ *   0x20 DTB address (64-bit)
+ *   0x28 fw_dynamic_info for OpenSBI
+ */
+
+const FW_DYNAMIC_INFO_MAGIC: u32 = 0x4942_534f; // "OSBI"
+const FW_DYNAMIC_INFO_VERSION: u32 = 2;
+const FW_DYNAMIC_INFO_NEXT_MODE_S: u32 = 1;
+
+const T0: u32 = 5;
+const A0: u32 = 10;
+const A1: u32 = 11;
+const A2: u32 = 12;
+
+/*
+ * next_addr is where OpenSBI continues after it is done, 0 when there is
+ * nothing to hand over to.
+ */
+pub fn boot_rom(start: u32, fdt: u32, next_addr: u32) -> Vec<u8> {
+    let words = [
+        auipc(T0, 0),
+        addi(A2, T0, 40),
+        csrr(A0, csrs::MHARTID as u32),
+        lw(A1, 32, T0),
+        lw(T0, 24, T0),
+        jalr(0, T0, 0),
+        start,
+        0,
+        fdt,
+        0,
+        // fw_dynamic_info: magic, version, next_addr, next_mode, options, boot_hart
+        FW_DYNAMIC_INFO_MAGIC,
+        FW_DYNAMIC_INFO_VERSION,
+        next_addr,
+        FW_DYNAMIC_INFO_NEXT_MODE_S,
+        0,
+        0
+    ];
+    return words.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect();
+}
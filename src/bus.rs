@@ -0,0 +1,248 @@
+use std::fmt;
+
+use crate::fdt::Node;
+use crate::fdt::PLIC_PHANDLE;
+use crate::memory::Memory;
+use crate::memory::R;
+use crate::memory::W;
+use crate::memory::X;
+
+/*
+ * System bus
+ *
+ * Every physical access from the core goes through the bus, which routes it
+ * by address range to a memory mapped device or to memory, which holds RAM
+ * and ROM. Device ranges are checked first so that a device can be mapped
+ * on top of RAM.
+ */
+
+/*
+ * Memory mapped peripheral.
+ *
+ * Offsets are relative to the base address the device was registered at and
+ * size is the access width in bytes (1, 2 or 4).
+ */
+pub trait Device {
+    fn read(&mut self, offset: u32, size: u32) -> u32;
+    fn write(&mut self, offset: u32, size: u32, value: u32);
+
+    // called once for every instruction retired
+    fn tick(&mut self) {}
+
+    // level of the device's interrupt line
+    fn interrupt(&self) -> bool { false }
+
+    // interrupt controllers are told the level of every line on the bus
+    fn update_irq_lines(&mut self, _lines: u64) {}
+
+    // bus masters get access to guest RAM after every register write and tick
+    fn dma(&mut self, _ram: &mut Memory) {}
+
+    // the machine is stopping, flush anything the host should keep
+    fn stop(&mut self) {}
+
+    // machine wide requests, collected by the bus on every tick
+    fn event(&mut self) -> Option<Event> { None }
+
+    // back to the power-on state
+    fn reset(&mut self) {}
+
+    // node describing the device mapped at [base, base+size)
+    fn device_tree(&self, _base: u32, _size: u32) -> Option<Node> { None }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Event {
+    Poweroff(i32),
+    Reset
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BusError {
+    Unmapped(u32),
+    ReadOnly(u32),
+    NotReadable(u32),
+    NotExecutable(u32)
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BusError::Unmapped(addr) => write!(f, "Unmapped address {:#010x}", addr),
+            BusError::ReadOnly(addr) => write!(f, "Write to read-only address {:#010x}", addr),
+            BusError::NotReadable(addr) => write!(f, "Read from execute-only address {:#010x}", addr),
+            BusError::NotExecutable(addr) => write!(f, "Execute from non-executable address {:#010x}", addr)
+        }
+    }
+}
+
+struct Mapping {
+    base: u32,
+    size: u32,
+    irq: u32,
+    device: Box<dyn Device>
+}
+
+pub struct Bus {
+    pub memory: Memory,
+    ram_base: u32,
+    ram_size: usize,
+    devices: Vec<Mapping>,
+    intc: Option<usize>,
+    irq_lines: u64,
+    event: Option<Event>
+}
+
+impl Bus {
+    pub fn new(ram_base: u32, ram_size: usize) -> Bus {
+        let mut memory = Memory::new();
+        memory.map(ram_base, ram_size as u64, R | W | X);
+        return Bus {
+            memory,
+            ram_base,
+            ram_size,
+            devices: Vec::new(),
+            intc: None,
+            irq_lines: 0,
+            event: None
+        };
+    }
+
+    pub fn ram_base(&self) -> u32 {
+        return self.ram_base;
+    }
+
+    pub fn ram_size(&self) -> usize {
+        return self.ram_size;
+    }
+
+    pub fn add_rom(&mut self, base: u32, data: Vec<u8>) {
+        self.memory.add_rom(base, &data);
+    }
+
+    /*
+     * Register a device at [base, base+size). irq is the interrupt line the
+     * device is wired to, 0 for none.
+     */
+    pub fn add_device(&mut self, base: u32, size: u32, irq: u32, device: Box<dyn Device>) {
+        self.devices.push(Mapping { base, size, irq, device });
+    }
+
+    /*
+     * Register the interrupt controller. Its own interrupt line is the
+     * external interrupt of the hart.
+     */
+    pub fn set_interrupt_controller(&mut self, base: u32, size: u32, device: Box<dyn Device>) {
+        self.devices.push(Mapping { base, size, irq: 0, device });
+        self.intc = Some(self.devices.len()-1);
+    }
+
+    fn device(&mut self, addr: u32, len: usize) -> Option<&mut Mapping> {
+        return self.devices.iter_mut().find(|m| in_range(addr, len, m.base, m.size as usize));
+    }
+
+    pub fn read(&mut self, addr: u32, size: u32) -> Result<u32, BusError> {
+        if let Some(m) = self.device(addr, size as usize) {
+            return Ok(m.device.read(addr - m.base, size));
+        }
+        return self.memory.read(addr, size);
+    }
+
+    // instruction fetch, needs execute permission
+    pub fn fetch(&mut self, addr: u32) -> Result<u32, BusError> {
+        if let Some(m) = self.device(addr, 4) {
+            return Ok(m.device.read(addr - m.base, 4));
+        }
+        return self.memory.fetch(addr);
+    }
+
+    pub fn write(&mut self, addr: u32, size: u32, value: u32) -> Result<(), BusError> {
+        if let Some(i) = self.devices.iter().position(|m| in_range(addr, size as usize, m.base, m.size as usize)) {
+            let m = &mut self.devices[i];
+            m.device.write(addr - m.base, size, value);
+            m.device.dma(&mut self.memory);
+            return Ok(());
+        }
+        return self.memory.write(addr, size, value);
+    }
+
+    /*
+     * Advance every device by one step and sample their interrupt lines.
+     */
+    pub fn tick(&mut self) {
+        let mut lines = 0;
+        for m in self.devices.iter_mut() {
+            m.device.tick();
+            m.device.dma(&mut self.memory);
+            if m.irq != 0 && m.device.interrupt() {
+                lines |= 1 << m.irq;
+            }
+            if let Some(event) = m.device.event() {
+                self.event.get_or_insert(event);
+            }
+        }
+        self.irq_lines = lines;
+        if let Some(i) = self.intc {
+            self.devices[i].device.update_irq_lines(lines);
+        }
+    }
+
+    pub fn external_interrupt(&self) -> bool {
+        return match self.intc {
+            Some(i) => self.devices[i].device.interrupt(),
+            None => false
+        };
+    }
+
+    pub fn has_device(&self, base: u32) -> bool {
+        return self.devices.iter().any(|m| m.base == base);
+    }
+
+    /*
+     * Add a node for every device that describes itself, wired to the
+     * interrupt controller when it has an interrupt line.
+     */
+    pub fn device_tree(&self, soc: &mut Node) {
+        for m in self.devices.iter() {
+            if let Some(mut node) = m.device.device_tree(m.base, m.size) {
+                if m.irq != 0 && self.intc.is_some() {
+                    node.u32("interrupts", m.irq)
+                        .u32("interrupt-parent", PLIC_PHANDLE);
+                }
+                soc.child(node);
+            }
+        }
+    }
+
+    // first event raised by a device since the last call
+    pub fn take_event(&mut self) -> Option<Event> {
+        return self.event.take();
+    }
+
+    /*
+     * Reset every device and put memory back to its checkpointed contents.
+     */
+    pub fn reset(&mut self) {
+        self.memory.reset();
+        for m in self.devices.iter_mut() {
+            m.device.reset();
+        }
+        self.irq_lines = 0;
+        self.event = None;
+    }
+
+    pub fn stop(&mut self) {
+        for m in self.devices.iter_mut() {
+            m.device.stop();
+        }
+    }
+
+    // bit n is set when interrupt line n is asserted
+    pub fn irq_lines(&self) -> u64 {
+        return self.irq_lines;
+    }
+}
+
+fn in_range(addr: u32, size: usize, base: u32, len: usize) -> bool {
+    return addr >= base && (addr - base) as usize + size <= len;
+}
@@ -0,0 +1,317 @@
+use std::convert::TryFrom;
+
+use crate::constants::{MEMSIZE, START_ADDR};
+use crate::devices::framebuffer::PixelFormat;
+use crate::devices::input::InputSource;
+use crate::devices::rtc::Clock;
+use crate::devices::virtio_blk::DiskMode;
+use crate::loader::Format;
+
+/*
+ * Command line configuration
+ */
+
+pub const USAGE: &str = "\
+Usage: rustv [options] <program> [args...]
+
+Arguments after the program are passed to it with --user or --semihosting.
+
+Options:
+  --user                Run the program as a Linux user process: entered at
+                        its entry point with argc, argv, envp and auxv on the
+                        stack instead of booting through the reset vector, and
+                        ECALLs served as Linux system calls
+  --env <NAME=value>    Add to the user process environment, may be given more
+                        than once
+  --format <format>     Program format: elf, bin, ihex or srec, detected from
+                        the contents by default
+  --load-addr <addr>    Where a raw binary or position-independent ELF is
+                        loaded (default 0x80000000)
+  --image <path[@addr][:format]>
+                        Load another image, may be given more than once. Raw
+                        binaries need the addr, the format is detected unless
+                        given as with --format
+  --kernel <path[@addr][:format]>
+                        Load a kernel for the firmware to jump to, at 0x80200000
+                        unless given
+  --initrd <path[@addr]>
+                        Load an initrd and publish it in the device tree, by
+                        default halfway into RAM (at most 128M in)
+  --append <cmdline>    Kernel command line, published as /chosen/bootargs
+  --semihosting         Serve semihosting calls (EBREAK between slli/srai markers)
+  --semihosting-root <dir>
+                        Host directory semihosting files are opened in
+                        (default the current directory)
+  --core <path>         Write an ELF core file to path when a trap has no handler
+  --core-at-exit        Also write the core file when the program stops normally,
+                        so a syscon or HTIF poweroff asks for a dump
+  --memory <size>       RAM size in bytes, with an optional K, M or G suffix
+  --dtb-dump <path>     Write the generated device tree to path, as source if
+                        it ends in .dts
+  --uart-input <src>    UART receive source: stdin (default), none or a file path
+  --raw                 Put the host terminal into raw mode while running
+  --disk <image>        Attach a virtio-blk device backed by the image file
+  --disk-mode <mode>    ro (read-only) or cow (copy-on-write overlay, default)
+  --virtio-console      Attach a virtio-console (hvc0)
+  --console-input <src> virtio-console input: none (default), stdin or a file path
+  --virtio-rng          Attach a virtio-rng entropy device
+  --seed <n>            Seed guest visible randomness and read input up front,
+                        making runs reproducible
+  --rtc <clock>         RTC time source: host, or virtual (derived from
+                        instructions retired, the default with --seed)
+  --fb <WxH[:format]>   Attach a framebuffer, format is one of r5g6b5, x8r8g8b8
+                        (default), a8r8g8b8, x8b8g8r8 or a8b8g8r8
+  --fb-output <path>    Where frames are saved, .png or .ppm (default
+                        framebuffer.ppm), {n} is replaced by the frame number
+  --fb-every <n>        Also save a frame every n frames of guest time";
+
+pub struct Config {
+    pub program: String,
+    pub args: Vec<String>,
+    pub user: bool,
+    pub env: Vec<String>,
+    pub format: Option<Format>,
+    pub load_addr: u32,
+    pub images: Vec<ImageConfig>,
+    pub kernel: Option<ImageConfig>,
+    pub initrd: Option<ImageConfig>,
+    pub append: Option<String>,
+    pub semihosting: bool,
+    pub semihosting_root: String,
+    pub core: Option<String>,
+    pub core_at_exit: bool,
+    pub memory: usize,
+    pub dtb_dump: Option<String>,
+    pub uart_input: InputSource,
+    pub raw: bool,
+    pub disk: Option<String>,
+    pub disk_mode: DiskMode,
+    pub virtio_console: bool,
+    pub console_input: InputSource,
+    pub virtio_rng: bool,
+    pub seed: Option<u64>,
+    pub rtc: Clock,
+    pub fb: Option<FbConfig>
+}
+
+// an image file, where to load it and its format
+pub struct ImageConfig {
+    pub path: String,
+    pub addr: Option<u32>,
+    pub format: Option<Format>
+}
+
+pub struct FbConfig {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    pub output: String,
+    pub every: u32
+}
+
+pub fn parse_args(args: &[String]) -> Result<Config, String> {
+    let mut program = None;
+    let mut program_args = Vec::new();
+    let mut user = false;
+    let mut env = Vec::new();
+    let mut format = None;
+    let mut load_addr = START_ADDR;
+    let mut images = Vec::new();
+    let mut kernel = None;
+    let mut initrd = None;
+    let mut append = None;
+    let mut semihosting = false;
+    let mut semihosting_root = ".".to_string();
+    let mut core = None;
+    let mut core_at_exit = false;
+    let mut memory = MEMSIZE;
+    let mut dtb_dump = None;
+    let mut uart_input = InputSource::Stdin;
+    let mut raw = false;
+    let mut disk = None;
+    let mut disk_mode = DiskMode::CopyOnWrite;
+    let mut virtio_console = false;
+    let mut console_input = InputSource::None;
+    let mut virtio_rng = false;
+    let mut seed = None;
+    let mut rtc = None;
+    let mut fb = None;
+    let mut fb_output = "framebuffer.ppm".to_string();
+    let mut fb_every = 0;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if program.is_some() {
+            program_args.push(arg.clone());
+            continue;
+        }
+        match arg.as_str() {
+            "--user" => user = true,
+            "--env" => env.push(value(&mut args, arg)?.clone()),
+            "--format" => {
+                let name = value(&mut args, arg)?;
+                format = Some(Format::parse(name).ok_or(format!("Unknown program format: {}", name))?);
+            },
+            "--load-addr" => load_addr = address(value(&mut args, arg)?)?,
+            "--image" => images.push(image(value(&mut args, arg)?)?),
+            "--kernel" => kernel = Some(image(value(&mut args, arg)?)?),
+            "--initrd" => initrd = Some(image(value(&mut args, arg)?)?),
+            "--append" => append = Some(value(&mut args, arg)?.clone()),
+            "--semihosting" => semihosting = true,
+            "--semihosting-root" => semihosting_root = value(&mut args, arg)?.clone(),
+            "--core" => core = Some(value(&mut args, arg)?.clone()),
+            "--core-at-exit" => core_at_exit = true,
+            "--memory" => memory = size(value(&mut args, arg)?)?,
+            "--dtb-dump" => dtb_dump = Some(value(&mut args, arg)?.clone()),
+            "--uart-input" => uart_input = input_source(value(&mut args, arg)?),
+            "--raw" => raw = true,
+            "--disk" => disk = Some(value(&mut args, arg)?.clone()),
+            "--disk-mode" => {
+                disk_mode = match value(&mut args, arg)?.as_str() {
+                    "ro" => DiskMode::ReadOnly,
+                    "cow" => DiskMode::CopyOnWrite,
+                    mode => return Err(format!("Unknown disk mode: {}", mode))
+                };
+            },
+            "--virtio-console" => virtio_console = true,
+            "--console-input" => console_input = input_source(value(&mut args, arg)?),
+            "--virtio-rng" => virtio_rng = true,
+            "--seed" => seed = Some(number(value(&mut args, arg)?)?),
+            "--rtc" => {
+                rtc = match value(&mut args, arg)?.as_str() {
+                    "host" => Some(Clock::Host),
+                    "virtual" => Some(Clock::Virtual),
+                    clock => return Err(format!("Unknown RTC clock: {}", clock))
+                };
+            },
+            "--fb" => fb = Some(fb_geometry(value(&mut args, arg)?)?),
+            "--fb-output" => fb_output = value(&mut args, arg)?.clone(),
+            "--fb-every" => {
+                let n = value(&mut args, arg)?;
+                fb_every = u32::try_from(number(n)?).map_err(|_| format!("Invalid frame count: {}", n))?;
+            },
+            opt if opt.starts_with("--") => {
+                return Err(format!("Unknown option: {}", opt));
+            },
+            _ => program = Some(arg.clone())
+        }
+    }
+
+    let program = program.ok_or("No program given")?;
+    if let (false, Some(arg)) = (user || semihosting, program_args.first()) {
+        return Err(format!("Unexpected argument: {}", arg));
+    }
+    let rtc = rtc.unwrap_or(if seed.is_some() { Clock::Virtual } else { Clock::Host });
+    let fb = fb.map(|(width, height, format)| FbConfig {
+        width,
+        height,
+        format,
+        output: fb_output,
+        every: fb_every
+    });
+    return Ok(Config {
+        program,
+        args: program_args,
+        user,
+        env,
+        format,
+        load_addr,
+        images,
+        kernel,
+        initrd,
+        append,
+        semihosting,
+        semihosting_root,
+        core,
+        core_at_exit,
+        memory,
+        dtb_dump,
+        uart_input,
+        raw,
+        disk,
+        disk_mode,
+        virtio_console,
+        console_input,
+        virtio_rng,
+        seed,
+        rtc,
+        fb
+    });
+}
+
+fn input_source(src: &str) -> InputSource {
+    return match src {
+        "stdin" => InputSource::Stdin,
+        "none" => InputSource::None,
+        path => InputSource::File(path.to_string())
+    };
+}
+
+// path with an optional @addr and :format, a path can have colons of its own
+fn image(s: &str) -> Result<ImageConfig, String> {
+    let (s, format) = match s.rsplit_once(':') {
+        Some((rest, format)) if Format::parse(format).is_some() => (rest, Format::parse(format)),
+        _ => (s, None)
+    };
+    if let Some((path, addr)) = s.rsplit_once('@') {
+        return Ok(ImageConfig { path: path.to_string(), addr: Some(address(addr)?), format });
+    }
+    return Ok(ImageConfig { path: s.to_string(), addr: None, format });
+}
+
+// WxH with an optional :format
+fn fb_geometry(s: &str) -> Result<(u32, u32, PixelFormat), String> {
+    let invalid = || format!("Invalid framebuffer geometry: {}", s);
+    let (size, format) = match s.split_once(':') {
+        Some((size, format)) => {
+            let format = PixelFormat::parse(format)
+                .ok_or(format!("Unknown pixel format: {}", format))?;
+            (size, format)
+        },
+        None => (s, PixelFormat::X8R8G8B8)
+    };
+    let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+    let width: u32 = width.parse().map_err(|_| invalid())?;
+    let height: u32 = height.parse().map_err(|_| invalid())?;
+    if width == 0 || height == 0 || width > 4096 || height > 4096 {
+        return Err(invalid());
+    }
+    return Ok((width, height, format));
+}
+
+// a number with an optional K, M or G suffix
+fn size(s: &str) -> Result<usize, String> {
+    let (num, shift) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len()-1], 10),
+        Some('M') | Some('m') => (&s[..s.len()-1], 20),
+        Some('G') | Some('g') => (&s[..s.len()-1], 30),
+        _ => (s, 0)
+    };
+    let invalid = || format!("Invalid memory size: {}", s);
+    let size = number(num)?.checked_mul(1 << shift).ok_or_else(invalid)?;
+    if size == 0 || size > 0x8000_0000 {
+        return Err(invalid());
+    }
+    return Ok(size as usize);
+}
+
+fn address(s: &str) -> Result<u32, String> {
+    let addr = number(s)?;
+    if addr > u32::MAX as u64 {
+        return Err(format!("Invalid address: {}", s));
+    }
+    return Ok(addr as u32);
+}
+
+// decimal or 0x prefixed hex
+fn number(s: &str) -> Result<u64, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse()
+    };
+    return parsed.map_err(|_| format!("Invalid number: {}", s));
+}
+
+fn value<'a>(args: &mut std::slice::Iter<'a, String>, opt: &str) -> Result<&'a String, String> {
+    return args.next().ok_or(format!("Missing value for {}", opt));
+}
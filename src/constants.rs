@@ -1,5 +1,32 @@
-pub const START_ADDR: u32 = 0x80000000;
 pub const MEMSIZE: usize = 16 * 1024;
+
+/*
+ * Physical memory map, follows QEMU virt
+ */
+pub const BOOT_ROM_BASE: u32 = 0x1000;
+// start of RAM
+pub const START_ADDR: u32 = 0x80000000;
+// where a kernel goes after the firmware, as OpenSBI expects on virt
+pub const KERNEL_ADDR: u32 = 0x80200000;
+// room kept for the stack below the top of RAM, at most an eighth of it
+pub const STACK_SIZE: u32 = 8 << 20;
+pub const SYSCON_BASE: u32 = 0x0010_0000;
+pub const SYSCON_SIZE: u32 = 0x1000;
+pub const RTC_BASE: u32 = 0x0010_1000;
+pub const RTC_SIZE: u32 = 0x1000;
+pub const RTC_IRQ: u32 = 11;
+pub const PLIC_BASE: u32 = 0x0c00_0000;
+pub const PLIC_SIZE: u32 = 0x0400_0000;
+pub const UART_BASE: u32 = 0x1000_0000;
+pub const UART_SIZE: u32 = 0x100;
+pub const UART_IRQ: u32 = 10;
+// virtio-mmio slots, slot n is at VIRTIO_BASE + n*VIRTIO_SIZE on irq VIRTIO_IRQ + n
+pub const VIRTIO_BASE: u32 = 0x1000_1000;
+pub const VIRTIO_SIZE: u32 = 0x1000;
+pub const VIRTIO_IRQ: u32 = 1;
+pub const VIRTIO_COUNT: u32 = 8;
+// not part of QEMU virt, pixel memory followed by the control registers
+pub const FB_BASE: u32 = 0x5000_0000;
 pub const REG_NAMES: [&str; 33] = [
     "zero",
     "ra",
@@ -107,9 +134,63 @@ pub mod funct3 {
     pub const CSRRCI: u32 = 0b111;
 }
 
+pub mod csrs {
+    pub const MSTATUS: usize = 0x300;
+    pub const MISA: usize = 0x301;
+    pub const MIE: usize = 0x304;
+    pub const MTVEC: usize = 0x305;
+    pub const MSCRATCH: usize = 0x340;
+    pub const MEPC: usize = 0x341;
+    pub const MCAUSE: usize = 0x342;
+    pub const MTVAL: usize = 0x343;
+    pub const MIP: usize = 0x344;
+    pub const MHARTID: usize = 0xf14;
+
+    // counters, the upper halves at +0x80
+    pub const MCYCLE: usize = 0xb00;
+    pub const MINSTRET: usize = 0xb02;
+    pub const CYCLE: usize = 0xc00;
+    pub const TIME: usize = 0xc01;
+    pub const INSTRET: usize = 0xc02;
+    pub const COUNTER_HIGH: usize = 0x80;
+
+    // misa bits
+    pub const MISA_MXL_32: i32 = 1 << 30;
+    pub const MISA_E: i32 = 1 << 4;
+    pub const MISA_I: i32 = 1 << 8;
+
+    // mstatus bits
+    pub const MSTATUS_MIE: i32 = 1 << 3;
+    pub const MSTATUS_MPIE: i32 = 1 << 7;
+    pub const MSTATUS_MPP: i32 = 0b11 << 11;
+
+    // mip / mie bits
+    pub const MEIP: i32 = 1 << 11;
+}
+
+pub mod causes {
+    pub const INTERRUPT: u32 = 1 << 31;
+
+    pub const INSTRUCTION_ACCESS_FAULT: u32 = 1;
+    pub const ILLEGAL_INSTRUCTION: u32 = 2;
+    pub const BREAKPOINT: u32 = 3;
+    pub const LOAD_ACCESS_FAULT: u32 = 5;
+    pub const STORE_ACCESS_FAULT: u32 = 7;
+    pub const ECALL_M: u32 = 11;
+
+    pub const M_EXTERNAL: u32 = INTERRUPT | 11;
+}
+
+pub mod funct7 {
+    // OP, and imm[11:5] of the OP_IMM shifts
+    pub const BASE: u32 = 0b0000000;
+    pub const SUB_SRA: u32 = 0b0100000;
+}
+
 pub mod funct12 {
     // SYSTEM
     pub const ECALL: u32 = 0b000000000000;
     pub const EBREAK: u32 = 0b000000000001;
+    pub const WFI: u32 = 0b000100000101;
     pub const MRET: u32 = 0b001100000010;
 }
@@ -0,0 +1,116 @@
+use std::fs;
+use std::io;
+
+use crate::Core;
+use crate::constants::causes;
+use crate::elf::*;
+use crate::elfwriter::{header, phdr};
+use crate::memory::PAGE_SIZE;
+
+/*
+ * ELF core dumps
+ *
+ * A post-mortem image gdb can open next to the program: a PT_NOTE with an
+ * NT_PRSTATUS register set in the rv32 Linux layout, then one PT_LOAD per
+ * run of allocated memory pages. Pages that were never written are left
+ * out, they read as zero anyway.
+ */
+
+pub const NT_PRSTATUS: u32 = 1;
+
+// struct elf_prstatus on rv32
+pub const PRSTATUS_SIZE: usize = 204;
+const PRSTATUS_CURSIG: usize = 12;
+const PRSTATUS_PID: usize = 24;
+const PRSTATUS_REG: usize = 72;
+
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGBUS: u32 = 7;
+pub const SIGSEGV: u32 = 11;
+
+// the signal Linux would have delivered for a trap cause
+pub fn signal(cause: u32) -> u32 {
+    return match cause {
+        0 | 4 | 6 => SIGBUS,
+        causes::ILLEGAL_INSTRUCTION => SIGILL,
+        causes::BREAKPOINT => SIGTRAP,
+        _ => SIGSEGV
+    };
+}
+
+/*
+ * NT_PRSTATUS descriptor: the signal and the general registers, pc in the
+ * slot of x0.
+ */
+pub fn prstatus(core: &Core, signal: u32, pc: u32) -> Vec<u8> {
+    let mut desc = vec![0; PRSTATUS_SIZE];
+    desc[0..4].copy_from_slice(&signal.to_le_bytes());
+    desc[PRSTATUS_CURSIG..PRSTATUS_CURSIG+2].copy_from_slice(&(signal as u16).to_le_bytes());
+    desc[PRSTATUS_PID..PRSTATUS_PID+4].copy_from_slice(&1u32.to_le_bytes());
+    for i in 0..32 {
+        let val = if i == 0 { pc } else { core.regs[i] as u32 };
+        let at = PRSTATUS_REG + 4*i;
+        desc[at..at+4].copy_from_slice(&val.to_le_bytes());
+    }
+    return desc;
+}
+
+fn note(name: &str, kind: u32, desc: &[u8]) -> Vec<u8> {
+    let mut note = Vec::new();
+    push_u32(&mut note, name.len() as u32 + 1);
+    push_u32(&mut note, desc.len() as u32);
+    push_u32(&mut note, kind);
+    note.extend_from_slice(name.as_bytes());
+    note.push(0);
+    note.resize((note.len() + 3) & !3, 0);
+    note.extend_from_slice(desc);
+    note.resize((note.len() + 3) & !3, 0);
+    return note;
+}
+
+// allocated pages merged into contiguous runs
+fn runs(core: &Core) -> Vec<(u32, Vec<u8>)> {
+    let mut runs: Vec<(u32, Vec<u8>)> = Vec::new();
+    for (addr, page) in core.bus.memory.pages() {
+        match runs.last_mut() {
+            Some((base, data)) if base.wrapping_add(data.len() as u32) == addr => data.extend_from_slice(page),
+            _ => runs.push((addr, page.to_vec()))
+        }
+    }
+    return runs;
+}
+
+/*
+ * Core file for the machine stopped at pc, signal 0 when it wasn't killed
+ * by a trap.
+ */
+pub fn core_dump(core: &Core, signal: u32, pc: u32) -> Vec<u8> {
+    let note = note("CORE", NT_PRSTATUS, &prstatus(core, signal, pc));
+    let runs = runs(core);
+    let phnum = 1 + runs.len();
+    let note_offset = EHDR_SIZE + phnum * PHDR_SIZE;
+    let mut offset = (note_offset + note.len() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+    let mut out = header(ET_CORE, 0, phnum, 0, 0, 0);
+    out.extend(phdr(PT_NOTE, note_offset as u32, 0, note.len() as u32, 0));
+    for (addr, data) in runs.iter() {
+        out.extend(phdr(PT_LOAD, offset as u32, *addr, data.len() as u32, PF_R | PF_W | PF_X));
+        offset += data.len();
+    }
+
+    out.extend(note);
+    for (_, data) in runs.iter() {
+        out.resize((out.len() + PAGE_SIZE - 1) & !(PAGE_SIZE - 1), 0);
+        out.extend_from_slice(data);
+    }
+    return out;
+}
+
+pub fn write_core(core: &Core, path: &str, signal: u32, pc: u32) -> io::Result<()> {
+    return fs::write(path, core_dump(core, signal, pc));
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
@@ -0,0 +1,299 @@
+use std::fs;
+use std::io;
+
+use crate::bus::Device;
+use crate::fdt::Node;
+
+/*
+ * Linear framebuffer
+ *
+ * Pixel memory starts at offset 0 and is followed, on the next page, by a
+ * small control block:
+ *
+ *   0x00 FLUSH   write: save the current frame
+ *   0x04 WIDTH   read only
+ *   0x08 HEIGHT  read only
+ *   0x0c STRIDE  read only, bytes per line
+ *   0x10 FORMAT  read only, see PixelFormat
+ *
+ * Frames are written as PPM or PNG depending on the output file extension,
+ * on a guest flush, every N frames of FRAME_TICKS instructions, and when the
+ * machine stops. A "{n}" in the output path is replaced by a running frame
+ * number, otherwise every dump overwrites the same file.
+ */
+
+pub const FRAME_TICKS: u32 = 100_000;
+
+const FLUSH: u32 = 0x00;
+const WIDTH: u32 = 0x04;
+const HEIGHT: u32 = 0x08;
+const STRIDE: u32 = 0x0c;
+const FORMAT: u32 = 0x10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelFormat {
+    R5G6B5,
+    X8R8G8B8,
+    A8R8G8B8,
+    X8B8G8R8,
+    A8B8G8R8
+}
+
+impl PixelFormat {
+    pub fn parse(name: &str) -> Option<PixelFormat> {
+        return match name {
+            "r5g6b5" => Some(PixelFormat::R5G6B5),
+            "x8r8g8b8" => Some(PixelFormat::X8R8G8B8),
+            "a8r8g8b8" => Some(PixelFormat::A8R8G8B8),
+            "x8b8g8r8" => Some(PixelFormat::X8B8G8R8),
+            "a8b8g8r8" => Some(PixelFormat::A8B8G8R8),
+            _ => None
+        };
+    }
+
+    // name used by the simple-framebuffer binding
+    pub fn name(&self) -> &'static str {
+        return match self {
+            PixelFormat::R5G6B5 => "r5g6b5",
+            PixelFormat::X8R8G8B8 => "x8r8g8b8",
+            PixelFormat::A8R8G8B8 => "a8r8g8b8",
+            PixelFormat::X8B8G8R8 => "x8b8g8r8",
+            PixelFormat::A8B8G8R8 => "a8b8g8r8"
+        };
+    }
+
+    pub fn bytes_per_pixel(&self) -> u32 {
+        return match self {
+            PixelFormat::R5G6B5 => 2,
+            _ => 4
+        };
+    }
+
+    fn rgb(&self, px: &[u8]) -> [u8; 3] {
+        return match self {
+            PixelFormat::R5G6B5 => {
+                let v = u16::from_le_bytes([px[0], px[1]]);
+                let (r, g, b) = ((v >> 11) & 0x1f, (v >> 5) & 0x3f, v & 0x1f);
+                [(r << 3 | r >> 2) as u8, (g << 2 | g >> 4) as u8, (b << 3 | b >> 2) as u8]
+            },
+            // little endian words, so blue is the lowest byte
+            PixelFormat::X8R8G8B8 | PixelFormat::A8R8G8B8 => [px[2], px[1], px[0]],
+            PixelFormat::X8B8G8R8 | PixelFormat::A8B8G8R8 => [px[0], px[1], px[2]]
+        };
+    }
+}
+
+pub struct Framebuffer {
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    pixels: Vec<u8>,
+    output: String,
+    every: u32,
+    ticks: u32,
+    frames: u32,
+    dumps: u32
+}
+
+impl Framebuffer {
+    /*
+     * every is the number of frames between dumps, 0 to only dump on flush
+     * and when the machine stops.
+     */
+    pub fn new(width: u32, height: u32, format: PixelFormat, output: &str, every: u32) -> Framebuffer {
+        let size = (width * height * format.bytes_per_pixel()) as usize;
+        return Framebuffer {
+            width,
+            height,
+            format,
+            pixels: vec![0; size],
+            output: output.to_string(),
+            every,
+            ticks: 0,
+            frames: 0,
+            dumps: 0
+        };
+    }
+
+    pub fn stride(&self) -> u32 {
+        return self.width * self.format.bytes_per_pixel();
+    }
+
+    // size of the pixel memory
+    pub fn size(&self) -> u32 {
+        return self.pixels.len() as u32;
+    }
+
+    // offset of the control block, pixel memory rounded up to a page
+    pub fn control(&self) -> u32 {
+        return (self.size() + 0xfff) & !0xfff;
+    }
+
+    // size of the whole mapping
+    pub fn mapping_size(&self) -> u32 {
+        return self.control() + 0x1000;
+    }
+
+    pub fn rgb(&self) -> Vec<u8> {
+        let bpp = self.format.bytes_per_pixel() as usize;
+        let mut rgb = Vec::with_capacity((self.width * self.height * 3) as usize);
+        for px in self.pixels.chunks(bpp) {
+            rgb.extend_from_slice(&self.format.rgb(px));
+        }
+        return rgb;
+    }
+
+    pub fn save(&mut self) -> io::Result<()> {
+        let path = self.output.replace("{n}", &self.dumps.to_string());
+        self.dumps += 1;
+        let rgb = self.rgb();
+        let data = if path.ends_with(".png") {
+            png(self.width, self.height, &rgb)
+        }
+        else {
+            ppm(self.width, self.height, &rgb)
+        };
+        return fs::write(path, data);
+    }
+
+    fn dump(&mut self) {
+        if let Err(e) = self.save() {
+            println!("framebuffer: couldn't save {}: {}", self.output, e);
+        }
+    }
+}
+
+impl Device for Framebuffer {
+    fn read(&mut self, offset: u32, size: u32) -> u32 {
+        if offset < self.size() {
+            let mut val = 0;
+            for i in 0..size.min(self.size() - offset) {
+                val |= (self.pixels[(offset+i) as usize] as u32) << (8*i);
+            }
+            return val;
+        }
+        return match offset.wrapping_sub(self.control()) {
+            WIDTH => self.width,
+            HEIGHT => self.height,
+            STRIDE => self.stride(),
+            FORMAT => self.format as u32,
+            _ => 0
+        };
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: u32) {
+        if offset < self.size() {
+            for i in 0..size.min(self.size() - offset) {
+                self.pixels[(offset+i) as usize] = (value >> (8*i)) as u8;
+            }
+            return;
+        }
+        if offset.wrapping_sub(self.control()) == FLUSH {
+            self.dump();
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.every == 0 {
+            return;
+        }
+        self.ticks += 1;
+        if self.ticks >= FRAME_TICKS {
+            self.ticks = 0;
+            self.frames += 1;
+            if self.frames == self.every {
+                self.frames = 0;
+                self.dump();
+            }
+        }
+    }
+
+    fn stop(&mut self) {
+        self.dump();
+    }
+
+    // only the pixel memory, the control block is ours
+    fn device_tree(&self, base: u32, _size: u32) -> Option<Node> {
+        let mut node = Node::at("framebuffer", base);
+        node.string("compatible", "simple-framebuffer")
+            .reg(base, self.size())
+            .u32("width", self.width)
+            .u32("height", self.height)
+            .u32("stride", self.stride())
+            .string("format", self.format.name());
+        return Some(node);
+    }
+}
+
+fn ppm(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let mut data = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    data.extend_from_slice(rgb);
+    return data;
+}
+
+/*
+ * Uncompressed PNG: the image data is wrapped in stored deflate blocks, which
+ * keeps the encoder tiny at the cost of file size.
+ */
+fn png(width: u32, height: u32, rgb: &[u8]) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(rgb.len() + height as usize);
+    for line in rgb.chunks((width * 3) as usize) {
+        raw.push(0); // filter: none
+        raw.extend_from_slice(line);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = if blocks.peek().is_none() { 1 } else { 0 };
+        let len = block.len() as u16;
+        zlib.push(last);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut ihdr = Vec::new();
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bit RGB
+
+    let mut data = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+    chunk(&mut data, b"IHDR", &ihdr);
+    chunk(&mut data, b"IDAT", &zlib);
+    chunk(&mut data, b"IEND", &[]);
+    return data;
+}
+
+fn chunk(data: &mut Vec<u8>, kind: &[u8; 4], body: &[u8]) {
+    data.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    let start = data.len();
+    data.extend_from_slice(kind);
+    data.extend_from_slice(body);
+    let crc = crc32(&data[start..]);
+    data.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    return !crc;
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    return (b << 16) | a;
+}
@@ -0,0 +1,86 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::thread;
+
+/*
+ * Host side input for console devices.
+ *
+ * Stdin is normally read on a background thread so that the guest never
+ * blocks on it. In deterministic mode it is read to the end up front instead,
+ * which makes the input independent of host timing.
+ */
+
+pub enum InputSource {
+    None,
+    Stdin,
+    File(String)
+}
+
+pub struct HostInput {
+    queue: VecDeque<u8>,
+    reader: Option<Receiver<u8>>
+}
+
+impl HostInput {
+    pub fn open(source: &InputSource, deterministic: bool) -> io::Result<HostInput> {
+        let mut input = HostInput::empty();
+        match source {
+            InputSource::None => {},
+            InputSource::Stdin if deterministic => {
+                let mut data = Vec::new();
+                io::stdin().read_to_end(&mut data)?;
+                input.queue.extend(data);
+            },
+            InputSource::Stdin => input.reader = Some(spawn_reader(io::stdin())),
+            InputSource::File(path) => input.queue.extend(fs::read(path)?)
+        }
+        return Ok(input);
+    }
+
+    pub fn empty() -> HostInput {
+        return HostInput { queue: VecDeque::new(), reader: None };
+    }
+
+    // pick up whatever the reader thread has received so far
+    pub fn poll(&mut self) {
+        if let Some(reader) = &self.reader {
+            while let Ok(byte) = reader.try_recv() {
+                self.queue.push_back(byte);
+            }
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.queue.extend(bytes);
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        return self.queue.pop_front();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.queue.is_empty();
+    }
+
+    pub fn clear(&mut self) {
+        self.queue.clear();
+    }
+}
+
+fn spawn_reader<R: Read + Send + 'static>(mut reader: R) -> Receiver<u8> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut buf = [0; 64];
+        while let Ok(n) = reader.read(&mut buf) {
+            if n == 0 { break; }
+            for byte in &buf[..n] {
+                if tx.send(*byte).is_err() { return; }
+            }
+        }
+    });
+    return rx;
+}
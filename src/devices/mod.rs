@@ -0,0 +1,13 @@
+/*
+ * Memory mapped peripherals that plug into the bus.
+ */
+pub mod framebuffer;
+pub mod input;
+pub mod plic;
+pub mod rtc;
+pub mod syscon;
+pub mod uart;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod virtio_console;
+pub mod virtio_rng;
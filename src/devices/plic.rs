@@ -0,0 +1,159 @@
+use crate::bus::Device;
+use crate::fdt::Node;
+use crate::fdt::CPU_INTC_PHANDLE;
+use crate::fdt::PLIC_PHANDLE;
+
+/*
+ * Platform-Level Interrupt Controller
+ *
+ * Register layout follows the SiFive PLIC used by QEMU virt. Context 0 is
+ * the machine mode external interrupt of hart 0, context 1 the supervisor
+ * one. Interrupts are level triggered: a source stays pending while its line
+ * is high and it isn't being serviced.
+ */
+
+const NUM_SOURCES: usize = 64;
+const NUM_CONTEXTS: usize = 2;
+
+const PENDING: u32 = 0x1000;
+const ENABLE: u32 = 0x2000;
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT: u32 = 0x20_0000;
+const CONTEXT_STRIDE: u32 = 0x1000;
+
+pub struct Plic {
+    priority: [u32; NUM_SOURCES],
+    pending: u64,
+    claimed: u64,
+    enable: [u64; NUM_CONTEXTS],
+    threshold: [u32; NUM_CONTEXTS]
+}
+
+impl Plic {
+    pub fn new() -> Plic {
+        return Plic {
+            priority: [0; NUM_SOURCES],
+            pending: 0,
+            claimed: 0,
+            enable: [0; NUM_CONTEXTS],
+            threshold: [0; NUM_CONTEXTS]
+        };
+    }
+
+    /*
+     * Highest priority pending source enabled for context, 0 if none.
+     */
+    fn best(&self, context: usize) -> u32 {
+        let mut best = 0;
+        let mut best_prio = self.threshold[context];
+        for id in 1..NUM_SOURCES {
+            let bit = 1 << id;
+            if self.pending & self.enable[context] & bit != 0 && self.priority[id] > best_prio {
+                best = id as u32;
+                best_prio = self.priority[id];
+            }
+        }
+        return best;
+    }
+
+    pub fn context_interrupt(&self, context: usize) -> bool {
+        return self.best(context) != 0;
+    }
+}
+
+impl Default for Plic {
+    fn default() -> Plic {
+        return Plic::new();
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, offset: u32, _size: u32) -> u32 {
+        match offset {
+            o if o < PENDING => {
+                return self.priority.get((o/4) as usize).copied().unwrap_or(0);
+            },
+            o if (PENDING..PENDING+8).contains(&o) => {
+                return (self.pending >> (8*(o-PENDING))) as u32;
+            },
+            o if (ENABLE..ENABLE+ENABLE_STRIDE*NUM_CONTEXTS as u32).contains(&o) => {
+                let context = ((o-ENABLE)/ENABLE_STRIDE) as usize;
+                let word = (o-ENABLE)%ENABLE_STRIDE;
+                return if word < 8 { (self.enable[context] >> (8*word)) as u32 } else { 0 };
+            },
+            o if (CONTEXT..CONTEXT+CONTEXT_STRIDE*NUM_CONTEXTS as u32).contains(&o) => {
+                let context = ((o-CONTEXT)/CONTEXT_STRIDE) as usize;
+                match (o-CONTEXT)%CONTEXT_STRIDE {
+                    0 => return self.threshold[context],
+                    4 => { // claim
+                        let id = self.best(context);
+                        if id != 0 {
+                            self.pending &= !(1 << id);
+                            self.claimed |= 1 << id;
+                        }
+                        return id;
+                    },
+                    _ => return 0
+                }
+            },
+            _ => return 0
+        }
+    }
+
+    fn write(&mut self, offset: u32, _size: u32, value: u32) {
+        match offset {
+            o if o < PENDING => {
+                if let Some(p) = self.priority.get_mut((o/4) as usize) {
+                    *p = value & 0x7;
+                }
+            },
+            o if (ENABLE..ENABLE+ENABLE_STRIDE*NUM_CONTEXTS as u32).contains(&o) => {
+                let context = ((o-ENABLE)/ENABLE_STRIDE) as usize;
+                let word = (o-ENABLE)%ENABLE_STRIDE;
+                if word < 8 {
+                    let shift = 8*word;
+                    let mask = 0xffff_ffffu64 << shift;
+                    let value = (value as u64) << shift;
+                    // source 0 doesn't exist
+                    self.enable[context] = ((self.enable[context] & !mask) | value) & !1;
+                }
+            },
+            o if (CONTEXT..CONTEXT+CONTEXT_STRIDE*NUM_CONTEXTS as u32).contains(&o) => {
+                let context = ((o-CONTEXT)/CONTEXT_STRIDE) as usize;
+                match (o-CONTEXT)%CONTEXT_STRIDE {
+                    0 => self.threshold[context] = value & 0x7,
+                    4 if (value as usize) < NUM_SOURCES => { // complete
+                        self.claimed &= !(1 << value);
+                    },
+                    _ => {}
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Plic::new();
+    }
+
+    fn device_tree(&self, base: u32, size: u32) -> Option<Node> {
+        let mut node = Node::at("plic", base);
+        node.u32("#interrupt-cells", 1)
+            .u32("#address-cells", 0)
+            .strings("compatible", &["sifive,plic-1.0.0", "riscv,plic0"])
+            .reg(base, size)
+            .empty("interrupt-controller")
+            .u32("riscv,ndev", NUM_SOURCES as u32 - 1)
+            .cells("interrupts-extended", &[CPU_INTC_PHANDLE, 11, CPU_INTC_PHANDLE, 9])
+            .u32("phandle", PLIC_PHANDLE);
+        return Some(node);
+    }
+
+    fn interrupt(&self) -> bool {
+        return self.context_interrupt(0);
+    }
+
+    fn update_irq_lines(&mut self, lines: u64) {
+        self.pending |= lines & !self.claimed & !1;
+    }
+}
@@ -0,0 +1,168 @@
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::bus::Device;
+use crate::fdt::Node;
+
+/*
+ * Goldfish RTC
+ *
+ * Time is in nanoseconds since the Unix epoch. Reading TIME_LOW latches the
+ * upper half for the following TIME_HIGH read, and an alarm is armed by
+ * writing ALARM_HIGH then ALARM_LOW. The interrupt line is high while an
+ * alarm has fired and hasn't been cleared.
+ */
+
+pub const TIME_LOW: u32 = 0x00;
+pub const TIME_HIGH: u32 = 0x04;
+const ALARM_LOW: u32 = 0x08;
+const ALARM_HIGH: u32 = 0x0c;
+const IRQ_ENABLED: u32 = 0x10;
+const CLEAR_ALARM: u32 = 0x14;
+const ALARM_STATUS: u32 = 0x18;
+const CLEAR_INTERRUPT: u32 = 0x1c;
+
+// the virtual clock starts at 2020-01-01 and runs at 100 MHz
+pub const VIRTUAL_EPOCH: u64 = 1_577_836_800_000_000_000;
+pub const NS_PER_TICK: u64 = 10;
+
+// how often the host clock is checked for alarms, in ticks
+const POLL_INTERVAL: u32 = 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Clock {
+    // host wall clock
+    Host,
+    // derived from instructions retired, the same on every run
+    Virtual
+}
+
+pub struct Rtc {
+    clock: Clock,
+    ticks: u64,
+    // added to the clock once the guest sets the time
+    offset: i64,
+    time_high: u32,
+    alarm_high: u32,
+    alarm: Option<u64>,
+    irq_enabled: bool,
+    irq_pending: bool,
+    poll: u32
+}
+
+impl Rtc {
+    pub fn new(clock: Clock) -> Rtc {
+        return Rtc {
+            clock,
+            ticks: 0,
+            offset: 0,
+            time_high: 0,
+            alarm_high: 0,
+            alarm: None,
+            irq_enabled: false,
+            irq_pending: false,
+            poll: 0
+        };
+    }
+
+    fn clock_ns(&self) -> u64 {
+        return match self.clock {
+            Clock::Host => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_nanos() as u64)
+                .unwrap_or(0),
+            Clock::Virtual => VIRTUAL_EPOCH + self.ticks * NS_PER_TICK
+        };
+    }
+
+    pub fn now(&self) -> u64 {
+        return self.clock_ns().wrapping_add(self.offset as u64);
+    }
+
+    fn set_time(&mut self, ns: u64) {
+        self.offset = ns.wrapping_sub(self.clock_ns()) as i64;
+    }
+
+    fn check_alarm(&mut self) {
+        if let Some(alarm) = self.alarm {
+            if self.now() >= alarm {
+                self.alarm = None;
+                self.irq_pending = true;
+            }
+        }
+    }
+}
+
+impl Device for Rtc {
+    fn read(&mut self, offset: u32, _size: u32) -> u32 {
+        return match offset {
+            TIME_LOW => {
+                let now = self.now();
+                self.time_high = (now >> 32) as u32;
+                now as u32
+            },
+            TIME_HIGH => self.time_high,
+            ALARM_LOW => self.alarm.unwrap_or(0) as u32,
+            ALARM_HIGH => self.alarm_high,
+            IRQ_ENABLED => self.irq_enabled as u32,
+            ALARM_STATUS => self.alarm.is_some() as u32,
+            _ => 0
+        };
+    }
+
+    fn write(&mut self, offset: u32, _size: u32, value: u32) {
+        match offset {
+            TIME_LOW => {
+                let high = self.now() >> 32;
+                self.set_time(high << 32 | value as u64);
+            },
+            TIME_HIGH => {
+                let low = self.now() & 0xffff_ffff;
+                self.set_time((value as u64) << 32 | low);
+            },
+            ALARM_LOW => {
+                self.alarm = Some((self.alarm_high as u64) << 32 | value as u64);
+                self.check_alarm();
+            },
+            ALARM_HIGH => self.alarm_high = value,
+            IRQ_ENABLED => self.irq_enabled = value & 1 != 0,
+            CLEAR_ALARM => self.alarm = None,
+            CLEAR_INTERRUPT => self.irq_pending = false,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self) {
+        self.ticks += 1;
+        if self.alarm.is_none() {
+            return;
+        }
+        if self.clock == Clock::Host {
+            self.poll += 1;
+            if self.poll < POLL_INTERVAL {
+                return;
+            }
+            self.poll = 0;
+        }
+        self.check_alarm();
+    }
+
+    fn interrupt(&self) -> bool {
+        return self.irq_enabled && self.irq_pending;
+    }
+
+    // the time of day survives a reboot
+    fn reset(&mut self) {
+        self.alarm = None;
+        self.alarm_high = 0;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+    }
+
+    fn device_tree(&self, base: u32, size: u32) -> Option<Node> {
+        let mut node = Node::at("rtc", base);
+        node.string("compatible", "google,goldfish-rtc")
+            .reg(base, size);
+        return Some(node);
+    }
+}
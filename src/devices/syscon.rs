@@ -0,0 +1,77 @@
+use crate::bus::Device;
+use crate::bus::Event;
+use crate::fdt::Node;
+use crate::fdt::SYSCON_PHANDLE;
+
+/*
+ * Poweroff / reboot device, compatible with the sifive_test device of QEMU
+ * virt. A 32-bit write to offset 0 with one of the codes below in the low
+ * half stops or resets the machine, FAIL takes the exit status from the
+ * upper half.
+ */
+
+const FINISHER_FAIL: u32 = 0x3333;
+const FINISHER_PASS: u32 = 0x5555;
+const FINISHER_RESET: u32 = 0x7777;
+
+pub struct Syscon {
+    event: Option<Event>
+}
+
+impl Syscon {
+    pub fn new() -> Syscon {
+        return Syscon { event: None };
+    }
+}
+
+impl Default for Syscon {
+    fn default() -> Syscon {
+        return Syscon::new();
+    }
+}
+
+impl Device for Syscon {
+    fn read(&mut self, _offset: u32, _size: u32) -> u32 {
+        return 0;
+    }
+
+    fn write(&mut self, offset: u32, _size: u32, value: u32) {
+        if offset != 0 {
+            return;
+        }
+        self.event = match value & 0xffff {
+            FINISHER_FAIL => Some(Event::Poweroff((value >> 16) as i32)),
+            FINISHER_PASS => Some(Event::Poweroff(0)),
+            FINISHER_RESET => Some(Event::Reset),
+            _ => self.event
+        };
+    }
+
+    fn event(&mut self) -> Option<Event> {
+        return self.event.take();
+    }
+
+    fn reset(&mut self) {
+        self.event = None;
+    }
+
+    fn device_tree(&self, base: u32, size: u32) -> Option<Node> {
+        let mut poweroff = Node::new("poweroff");
+        poweroff.string("compatible", "syscon-poweroff")
+            .u32("regmap", SYSCON_PHANDLE)
+            .u32("offset", 0)
+            .u32("value", FINISHER_PASS);
+        let mut reboot = Node::new("reboot");
+        reboot.string("compatible", "syscon-reboot")
+            .u32("regmap", SYSCON_PHANDLE)
+            .u32("offset", 0)
+            .u32("value", FINISHER_RESET);
+        let mut node = Node::at("test", base);
+        node.strings("compatible", &["sifive,test1", "sifive,test0", "syscon", "simple-mfd"])
+            .reg(base, size)
+            .u32("phandle", SYSCON_PHANDLE)
+            .child(poweroff)
+            .child(reboot);
+        return Some(node);
+    }
+}
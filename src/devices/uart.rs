@@ -0,0 +1,230 @@
+use std::io;
+use std::io::Write;
+use std::process::Command;
+use std::process::Stdio;
+
+use crate::bus::Device;
+use crate::devices::input::HostInput;
+use crate::fdt::Node;
+
+/*
+ * NS16550A compatible UART
+ *
+ * Transmitted bytes go straight to the output, so the transmitter is always
+ * empty. Received bytes come from the host input and are drained one at a
+ * time through RBR.
+ */
+
+const RBR: u32 = 0; // receive buffer (read, DLAB=0)
+const THR: u32 = 0; // transmit holding (write, DLAB=0)
+const IER: u32 = 1; // interrupt enable
+const IIR: u32 = 2; // interrupt identification (read)
+const FCR: u32 = 2; // fifo control (write)
+const LCR: u32 = 3; // line control
+const MCR: u32 = 4; // modem control
+const LSR: u32 = 5; // line status
+const MSR: u32 = 6; // modem status
+const SCR: u32 = 7; // scratch
+
+const IER_RDA: u8 = 0x1; // received data available
+const IER_THRE: u8 = 0x2; // transmitter holding register empty
+
+const IIR_NONE: u8 = 0x1;
+const IIR_THRE: u8 = 0x2;
+const IIR_RDA: u8 = 0x4;
+const IIR_FIFO: u8 = 0xc0;
+
+const LSR_DR: u8 = 0x1; // data ready
+const LSR_THRE: u8 = 0x20;
+const LSR_TEMT: u8 = 0x40;
+
+const LCR_DLAB: u8 = 0x80;
+
+// how often the host input is polled, in ticks
+const POLL_INTERVAL: u32 = 1024;
+
+pub struct Uart {
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    fcr: u8,
+    scr: u8,
+    dll: u8,
+    dlm: u8,
+    thre_pending: bool,
+    rx: HostInput,
+    output: Box<dyn Write>,
+    poll: u32
+}
+
+impl Uart {
+    pub fn new(input: HostInput) -> Uart {
+        let mut uart = Uart::with_output(Box::new(io::stdout()));
+        uart.rx = input;
+        return uart;
+    }
+
+    pub fn with_output(output: Box<dyn Write>) -> Uart {
+        return Uart {
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            fcr: 0,
+            scr: 0,
+            dll: 0,
+            dlm: 0,
+            thre_pending: false,
+            rx: HostInput::empty(),
+            output,
+            poll: 0
+        };
+    }
+
+    // queue bytes as if they were received on the line
+    pub fn receive(&mut self, bytes: &[u8]) {
+        self.rx.push(bytes);
+    }
+
+    fn iir(&self) -> u8 {
+        let fifo = if self.fcr & 0x1 != 0 { IIR_FIFO } else { 0 };
+        if self.ier & IER_RDA != 0 && !self.rx.is_empty() {
+            return fifo | IIR_RDA;
+        }
+        if self.ier & IER_THRE != 0 && self.thre_pending {
+            return fifo | IIR_THRE;
+        }
+        return fifo | IIR_NONE;
+    }
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: u32, _size: u32) -> u32 {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let val = match offset {
+            RBR if dlab => self.dll,
+            RBR => {
+                if self.rx.is_empty() {
+                    self.rx.poll();
+                }
+                self.rx.pop().unwrap_or(0)
+            },
+            IER if dlab => self.dlm,
+            IER => self.ier,
+            IIR => {
+                let iir = self.iir();
+                // reading IIR clears the THRE interrupt
+                if iir & 0xf == IIR_THRE {
+                    self.thre_pending = false;
+                }
+                iir
+            },
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                let dr = if self.rx.is_empty() { 0 } else { LSR_DR };
+                LSR_THRE | LSR_TEMT | dr
+            },
+            MSR => 0xb0, // DCD, DSR and CTS asserted
+            SCR => self.scr,
+            _ => 0
+        };
+        return val as u32;
+    }
+
+    fn write(&mut self, offset: u32, _size: u32, value: u32) {
+        let value = value as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match offset {
+            THR if dlab => self.dll = value,
+            THR => {
+                let _ = self.output.write_all(&[value]);
+                let _ = self.output.flush();
+                self.thre_pending = true;
+            },
+            IER if dlab => self.dlm = value,
+            IER => {
+                // enabling THRE interrupts fires one right away
+                if value & IER_THRE != 0 && self.ier & IER_THRE == 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = value & 0xf;
+            },
+            FCR => {
+                if value & 0x2 != 0 {
+                    self.rx.clear();
+                }
+                self.fcr = value;
+            },
+            LCR => self.lcr = value,
+            MCR => self.mcr = value,
+            SCR => self.scr = value,
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self) {
+        self.poll += 1;
+        if self.poll >= POLL_INTERVAL {
+            self.poll = 0;
+            self.rx.poll();
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        return self.iir() & IIR_NONE == 0;
+    }
+
+    // pending input stays, it is still on its way from the host
+    fn reset(&mut self) {
+        self.ier = 0;
+        self.lcr = 0;
+        self.mcr = 0;
+        self.fcr = 0;
+        self.scr = 0;
+        self.dll = 0;
+        self.dlm = 0;
+        self.thre_pending = false;
+    }
+
+    fn device_tree(&self, base: u32, size: u32) -> Option<Node> {
+        let mut node = Node::at("serial", base);
+        node.string("compatible", "ns16550a")
+            .reg(base, size)
+            .u32("clock-frequency", 0x38_4000);
+        return Some(node);
+    }
+}
+
+/*
+ * Puts the host terminal into raw mode for as long as the guard lives, so
+ * that keys reach the guest unbuffered and without echo.
+ */
+pub struct RawMode {
+    saved: String
+}
+
+impl RawMode {
+    pub fn enable() -> Option<RawMode> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        return Some(RawMode { saved: saved.trim().to_string() });
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        stty(&[&self.saved]);
+    }
+}
+
+fn stty(args: &[&str]) -> Option<String> {
+    let out = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .output()
+        .ok()?;
+    if !out.status.success() {
+        return None;
+    }
+    return String::from_utf8(out.stdout).ok();
+}
@@ -0,0 +1,373 @@
+use crate::bus::BusError;
+use crate::bus::Device;
+use crate::fdt::Node;
+use crate::memory::Memory;
+
+/*
+ * virtio-mmio transport (version 2) with split virtqueues
+ *
+ * The transport owns the common register block and the queues, the device
+ * specific part is plugged in through VirtioDevice. Queue notifications are
+ * latched on register writes and processed in dma() once the bus hands over
+ * guest RAM.
+ */
+
+const MAGIC: u32 = 0x7472_6976; // "virt"
+const VERSION: u32 = 2;
+const VENDOR_ID: u32 = 0x554d_4551; // "QEMU"
+
+const MAGIC_VALUE: u32 = 0x000;
+const VERSION_REG: u32 = 0x004;
+const DEVICE_ID: u32 = 0x008;
+const VENDOR_ID_REG: u32 = 0x00c;
+const DEVICE_FEATURES: u32 = 0x010;
+const DEVICE_FEATURES_SEL: u32 = 0x014;
+const DRIVER_FEATURES: u32 = 0x020;
+const DRIVER_FEATURES_SEL: u32 = 0x024;
+const QUEUE_SEL: u32 = 0x030;
+const QUEUE_NUM_MAX: u32 = 0x034;
+const QUEUE_NUM: u32 = 0x038;
+const QUEUE_READY: u32 = 0x044;
+const QUEUE_NOTIFY: u32 = 0x050;
+const INTERRUPT_STATUS: u32 = 0x060;
+const INTERRUPT_ACK: u32 = 0x064;
+const STATUS: u32 = 0x070;
+const QUEUE_DESC_LOW: u32 = 0x080;
+const QUEUE_DESC_HIGH: u32 = 0x084;
+const QUEUE_DRIVER_LOW: u32 = 0x090;
+const QUEUE_DRIVER_HIGH: u32 = 0x094;
+const QUEUE_DEVICE_LOW: u32 = 0x0a0;
+const QUEUE_DEVICE_HIGH: u32 = 0x0a4;
+const CONFIG_GENERATION: u32 = 0x0fc;
+const CONFIG: u32 = 0x100;
+
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+const STATUS_DRIVER_OK: u32 = 4;
+
+const QUEUE_SIZE_MAX: u32 = 256;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+// most a device hands over in one buffer, whatever room the driver posts,
+// or takes from one at a time
+pub const FILL_MAX: u64 = 64 << 10;
+
+pub const INT_USED_BUFFER: u32 = 1;
+pub const INT_CONFIG_CHANGE: u32 = 2;
+
+/*
+ * Device specific half of a virtio device.
+ */
+pub trait VirtioDevice {
+    fn device_id(&self) -> u32;
+    fn features(&self) -> u64;
+    fn num_queues(&self) -> usize;
+    fn read_config(&self, offset: u32, size: u32) -> u32;
+    fn write_config(&mut self, _offset: u32, _size: u32, _value: u32) {}
+
+    // the driver made new buffers available on queue
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], ram: &mut Memory) -> u32;
+
+    // called on every bus tick with the queues ready, returns interrupt bits
+    fn poll(&mut self, _queues: &mut [Queue], _ram: &mut Memory) -> u32 { 0 }
+
+    fn reset(&mut self) {}
+}
+
+pub struct Desc {
+    pub addr: u32,
+    pub len: u32,
+    pub write: bool
+}
+
+/*
+ * A chain of descriptors popped off the available ring.
+ */
+pub struct Chain {
+    pub head: u16,
+    pub descs: Vec<Desc>
+}
+
+impl Chain {
+    // every buffer of the chain has to lie in RAM
+    pub fn check(&self, ram: &Memory) -> Result<(), BusError> {
+        for d in self.descs.iter() {
+            if !ram.contains(d.addr, d.len as usize) {
+                return Err(BusError::Unmapped(d.addr));
+            }
+        }
+        return Ok(());
+    }
+
+    // device readable part of the chain, concatenated
+    pub fn read_all(&self, ram: &Memory) -> Result<Vec<u8>, BusError> {
+        self.check(ram)?;
+        let mut data = Vec::new();
+        for d in self.descs.iter().filter(|d| !d.write) {
+            let mut buf = vec![0; d.len as usize];
+            ram.read_slice(d.addr, &mut buf)?;
+            data.extend(buf);
+        }
+        return Ok(data);
+    }
+
+    // device readable part in pieces of at most FILL_MAX bytes
+    pub fn read_pieces(&self, ram: &Memory, mut f: impl FnMut(&[u8])) -> Result<(), BusError> {
+        self.check(ram)?;
+        for d in self.descs.iter().filter(|d| !d.write) {
+            let mut at = 0;
+            while at < d.len {
+                let mut buf = vec![0; (d.len - at).min(FILL_MAX as u32) as usize];
+                ram.read_slice(d.addr + at, &mut buf)?;
+                f(&buf);
+                at += buf.len() as u32;
+            }
+        }
+        return Ok(());
+    }
+
+    // scatter data over the device writable part, returns bytes written
+    pub fn write_all(&self, ram: &mut Memory, data: &[u8]) -> Result<u32, BusError> {
+        let mut written = 0;
+        for d in self.descs.iter().filter(|d| d.write) {
+            if written == data.len() { break; }
+            let n = (d.len as usize).min(data.len() - written);
+            ram.write_slice(d.addr, &data[written..written+n])?;
+            written += n;
+        }
+        return Ok(written as u32);
+    }
+
+    // the last byte of the device writable part, if there is one
+    pub fn last_writable_byte(&self) -> Option<u32> {
+        let d = self.descs.iter().rev().find(|d| d.write && d.len > 0)?;
+        return d.addr.checked_add(d.len - 1);
+    }
+
+    // summed in u64, the guest chooses the lengths
+    pub fn readable_len(&self) -> u64 {
+        return self.descs.iter().filter(|d| !d.write).map(|d| d.len as u64).sum();
+    }
+
+    pub fn writable_len(&self) -> u64 {
+        return self.descs.iter().filter(|d| d.write).map(|d| d.len as u64).sum();
+    }
+}
+
+pub struct Queue {
+    pub num: u32,
+    pub ready: bool,
+    desc: u64,
+    driver: u64,
+    device: u64,
+    last_avail: u16
+}
+
+impl Queue {
+    fn new() -> Queue {
+        return Queue { num: 0, ready: false, desc: 0, driver: 0, device: 0, last_avail: 0 };
+    }
+
+    fn addr(addr: u64) -> Result<u32, BusError> {
+        if addr >> 32 != 0 {
+            return Err(BusError::Unmapped(addr as u32));
+        }
+        return Ok(addr as u32);
+    }
+
+    /*
+     * Next chain the driver made available, if any.
+     */
+    pub fn pop(&mut self, ram: &Memory) -> Result<Option<Chain>, BusError> {
+        if !self.ready || self.num == 0 {
+            return Ok(None);
+        }
+        let driver = Queue::addr(self.driver)?;
+        let avail_idx = ram.read(driver + 2, 2)? as u16;
+        if avail_idx == self.last_avail {
+            return Ok(None);
+        }
+        let slot = self.last_avail as u32 % self.num;
+        let head = ram.read(driver + 4 + 2*slot, 2)? as u16;
+        self.last_avail = self.last_avail.wrapping_add(1);
+
+        let table = Queue::addr(self.desc)?;
+        let mut descs = Vec::new();
+        let mut i = head as u32;
+        loop {
+            // a chain can't be longer than the queue, guards against loops
+            if i >= self.num || descs.len() as u32 >= self.num {
+                return Err(BusError::Unmapped(table + 16*i));
+            }
+            let d = table + 16*i;
+            let addr = Queue::addr(ram.read(d, 4)? as u64 | (ram.read(d + 4, 4)? as u64) << 32)?;
+            let len = ram.read(d + 8, 4)?;
+            let flags = ram.read(d + 12, 2)? as u16;
+            let next = ram.read(d + 14, 2)?;
+            descs.push(Desc { addr, len, write: flags & DESC_F_WRITE != 0 });
+            if flags & DESC_F_NEXT == 0 { break; }
+            i = next;
+        }
+        return Ok(Some(Chain { head, descs }));
+    }
+
+    /*
+     * Return a chain to the driver with len bytes written to it.
+     */
+    pub fn push_used(&mut self, ram: &mut Memory, head: u16, len: u32) -> Result<(), BusError> {
+        let device = Queue::addr(self.device)?;
+        let used_idx = ram.read(device + 2, 2)? as u16;
+        let slot = used_idx as u32 % self.num;
+        ram.write(device + 4 + 8*slot, 4, head as u32)?;
+        ram.write(device + 8 + 8*slot, 4, len)?;
+        ram.write(device + 2, 2, used_idx.wrapping_add(1) as u32)?;
+        return Ok(());
+    }
+}
+
+pub struct VirtioMmio<D: VirtioDevice> {
+    device: D,
+    queues: Vec<Queue>,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    status: u32,
+    interrupt_status: u32,
+    notified: u64
+}
+
+impl<D: VirtioDevice> VirtioMmio<D> {
+    pub fn new(device: D) -> VirtioMmio<D> {
+        let queues = (0..device.num_queues()).map(|_| Queue::new()).collect();
+        return VirtioMmio {
+            device,
+            queues,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            status: 0,
+            interrupt_status: 0,
+            notified: 0
+        };
+    }
+
+    fn features(&self) -> u64 {
+        return self.device.features() | VIRTIO_F_VERSION_1;
+    }
+
+    fn queue(&mut self) -> Option<&mut Queue> {
+        return self.queues.get_mut(self.queue_sel as usize);
+    }
+
+    fn reset(&mut self) {
+        for q in self.queues.iter_mut() {
+            *q = Queue::new();
+        }
+        self.driver_features = 0;
+        self.status = 0;
+        self.interrupt_status = 0;
+        self.notified = 0;
+        self.device.reset();
+    }
+}
+
+fn set_low(reg: &mut u64, value: u32) {
+    *reg = (*reg & !0xffff_ffff) | value as u64;
+}
+
+fn set_high(reg: &mut u64, value: u32) {
+    *reg = (*reg & 0xffff_ffff) | (value as u64) << 32;
+}
+
+impl<D: VirtioDevice> Device for VirtioMmio<D> {
+    fn read(&mut self, offset: u32, size: u32) -> u32 {
+        if offset >= CONFIG {
+            return self.device.read_config(offset - CONFIG, size);
+        }
+        return match offset {
+            MAGIC_VALUE => MAGIC,
+            VERSION_REG => VERSION,
+            DEVICE_ID => self.device.device_id(),
+            VENDOR_ID_REG => VENDOR_ID,
+            DEVICE_FEATURES => match self.device_features_sel {
+                0 => self.features() as u32,
+                1 => (self.features() >> 32) as u32,
+                _ => 0
+            },
+            QUEUE_NUM_MAX if self.queue().is_some() => QUEUE_SIZE_MAX,
+            QUEUE_READY => self.queue().map(|q| q.ready as u32).unwrap_or(0),
+            INTERRUPT_STATUS => self.interrupt_status,
+            STATUS => self.status,
+            CONFIG_GENERATION => 0,
+            _ => 0
+        };
+    }
+
+    fn write(&mut self, offset: u32, size: u32, value: u32) {
+        if offset >= CONFIG {
+            self.device.write_config(offset - CONFIG, size, value);
+            return;
+        }
+        match offset {
+            DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            DRIVER_FEATURES => match self.driver_features_sel {
+                0 => set_low(&mut self.driver_features, value),
+                1 => set_high(&mut self.driver_features, value),
+                _ => {}
+            },
+            QUEUE_SEL => self.queue_sel = value,
+            QUEUE_NUM => if let Some(q) = self.queue() { q.num = value.min(QUEUE_SIZE_MAX) },
+            QUEUE_READY => if let Some(q) = self.queue() { q.ready = value & 1 == 1 },
+            QUEUE_DESC_LOW => if let Some(q) = self.queue() { set_low(&mut q.desc, value) },
+            QUEUE_DESC_HIGH => if let Some(q) = self.queue() { set_high(&mut q.desc, value) },
+            QUEUE_DRIVER_LOW => if let Some(q) = self.queue() { set_low(&mut q.driver, value) },
+            QUEUE_DRIVER_HIGH => if let Some(q) = self.queue() { set_high(&mut q.driver, value) },
+            QUEUE_DEVICE_LOW => if let Some(q) = self.queue() { set_low(&mut q.device, value) },
+            QUEUE_DEVICE_HIGH => if let Some(q) = self.queue() { set_high(&mut q.device, value) },
+            QUEUE_NOTIFY if (value as usize) < self.queues.len() => {
+                self.notified |= 1 << value;
+            },
+            INTERRUPT_ACK => self.interrupt_status &= !value,
+            STATUS => {
+                if value == 0 {
+                    self.reset();
+                }
+                else {
+                    self.status = value;
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn interrupt(&self) -> bool {
+        return self.interrupt_status != 0;
+    }
+
+    fn reset(&mut self) {
+        VirtioMmio::reset(self);
+    }
+
+    fn device_tree(&self, base: u32, size: u32) -> Option<Node> {
+        let mut node = Node::at("virtio_mmio", base);
+        node.string("compatible", "virtio,mmio")
+            .reg(base, size);
+        return Some(node);
+    }
+
+    fn dma(&mut self, ram: &mut Memory) {
+        while self.notified != 0 {
+            let queue = self.notified.trailing_zeros() as usize;
+            self.notified &= !(1 << queue);
+            self.interrupt_status |= self.device.notify(queue, &mut self.queues, ram);
+        }
+        if self.status & STATUS_DRIVER_OK != 0 {
+            self.interrupt_status |= self.device.poll(&mut self.queues, ram);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+
+use crate::bus::BusError;
+use crate::devices::virtio::*;
+use crate::memory::Memory;
+
+/*
+ * virtio-blk backed by a host image file
+ *
+ * The image itself is never written: in read-only mode the device offers
+ * VIRTIO_BLK_F_RO and fails writes, in copy-on-write mode written sectors
+ * are kept in an in-memory overlay that lives as long as the emulator.
+ */
+
+const DEVICE_ID_BLOCK: u32 = 2;
+
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+const SECTOR_SIZE: u64 = 512;
+const HEADER_SIZE: usize = 16;
+// what VIRTIO_BLK_T_GET_ID may write
+const ID_SIZE: u64 = 20;
+const ID: &[u8] = b"rustv-virtio-blk";
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum DiskMode {
+    ReadOnly,
+    CopyOnWrite
+}
+
+pub struct VirtioBlk {
+    image: File,
+    size: u64,
+    mode: DiskMode,
+    overlay: HashMap<u64, Vec<u8>>
+}
+
+impl VirtioBlk {
+    pub fn open(path: &str, mode: DiskMode) -> io::Result<VirtioBlk> {
+        let image = File::open(path)?;
+        let size = image.metadata()?.len();
+        return Ok(VirtioBlk { image, size, mode, overlay: HashMap::new() });
+    }
+
+    fn capacity(&self) -> u64 {
+        return self.size.div_ceil(SECTOR_SIZE);
+    }
+
+    fn read_sectors(&mut self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        for (i, chunk) in buf.chunks_mut(SECTOR_SIZE as usize).enumerate() {
+            let sector = sector + i as u64;
+            if let Some(data) = self.overlay.get(&sector) {
+                chunk.copy_from_slice(&data[..chunk.len()]);
+                continue;
+            }
+            // the last sector of an odd sized image is padded with zeroes
+            chunk.fill(0);
+            let offset = sector * SECTOR_SIZE;
+            let n = (self.size.saturating_sub(offset) as usize).min(chunk.len());
+            self.image.seek(SeekFrom::Start(offset))?;
+            self.image.read_exact(&mut chunk[..n])?;
+        }
+        return Ok(());
+    }
+
+    fn write_sectors(&mut self, sector: u64, data: &[u8]) {
+        for (i, chunk) in data.chunks(SECTOR_SIZE as usize).enumerate() {
+            let sector = sector + i as u64;
+            let mut full = vec![0; SECTOR_SIZE as usize];
+            if chunk.len() < full.len() {
+                let _ = self.read_sectors(sector, &mut full);
+            }
+            full[..chunk.len()].copy_from_slice(chunk);
+            self.overlay.insert(sector, full);
+        }
+    }
+
+    fn in_range(&self, sector: u64, len: usize) -> bool {
+        return sector.saturating_mul(SECTOR_SIZE).saturating_add(len as u64)
+            <= self.capacity() * SECTOR_SIZE;
+    }
+
+    /*
+     * Serve one request. The writable part of the chain is the data for
+     * reads followed by the status byte. A chain outside RAM, with more
+     * room than the whole disk or too short for a request only gets
+     * VIRTIO_BLK_S_IOERR, before anything is allocated for it.
+     */
+    fn request(&mut self, chain: &Chain, ram: &mut Memory) -> Result<u32, BusError> {
+        let disk = self.capacity() * SECTOR_SIZE;
+        let writable = chain.writable_len();
+        if chain.check(ram).is_err() || chain.readable_len() > HEADER_SIZE as u64 + disk || writable > disk.max(ID_SIZE) + 1 {
+            return Ok(fail(chain, ram));
+        }
+        let readable = chain.read_all(ram)?;
+        let writable = writable as usize;
+        if readable.len() < HEADER_SIZE || writable == 0 {
+            return Ok(fail(chain, ram));
+        }
+        let kind = u32::from_le_bytes([readable[0], readable[1], readable[2], readable[3]]);
+        let mut sector = [0; 8];
+        sector.copy_from_slice(&readable[8..16]);
+        let sector = u64::from_le_bytes(sector);
+
+        let mut resp = vec![0; writable-1];
+        let status = match kind {
+            VIRTIO_BLK_T_IN => {
+                if !self.in_range(sector, resp.len()) || self.read_sectors(sector, &mut resp).is_err() {
+                    VIRTIO_BLK_S_IOERR
+                }
+                else {
+                    VIRTIO_BLK_S_OK
+                }
+            },
+            VIRTIO_BLK_T_OUT => {
+                let data = &readable[HEADER_SIZE..];
+                if self.mode == DiskMode::ReadOnly || !self.in_range(sector, data.len()) {
+                    VIRTIO_BLK_S_IOERR
+                }
+                else {
+                    self.write_sectors(sector, data);
+                    VIRTIO_BLK_S_OK
+                }
+            },
+            // nothing is ever written back to the image
+            VIRTIO_BLK_T_FLUSH => VIRTIO_BLK_S_OK,
+            VIRTIO_BLK_T_GET_ID => {
+                let n = ID.len().min(resp.len());
+                resp[..n].copy_from_slice(&ID[..n]);
+                VIRTIO_BLK_S_OK
+            },
+            _ => VIRTIO_BLK_S_UNSUPP
+        };
+        resp.push(status);
+        return chain.write_all(ram, &resp);
+    }
+}
+
+/*
+ * Fail a chain that can't be served: VIRTIO_BLK_S_IOERR goes in its last
+ * writable byte when that is in RAM, so the driver doesn't read a stale
+ * status. Returns the bytes written.
+ */
+fn fail(chain: &Chain, ram: &mut Memory) -> u32 {
+    return match chain.last_writable_byte() {
+        Some(addr) if ram.write_slice(addr, &[VIRTIO_BLK_S_IOERR]).is_ok() => 1,
+        _ => 0
+    };
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn device_id(&self) -> u32 {
+        return DEVICE_ID_BLOCK;
+    }
+
+    fn features(&self) -> u64 {
+        let ro = if self.mode == DiskMode::ReadOnly { VIRTIO_BLK_F_RO } else { 0 };
+        return VIRTIO_BLK_F_FLUSH | ro;
+    }
+
+    fn num_queues(&self) -> usize {
+        return 1;
+    }
+
+    fn read_config(&self, offset: u32, _size: u32) -> u32 {
+        return match offset {
+            0 => self.capacity() as u32,
+            4 => (self.capacity() >> 32) as u32,
+            _ => 0
+        };
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], ram: &mut Memory) -> u32 {
+        let q = &mut queues[queue];
+        let mut interrupt = 0;
+        loop {
+            let chain = match q.pop(ram) {
+                Ok(Some(chain)) => chain,
+                Ok(None) => break,
+                Err(e) => {
+                    println!("virtio-blk: bad descriptor: {}", e);
+                    break;
+                }
+            };
+            let len = match self.request(&chain, ram) {
+                Ok(len) => len,
+                Err(e) => {
+                    println!("virtio-blk: bad buffer: {}", e);
+                    0
+                }
+            };
+            if q.push_used(ram, chain.head, len).is_ok() {
+                interrupt = INT_USED_BUFFER;
+            }
+        }
+        return interrupt;
+    }
+}
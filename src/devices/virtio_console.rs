@@ -0,0 +1,118 @@
+use std::io;
+use std::io::Write;
+
+use crate::devices::input::HostInput;
+use crate::devices::virtio::*;
+use crate::memory::Memory;
+
+/*
+ * virtio-console with a single port (hvc0)
+ *
+ * Queue 0 carries host input to the guest and queue 1 guest output to the
+ * host. Input is handed over whenever the driver has receive buffers posted.
+ */
+
+const DEVICE_ID_CONSOLE: u32 = 3;
+
+const RECEIVEQ: usize = 0;
+const TRANSMITQ: usize = 1;
+
+const COLS: u32 = 80;
+const ROWS: u32 = 25;
+
+// how often the host input is polled, in ticks
+const POLL_INTERVAL: u32 = 1024;
+
+pub struct VirtioConsole {
+    input: HostInput,
+    output: Box<dyn Write>,
+    poll: u32
+}
+
+impl VirtioConsole {
+    pub fn new(input: HostInput) -> VirtioConsole {
+        return VirtioConsole::with_output(input, Box::new(io::stdout()));
+    }
+
+    pub fn with_output(input: HostInput, output: Box<dyn Write>) -> VirtioConsole {
+        return VirtioConsole { input, output, poll: 0 };
+    }
+}
+
+impl VirtioDevice for VirtioConsole {
+    fn device_id(&self) -> u32 {
+        return DEVICE_ID_CONSOLE;
+    }
+
+    fn features(&self) -> u64 {
+        return 0;
+    }
+
+    fn num_queues(&self) -> usize {
+        return 2;
+    }
+
+    fn read_config(&self, offset: u32, _size: u32) -> u32 {
+        return match offset {
+            0 => COLS | ROWS << 16,
+            4 => 1, // max_nr_ports
+            _ => 0
+        };
+    }
+
+    fn write_config(&mut self, offset: u32, _size: u32, value: u32) {
+        // emergency write
+        if offset == 8 {
+            let _ = self.output.write_all(&[value as u8]);
+            let _ = self.output.flush();
+        }
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], ram: &mut Memory) -> u32 {
+        if queue != TRANSMITQ {
+            return self.poll(queues, ram);
+        }
+        let q = &mut queues[TRANSMITQ];
+        let mut interrupt = 0;
+        while let Ok(Some(chain)) = q.pop(ram) {
+            let output = &mut self.output;
+            let _ = chain.read_pieces(ram, |data| {
+                let _ = output.write_all(data);
+            });
+            if q.push_used(ram, chain.head, 0).is_ok() {
+                interrupt = INT_USED_BUFFER;
+            }
+        }
+        let _ = self.output.flush();
+        return interrupt;
+    }
+
+    fn poll(&mut self, queues: &mut [Queue], ram: &mut Memory) -> u32 {
+        self.poll += 1;
+        if self.poll >= POLL_INTERVAL {
+            self.poll = 0;
+            self.input.poll();
+        }
+        let q = &mut queues[RECEIVEQ];
+        let mut interrupt = 0;
+        while !self.input.is_empty() {
+            let chain = match q.pop(ram) {
+                Ok(Some(chain)) => chain,
+                _ => break
+            };
+            let room = chain.writable_len().min(FILL_MAX) as usize;
+            let mut data = Vec::new();
+            while data.len() < room {
+                match self.input.pop() {
+                    Some(byte) => data.push(byte),
+                    None => break
+                }
+            }
+            let len = chain.write_all(ram, &data).unwrap_or(0);
+            if q.push_used(ram, chain.head, len).is_ok() {
+                interrupt = INT_USED_BUFFER;
+            }
+        }
+        return interrupt;
+    }
+}
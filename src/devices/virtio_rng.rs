@@ -0,0 +1,55 @@
+use crate::devices::virtio::*;
+use crate::memory::Memory;
+use crate::rng::Rng;
+
+/*
+ * virtio-rng (entropy source)
+ *
+ * Every buffer the driver posts is filled, up to FILL_MAX bytes, from the
+ * emulator's generator, so the guest sees the same bytes on every run for
+ * a given seed.
+ */
+
+const DEVICE_ID_RNG: u32 = 4;
+
+pub struct VirtioRng {
+    rng: Rng
+}
+
+impl VirtioRng {
+    pub fn new(rng: Rng) -> VirtioRng {
+        return VirtioRng { rng };
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn device_id(&self) -> u32 {
+        return DEVICE_ID_RNG;
+    }
+
+    fn features(&self) -> u64 {
+        return 0;
+    }
+
+    fn num_queues(&self) -> usize {
+        return 1;
+    }
+
+    fn read_config(&self, _offset: u32, _size: u32) -> u32 {
+        return 0;
+    }
+
+    fn notify(&mut self, queue: usize, queues: &mut [Queue], ram: &mut Memory) -> u32 {
+        let q = &mut queues[queue];
+        let mut interrupt = 0;
+        while let Ok(Some(chain)) = q.pop(ram) {
+            let mut data = vec![0; chain.writable_len().min(FILL_MAX) as usize];
+            self.rng.fill(&mut data);
+            let len = chain.write_all(ram, &data).unwrap_or(0);
+            if q.push_used(ram, chain.head, len).is_ok() {
+                interrupt = INT_USED_BUFFER;
+            }
+        }
+        return interrupt;
+    }
+}
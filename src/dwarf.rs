@@ -0,0 +1,468 @@
+use crate::elf::{ElfError, ElfFile};
+
+/*
+ * DWARF line tables
+ *
+ * Runs the .debug_line programs (DWARF 2 to 5, 32 and 64-bit) into a table
+ * of address ranges and source lines, for addr2line style lookups. Function
+ * names come from the symbol table, .debug_info isn't read.
+ */
+
+// standard opcodes
+const DW_LNS_COPY: u8 = 1;
+const DW_LNS_ADVANCE_PC: u8 = 2;
+const DW_LNS_ADVANCE_LINE: u8 = 3;
+const DW_LNS_SET_FILE: u8 = 4;
+const DW_LNS_SET_COLUMN: u8 = 5;
+const DW_LNS_NEGATE_STMT: u8 = 6;
+const DW_LNS_SET_BASIC_BLOCK: u8 = 7;
+const DW_LNS_CONST_ADD_PC: u8 = 8;
+const DW_LNS_FIXED_ADVANCE_PC: u8 = 9;
+const DW_LNS_PROLOGUE_END: u8 = 10;
+const DW_LNS_EPILOGUE_BEGIN: u8 = 11;
+const DW_LNS_SET_ISA: u8 = 12;
+
+// extended opcodes
+const DW_LNE_END_SEQUENCE: u8 = 1;
+const DW_LNE_SET_ADDRESS: u8 = 2;
+const DW_LNE_DEFINE_FILE: u8 = 3;
+
+// DWARF 5 entry formats
+const DW_LNCT_PATH: u64 = 1;
+const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+const DW_FORM_BLOCK2: u64 = 0x03;
+const DW_FORM_BLOCK4: u64 = 0x04;
+const DW_FORM_DATA2: u64 = 0x05;
+const DW_FORM_DATA4: u64 = 0x06;
+const DW_FORM_DATA8: u64 = 0x07;
+const DW_FORM_STRING: u64 = 0x08;
+const DW_FORM_BLOCK: u64 = 0x09;
+const DW_FORM_BLOCK1: u64 = 0x0a;
+const DW_FORM_DATA1: u64 = 0x0b;
+const DW_FORM_STRP: u64 = 0x0e;
+const DW_FORM_UDATA: u64 = 0x0f;
+const DW_FORM_DATA16: u64 = 0x1e;
+const DW_FORM_LINE_STRP: u64 = 0x1f;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: u32,
+    pub column: u32
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Row {
+    address: u32,
+    file: usize,
+    line: u32,
+    column: u32
+}
+
+// rows of one contiguous address range, the last one ends it
+#[derive(Clone, Debug)]
+struct Sequence {
+    start: u32,
+    end: u32,
+    rows: Vec<Row>
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LineTable {
+    files: Vec<String>,
+    sequences: Vec<Sequence>
+}
+
+impl LineTable {
+    /*
+     * Line table of the program, None without debug info.
+     */
+    pub fn from_elf(elf: &ElfFile) -> Result<Option<LineTable>, ElfError> {
+        let debug_line = match elf.section(".debug_line") {
+            Some(sh) => elf.section_data(sh)?,
+            None => return Ok(None)
+        };
+        let line_str = match elf.section(".debug_line_str") {
+            Some(sh) => elf.section_data(sh)?,
+            None => &[]
+        };
+        let strs = match elf.section(".debug_str") {
+            Some(sh) => elf.section_data(sh)?,
+            None => &[]
+        };
+        return LineTable::parse(debug_line, line_str, strs).map(Some);
+    }
+
+    pub fn parse(debug_line: &[u8], line_str: &[u8], strs: &[u8]) -> Result<LineTable, ElfError> {
+        let mut table = LineTable::default();
+        // rows with a file the unit doesn't have point here
+        table.file(String::from("??"));
+        let mut r = Reader::new(debug_line);
+        while !r.done() {
+            table.unit(&mut r, line_str, strs)?;
+        }
+        table.sequences.sort_by_key(|s| s.start);
+        return Ok(table);
+    }
+
+    /*
+     * Source location of the row covering addr.
+     */
+    pub fn lookup(&self, addr: u32) -> Option<SourceLocation> {
+        let i = self.sequences.partition_point(|s| s.start <= addr);
+        let seq = self.sequences[..i].iter().rev().find(|s| addr < s.end)?;
+        let j = seq.rows.partition_point(|row| row.address <= addr);
+        let row = &seq.rows[j.checked_sub(1)?];
+        return Some(SourceLocation {
+            file: self.files[row.file].clone(),
+            line: row.line,
+            column: row.column
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.sequences.is_empty();
+    }
+
+    // move every address by bias, for programs loaded away from their link address
+    pub fn relocate(&mut self, bias: u32) {
+        for seq in self.sequences.iter_mut() {
+            seq.start = seq.start.wrapping_add(bias);
+            seq.end = seq.end.wrapping_add(bias);
+            for row in seq.rows.iter_mut() {
+                row.address = row.address.wrapping_add(bias);
+            }
+        }
+    }
+
+    // one line program, r is left at the start of the next
+    fn unit(&mut self, r: &mut Reader, line_str: &[u8], strs: &[u8]) -> Result<(), ElfError> {
+        let mut length = r.u32()? as u64;
+        let mut offset_size = 4;
+        if length == 0xffff_ffff {
+            length = r.u64()?;
+            offset_size = 8;
+        }
+        let mut unit = Reader::new(r.bytes(length as usize)?);
+
+        let version = unit.u16()?;
+        if !(2..=5).contains(&version) {
+            return Err(ElfError::Malformed(format!("DWARF line table version {}", version)));
+        }
+        let mut address_size = 4;
+        if version >= 5 {
+            address_size = unit.u8()?;
+            unit.u8()?; // segment selector size
+        }
+        let header_length = unit.offset(offset_size)?;
+        let mut program = unit.clone();
+        program.skip(header_length as usize)?;
+
+        let min_inst_length = unit.u8()? as u32;
+        if version >= 4 {
+            unit.u8()?; // maximum operations per instruction, only for VLIW
+        }
+        unit.u8()?; // default is_stmt
+        let line_base = unit.u8()? as i8 as i64;
+        let line_range = unit.u8()?;
+        let opcode_base = unit.u8()?;
+        if line_range == 0 || opcode_base == 0 {
+            return Err(ElfError::Malformed("DWARF line table header".to_string()));
+        }
+        let mut opcode_lengths = Vec::new();
+        for _ in 1..opcode_base {
+            opcode_lengths.push(unit.u8()?);
+        }
+
+        // global index of every file of the unit, in the unit's numbering
+        let mut files = Vec::new();
+        if version >= 5 {
+            let dirs = entries(&mut unit, offset_size, line_str, strs)?;
+            let dirs: Vec<String> = dirs.into_iter().map(|(path, _)| path).collect();
+            for (path, dir) in entries(&mut unit, offset_size, line_str, strs)? {
+                files.push(self.file(join(dirs.get(dir as usize), &path)));
+            }
+        }
+        else {
+            let mut dirs = Vec::new();
+            loop {
+                let dir = unit.cstr()?;
+                if dir.is_empty() {
+                    break;
+                }
+                dirs.push(dir);
+            }
+            // numbered from 1, 0 is never valid
+            files.push(self.file(String::from("??")));
+            loop {
+                let name = unit.cstr()?;
+                if name.is_empty() {
+                    break;
+                }
+                let dir = unit.uleb()?;
+                unit.uleb()?; // modification time
+                unit.uleb()?; // length
+                let dir = if dir == 0 { None } else { dirs.get(dir as usize - 1) };
+                files.push(self.file(join(dir, &name)));
+            }
+        }
+
+        let mut state = State::new();
+        let mut rows = Vec::new();
+        while !program.done() {
+            let opcode = program.u8()?;
+            if opcode >= opcode_base {
+                let adjusted = opcode - opcode_base;
+                state.address = state.address.wrapping_add((adjusted / line_range) as u32 * min_inst_length);
+                state.line = state.line.wrapping_add(line_base + (adjusted % line_range) as i64);
+                rows.push(state.row(&files));
+                continue;
+            }
+            match opcode {
+                0 => {
+                    let len = program.uleb()? as usize;
+                    let mut ext = Reader::new(program.bytes(len)?);
+                    if len == 0 {
+                        continue;
+                    }
+                    match ext.u8()? {
+                        DW_LNE_END_SEQUENCE => {
+                            rows.push(state.row(&files));
+                            self.sequence(rows);
+                            rows = Vec::new();
+                            state = State::new();
+                        },
+                        DW_LNE_SET_ADDRESS => {
+                            state.address = match address_size {
+                                8 => ext.u64()? as u32,
+                                _ => ext.u32()?
+                            };
+                        },
+                        DW_LNE_DEFINE_FILE => {
+                            let name = ext.cstr()?;
+                            files.push(self.file(name));
+                        },
+                        // discriminators and vendor extensions
+                        _ => {}
+                    }
+                },
+                DW_LNS_COPY => rows.push(state.row(&files)),
+                DW_LNS_ADVANCE_PC => {
+                    let advance = program.uleb()? as u32;
+                    state.address = state.address.wrapping_add(advance.wrapping_mul(min_inst_length));
+                },
+                DW_LNS_ADVANCE_LINE => state.line = state.line.wrapping_add(program.sleb()?),
+                DW_LNS_SET_FILE => state.file = program.uleb()? as usize,
+                DW_LNS_SET_COLUMN => state.column = program.uleb()? as u32,
+                DW_LNS_NEGATE_STMT | DW_LNS_SET_BASIC_BLOCK | DW_LNS_PROLOGUE_END | DW_LNS_EPILOGUE_BEGIN => {},
+                DW_LNS_CONST_ADD_PC => {
+                    let adjusted = 255 - opcode_base;
+                    state.address = state.address.wrapping_add((adjusted / line_range) as u32 * min_inst_length);
+                },
+                DW_LNS_FIXED_ADVANCE_PC => {
+                    state.address = state.address.wrapping_add(program.u16()? as u32);
+                },
+                DW_LNS_SET_ISA => { program.uleb()?; },
+                // opcodes from a newer standard, skip their operands
+                _ => {
+                    for _ in 0..opcode_lengths[opcode as usize - 1] {
+                        program.uleb()?;
+                    }
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn file(&mut self, name: String) -> usize {
+        if let Some(i) = self.files.iter().position(|f| *f == name) {
+            return i;
+        }
+        self.files.push(name);
+        return self.files.len() - 1;
+    }
+
+    fn sequence(&mut self, rows: Vec<Row>) {
+        let (start, end) = match (rows.first(), rows.last()) {
+            (Some(first), Some(last)) if first.address < last.address => (first.address, last.address),
+            _ => return
+        };
+        self.sequences.push(Sequence { start, end, rows });
+    }
+}
+
+struct State {
+    address: u32,
+    file: usize,
+    line: i64,
+    column: u32
+}
+
+impl State {
+    fn new() -> State {
+        return State { address: 0, file: 1, line: 1, column: 0 };
+    }
+
+    // rows point into the table's file list rather than the unit's
+    fn row(&self, files: &[usize]) -> Row {
+        return Row {
+            address: self.address,
+            file: files.get(self.file).copied().unwrap_or(0),
+            line: self.line.clamp(0, u32::MAX as i64) as u32,
+            column: self.column
+        };
+    }
+}
+
+/*
+ * DWARF 5 directory or file entries: the path and directory index of each.
+ */
+fn entries(r: &mut Reader, offset_size: usize, line_str: &[u8], strs: &[u8]) -> Result<Vec<(String, u64)>, ElfError> {
+    let mut format = Vec::new();
+    for _ in 0..r.u8()? {
+        format.push((r.uleb()?, r.uleb()?));
+    }
+    let mut entries = Vec::new();
+    for _ in 0..r.uleb()? {
+        let mut path = String::new();
+        let mut dir = 0;
+        for (content, form) in format.iter() {
+            match (*content, *form) {
+                (DW_LNCT_PATH, DW_FORM_STRING) => path = r.cstr()?,
+                (DW_LNCT_PATH, DW_FORM_LINE_STRP) => path = string_at(line_str, r.offset(offset_size)?)?,
+                (DW_LNCT_PATH, DW_FORM_STRP) => path = string_at(strs, r.offset(offset_size)?)?,
+                (DW_LNCT_DIRECTORY_INDEX, DW_FORM_DATA1) => dir = r.u8()? as u64,
+                (DW_LNCT_DIRECTORY_INDEX, DW_FORM_DATA2) => dir = r.u16()? as u64,
+                (DW_LNCT_DIRECTORY_INDEX, DW_FORM_UDATA) => dir = r.uleb()?,
+                (_, form) => skip_form(r, form, offset_size)?
+            }
+        }
+        entries.push((path, dir));
+    }
+    return Ok(entries);
+}
+
+fn skip_form(r: &mut Reader, form: u64, offset_size: usize) -> Result<(), ElfError> {
+    let len = match form {
+        DW_FORM_DATA1 => 1,
+        DW_FORM_DATA2 => 2,
+        DW_FORM_DATA4 => 4,
+        DW_FORM_DATA8 => 8,
+        DW_FORM_DATA16 => 16,
+        DW_FORM_UDATA => { r.uleb()?; 0 },
+        DW_FORM_STRING => { r.cstr()?; 0 },
+        DW_FORM_STRP | DW_FORM_LINE_STRP => offset_size,
+        DW_FORM_BLOCK => r.uleb()? as usize,
+        DW_FORM_BLOCK1 => r.u8()? as usize,
+        DW_FORM_BLOCK2 => r.u16()? as usize,
+        DW_FORM_BLOCK4 => r.u32()? as usize,
+        _ => return Err(ElfError::Malformed(format!("DWARF form {:#x} in line table header", form)))
+    };
+    return r.skip(len);
+}
+
+fn join(dir: Option<&String>, name: &str) -> String {
+    return match dir {
+        Some(dir) if !name.starts_with('/') && !dir.is_empty() => format!("{}/{}", dir, name),
+        _ => name.to_string()
+    };
+}
+
+fn string_at(table: &[u8], offset: u64) -> Result<String, ElfError> {
+    let mut r = Reader::new(table);
+    r.skip(offset as usize)?;
+    return r.cstr();
+}
+
+// bounds checked little endian reader
+#[derive(Clone)]
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8]) -> Reader<'a> {
+        return Reader { data, pos: 0 };
+    }
+
+    fn done(&self) -> bool {
+        return self.pos >= self.data.len();
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ElfError> {
+        let end = self.pos.checked_add(len).ok_or(ElfError::Truncated("line table"))?;
+        let bytes = self.data.get(self.pos..end).ok_or(ElfError::Truncated("line table"))?;
+        self.pos = end;
+        return Ok(bytes);
+    }
+
+    fn skip(&mut self, len: usize) -> Result<(), ElfError> {
+        return self.bytes(len).map(|_| ());
+    }
+
+    fn u8(&mut self) -> Result<u8, ElfError> {
+        return Ok(self.bytes(1)?[0]);
+    }
+
+    fn u16(&mut self) -> Result<u16, ElfError> {
+        let b = self.bytes(2)?;
+        return Ok(u16::from_le_bytes([b[0], b[1]]));
+    }
+
+    fn u32(&mut self) -> Result<u32, ElfError> {
+        let b = self.bytes(4)?;
+        return Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+    }
+
+    fn u64(&mut self) -> Result<u64, ElfError> {
+        let lo = self.u32()? as u64;
+        let hi = self.u32()? as u64;
+        return Ok(hi << 32 | lo);
+    }
+
+    fn offset(&mut self, offset_size: usize) -> Result<u64, ElfError> {
+        return if offset_size == 8 { self.u64() } else { self.u32().map(|v| v as u64) };
+    }
+
+    fn uleb(&mut self) -> Result<u64, ElfError> {
+        let mut val = 0;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                val |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+    }
+
+    fn sleb(&mut self) -> Result<i64, ElfError> {
+        let mut val = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                val |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    val |= -1 << shift;
+                }
+                return Ok(val);
+            }
+        }
+    }
+
+    fn cstr(&mut self) -> Result<String, ElfError> {
+        let rest = self.data.get(self.pos..).unwrap_or(&[]);
+        let len = rest.iter().position(|b| *b == 0).ok_or(ElfError::Truncated("line table"))?;
+        self.pos += len + 1;
+        return Ok(String::from_utf8_lossy(&rest[..len]).into_owned());
+    }
+}
@@ -1,116 +1,673 @@
+use std::fmt;
+
 use crate::Core;
-use crate::constants::START_ADDR;
+use crate::memory;
+use crate::memory::PAGE_SIZE;
+use crate::symbols::*;
+
+/*
+ * ELF32 little-endian RISC-V reader
+ *
+ * Every read is bounds checked, so a truncated or corrupted file comes back
+ * as an ElfError instead of bringing the emulator down.
+ */
+
+pub const ELFCLASS32: u8 = 1;
+pub const ELFDATA2LSB: u8 = 1;
+pub const EV_CURRENT: u32 = 1;
+pub const EM_RISCV: u16 = 243;
+
+pub const ET_EXEC: u16 = 2;
+pub const ET_DYN: u16 = 3;
+pub const ET_CORE: u16 = 4;
+
+pub const EHDR_SIZE: usize = 52;
+pub const PHDR_SIZE: usize = 32;
+pub const SHDR_SIZE: usize = 40;
+pub const SYM_SIZE: usize = 16;
+pub const RELA_SIZE: usize = 12;
+pub const DYN_SIZE: usize = 8;
+
+pub const PT_LOAD: u32 = 1;
+pub const PT_DYNAMIC: u32 = 2;
+pub const PT_NOTE: u32 = 4;
+pub const PT_PHDR: u32 = 6;
+
+// segment permissions
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+pub const SHT_PROGBITS: u32 = 1;
+pub const SHT_SYMTAB: u32 = 2;
+pub const SHT_STRTAB: u32 = 3;
+pub const SHT_RELA: u32 = 4;
+pub const SHT_NOBITS: u32 = 8;
+pub const SHT_DYNSYM: u32 = 11;
+pub const SHT_RISCV_ATTRIBUTES: u32 = 0x7000_0003;
+
+pub const SHF_WRITE: u32 = 1;
+pub const SHF_ALLOC: u32 = 2;
+pub const SHF_EXECINSTR: u32 = 4;
+
+// e_flags
+pub const EF_RISCV_RVC: u32 = 0x1;
+pub const EF_RISCV_FLOAT_ABI: u32 = 0x6;
+pub const EF_RISCV_FLOAT_ABI_SINGLE: u32 = 0x2;
+pub const EF_RISCV_FLOAT_ABI_DOUBLE: u32 = 0x4;
+pub const EF_RISCV_FLOAT_ABI_QUAD: u32 = 0x6;
+pub const EF_RISCV_RVE: u32 = 0x8;
+
+// build attributes, odd tags have string values and even ones numbers
+const ATTRIBUTES_VERSION: u8 = b'A';
+const TAG_FILE: u64 = 1;
+pub const TAG_RISCV_ARCH: u64 = 5;
+
+// PT_DYNAMIC tags
+pub const DT_NULL: u32 = 0;
+pub const DT_PLTRELSZ: u32 = 2;
+pub const DT_STRTAB: u32 = 5;
+pub const DT_SYMTAB: u32 = 6;
+pub const DT_RELA: u32 = 7;
+pub const DT_RELASZ: u32 = 8;
+pub const DT_STRSZ: u32 = 10;
+pub const DT_PLTREL: u32 = 20;
+pub const DT_JMPREL: u32 = 23;
+
+// dynamic relocations
+pub const R_RISCV_NONE: u32 = 0;
+pub const R_RISCV_32: u32 = 1;
+pub const R_RISCV_RELATIVE: u32 = 3;
+pub const R_RISCV_JUMP_SLOT: u32 = 5;
+
+#[derive(Debug, PartialEq)]
+pub enum ElfError {
+    NotElf,
+    Class(u8),
+    Endianness(u8),
+    Version(u32),
+    Machine(u16),
+    Truncated(&'static str),
+    Malformed(String),
+    Segment(u32),
+    // relocation type and r_offset
+    Relocation(u32, u32),
+    Undefined(String)
+}
+
+impl fmt::Display for ElfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ElfError::NotElf => write!(f, "Not an ELF file"),
+            ElfError::Class(c) => write!(f, "Can only run 32-bit programs (ELF class {})", c),
+            ElfError::Endianness(d) => write!(f, "Can only run little-endian programs (ELF data {})", d),
+            ElfError::Version(v) => write!(f, "Unsupported ELF version {}", v),
+            ElfError::Machine(m) => write!(f, "ELF not RISC-V architecture (machine {})", m),
+            ElfError::Truncated(what) => write!(f, "Truncated ELF file: {} out of bounds", what),
+            ElfError::Malformed(msg) => write!(f, "Malformed ELF file: {}", msg),
+            ElfError::Segment(addr) => write!(f, "Segment at {:#010x} doesn't fit in memory", addr),
+            ElfError::Relocation(kind, offset) => write!(f, "Unsupported relocation type {} at {:#010x}", kind, offset),
+            ElfError::Undefined(name) => write!(f, "Relocation against undefined symbol {}", name)
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub e_type: u16,
+    pub e_machine: u16,
+    pub e_version: u32,
+    pub e_entry: u32,
+    pub e_phoff: u32,
+    pub e_shoff: u32,
+    pub e_flags: u32,
+    pub e_ehsize: u16,
+    pub e_phentsize: u16,
+    pub e_phnum: u16,
+    pub e_shentsize: u16,
+    pub e_shnum: u16,
+    pub e_shstrndx: u16
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub p_offset: u32,
+    pub p_vaddr: u32,
+    pub p_paddr: u32,
+    pub p_filesz: u32,
+    pub p_memsz: u32,
+    pub p_flags: u32,
+    pub p_align: u32
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SectionHeader {
+    pub name: String,
+    pub sh_name: u32,
+    pub sh_type: u32,
+    pub sh_flags: u32,
+    pub sh_addr: u32,
+    pub sh_offset: u32,
+    pub sh_size: u32,
+    pub sh_link: u32,
+    pub sh_info: u32,
+    pub sh_addralign: u32,
+    pub sh_entsize: u32
+}
+
+// a loaded segment, perms as in crate::memory
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segment {
+    pub base: u32,
+    pub size: u32,
+    pub perms: u8
+}
+
+impl ProgramHeader {
+    pub fn perms(&self) -> u8 {
+        let mut perms = 0;
+        if self.p_flags & PF_R != 0 {
+            perms |= memory::R;
+        }
+        if self.p_flags & PF_W != 0 {
+            perms |= memory::W;
+        }
+        if self.p_flags & PF_X != 0 {
+            perms |= memory::X;
+        }
+        return perms;
+    }
+}
+
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    pub header: Header,
+    pub phdrs: Vec<ProgramHeader>,
+    pub shdrs: Vec<SectionHeader>
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<ElfFile<'a>, ElfError> {
+        if data.len() < 4 || data[0..4] != [0x7f, b'E', b'L', b'F'] {
+            return Err(ElfError::NotElf);
+        }
+        let ident = slice(data, 0, 16, "identification")?;
+        if ident[4] != ELFCLASS32 {
+            return Err(ElfError::Class(ident[4]));
+        }
+        if ident[5] != ELFDATA2LSB {
+            return Err(ElfError::Endianness(ident[5]));
+        }
+        if ident[6] as u32 != EV_CURRENT {
+            return Err(ElfError::Version(ident[6] as u32));
+        }
+
+        let ehdr = slice(data, 0, EHDR_SIZE, "file header")?;
+        let header = Header {
+            e_type: u16_at(ehdr, 0x10),
+            e_machine: u16_at(ehdr, 0x12),
+            e_version: u32_at(ehdr, 0x14),
+            e_entry: u32_at(ehdr, 0x18),
+            e_phoff: u32_at(ehdr, 0x1c),
+            e_shoff: u32_at(ehdr, 0x20),
+            e_flags: u32_at(ehdr, 0x24),
+            e_ehsize: u16_at(ehdr, 0x28),
+            e_phentsize: u16_at(ehdr, 0x2a),
+            e_phnum: u16_at(ehdr, 0x2c),
+            e_shentsize: u16_at(ehdr, 0x2e),
+            e_shnum: u16_at(ehdr, 0x30),
+            e_shstrndx: u16_at(ehdr, 0x32)
+        };
+        if header.e_machine != EM_RISCV {
+            return Err(ElfError::Machine(header.e_machine));
+        }
+        if header.e_version != EV_CURRENT {
+            return Err(ElfError::Version(header.e_version));
+        }
+        if (header.e_ehsize as usize) < EHDR_SIZE {
+            return Err(ElfError::Malformed(format!("header size {}", header.e_ehsize)));
+        }
+
+        let mut phdrs = Vec::new();
+        if header.e_phnum > 0 && (header.e_phentsize as usize) < PHDR_SIZE {
+            return Err(ElfError::Malformed(format!("program header size {}", header.e_phentsize)));
+        }
+        for i in 0..header.e_phnum as usize {
+            let offset = header.e_phoff as usize + i * header.e_phentsize as usize;
+            let ph = slice(data, offset, PHDR_SIZE, "program header")?;
+            phdrs.push(ProgramHeader {
+                p_type: u32_at(ph, 0x00),
+                p_offset: u32_at(ph, 0x04),
+                p_vaddr: u32_at(ph, 0x08),
+                p_paddr: u32_at(ph, 0x0c),
+                p_filesz: u32_at(ph, 0x10),
+                p_memsz: u32_at(ph, 0x14),
+                p_flags: u32_at(ph, 0x18),
+                p_align: u32_at(ph, 0x1c)
+            });
+        }
+
+        let mut shdrs = Vec::new();
+        if header.e_shnum > 0 && (header.e_shentsize as usize) < SHDR_SIZE {
+            return Err(ElfError::Malformed(format!("section header size {}", header.e_shentsize)));
+        }
+        for i in 0..header.e_shnum as usize {
+            let offset = header.e_shoff as usize + i * header.e_shentsize as usize;
+            let sh = slice(data, offset, SHDR_SIZE, "section header")?;
+            shdrs.push(SectionHeader {
+                name: String::new(),
+                sh_name: u32_at(sh, 0x00),
+                sh_type: u32_at(sh, 0x04),
+                sh_flags: u32_at(sh, 0x08),
+                sh_addr: u32_at(sh, 0x0c),
+                sh_offset: u32_at(sh, 0x10),
+                sh_size: u32_at(sh, 0x14),
+                sh_link: u32_at(sh, 0x18),
+                sh_info: u32_at(sh, 0x1c),
+                sh_addralign: u32_at(sh, 0x20),
+                sh_entsize: u32_at(sh, 0x24)
+            });
+        }
+
+        let mut elf = ElfFile { data, header, phdrs, shdrs };
+        if !elf.shdrs.is_empty() {
+            let shstrndx = elf.header.e_shstrndx as usize;
+            let shstrtab = elf.shdrs.get(shstrndx)
+                .ok_or_else(|| ElfError::Malformed(format!("section name table index {}", shstrndx)))?;
+            let names = elf.section_data(shstrtab)?;
+            for sh in elf.shdrs.iter_mut() {
+                sh.name = cstr(names, sh.sh_name as usize, "section name")?;
+            }
+        }
+        return Ok(elf);
+    }
+
+    pub fn section(&self, name: &str) -> Option<&SectionHeader> {
+        return self.shdrs.iter().find(|sh| sh.name == name);
+    }
+
+    // contents of a section as stored in the file
+    pub fn section_data(&self, sh: &SectionHeader) -> Result<&'a [u8], ElfError> {
+        return slice(self.data, sh.sh_offset as usize, sh.sh_size as usize, "section");
+    }
+
+    // the part of a segment that is stored in the file
+    pub fn segment_data(&self, ph: &ProgramHeader) -> Result<&'a [u8], ElfError> {
+        return slice(self.data, ph.p_offset as usize, ph.p_filesz as usize, "segment");
+    }
 
-pub fn load_elf(core: &mut Core, elf: &Vec<u8>) {
     /*
-     * ELF Header
+     * File contents at link address addr, which has to lie in the stored
+     * part of a PT_LOAD segment.
      */
-    if (elf[0x0],elf[0x1],elf[0x2],elf[0x3]) != (0x7f,0x45,0x4c,0x46) {
-        panic!("File not ELF");
-    }
-    if elf[0x4] != 1 {
-        panic!("Can only run 32bit programs.");
+    pub fn vaddr_data(&self, addr: u32, len: u32) -> Result<&'a [u8], ElfError> {
+        let ph = self.phdrs.iter()
+            .filter(|ph| ph.p_type == PT_LOAD)
+            .find(|ph| addr >= ph.p_vaddr && addr as u64 + len as u64 <= ph.p_vaddr as u64 + ph.p_filesz as u64)
+            .ok_or_else(|| ElfError::Malformed(format!("address {:#010x} not in a segment", addr)))?;
+        return slice(self.segment_data(ph)?, (addr - ph.p_vaddr) as usize, len as usize, "segment");
     }
-    if elf[0x12] != 0xf3 {
-        panic!("ELF not RISC-V architecture.");
+
+    // (tag, value) entries of the PT_DYNAMIC segment up to DT_NULL, empty without one
+    pub fn dynamic(&self) -> Result<Vec<(u32, u32)>, ElfError> {
+        let mut entries = Vec::new();
+        if let Some(ph) = self.phdrs.iter().find(|ph| ph.p_type == PT_DYNAMIC) {
+            for entry in self.segment_data(ph)?.chunks_exact(DYN_SIZE) {
+                if u32_at(entry, 0) == DT_NULL {
+                    break;
+                }
+                entries.push((u32_at(entry, 0), u32_at(entry, 4)));
+            }
+        }
+        return Ok(entries);
     }
 
-    let e_phentsize = take2(&elf, 0x2a) as usize; // size of entry
-    let e_phnum = take2(&elf, 0x2c); // number of entries
-    let e_phoff = take4(&elf, 0x1c) as usize; // program header offset
+    /*
+     * Symbols of every symbol table in the file, each read with its own
+     * string table. The null symbol at the start of a table is skipped.
+     */
+    pub fn symbols(&self) -> Result<SymbolTable, ElfError> {
+        let mut symbols = Vec::new();
+        for symtab in self.shdrs.iter().filter(|sh| sh.sh_type == SHT_SYMTAB || sh.sh_type == SHT_DYNSYM) {
+            symbols.extend(self.symbol_table(symtab)?.into_iter().skip(1));
+        }
+        return Ok(SymbolTable::new(symbols));
+    }
 
     /*
-     * Program Header
+     * Tag_RISCV_arch from .riscv.attributes, the ISA the program was built
+     * for, like "rv32i2p1_m2p0_zicsr2p0". None if the file doesn't say.
      */
-    let mut index = e_phoff;
-    for _ in 0..e_phnum {
-        let p_offset = take4(&elf, index+0x04) as usize;
-        let p_vaddr = take4(&elf, index+0x08);
-        let p_memsz = take4(&elf, index+0x14) as usize;
-
-        for i in 0..p_memsz {
-            // we imagine 0x0 = 0x80000000
-            core.memory[(p_vaddr as u32-START_ADDR) as usize+i] = elf[p_offset+i];
-        }
-        index += e_phentsize;
-    }
-}
-
-// very hacky very bad k
-pub fn get_riscv_tests_addrs(elf: &Vec<u8>) -> (u32, u32) {
-    let e_shstrndx = take2(&elf, 0x32); // size of entry
-    let e_shoff = take4(&elf, 0x20) as usize; // start of section header
-    let e_shentsize = take2(&elf, 0x2e) as usize; // size of entry
-    let e_shnum = take2(&elf, 0x30); // size of entry
-
-    // table for section header names
-    let shstrtab_addr = e_shoff as i32 + e_shentsize as i32 * e_shstrndx as i32 + 0x10;
-    let shstrtab = take4(&elf, shstrtab_addr as usize) as usize;
-
-    let mut index = e_shoff;
-    let mut sht_symtab = 0;
-    let mut sht_size = 0;
-    let mut sht_entsize = 0;
-    let mut strtab = 0;
-    for _ in 0..e_shnum {
-        let sh_name = take4(&elf, index) as usize;
-        let sh_size = take4(&elf, index+0x14) as usize;
-        let sh_type = take4(&elf, index+0x04);
-        let sh_offset = take4(&elf, index+0x10) as usize;
-        let sh_entsize = take4(&elf, index+0x24) as usize;
-
-        if sh_type == 0x2 { // symtab
-            sht_symtab = sh_offset;
-            sht_size = sh_size;
-            sht_entsize = sh_entsize;
-        }
-        else if sh_type == 0x3 && read(&elf, shstrtab+sh_name) == ".strtab" {
-            strtab = sh_offset;
-        }
-
-        index += e_shentsize;
-    }
-
-    let mut ix = sht_symtab;
-    let mut fail_addr = 0;
-    let mut pass_addr = 0;
-    while ix < ix+sht_size {
-        let st_name = take4(&elf, ix) as usize;
-        let st_value = take4(&elf, ix+0x04);
-        if st_name != 0 {
-            let name = read(&elf, strtab+st_name);
-            if name == "fail" {
-                fail_addr = st_value;
-                if pass_addr != 0 { break };
+    pub fn arch(&self) -> Result<Option<String>, ElfError> {
+        let section = match self.shdrs.iter().find(|sh| sh.sh_type == SHT_RISCV_ATTRIBUTES) {
+            Some(sh) => self.section_data(sh)?,
+            None => return Ok(None)
+        };
+        let malformed = |what: &str| ElfError::Malformed(format!("attributes {}", what));
+        if section.first() != Some(&ATTRIBUTES_VERSION) {
+            return Err(malformed("version"));
+        }
+        let mut pos = 1;
+        while pos < section.len() {
+            // vendor subsection: length, name and its subsubsections
+            let len = u32_at(slice(section, pos, 4, "attributes")?, 0) as usize;
+            let sub = slice(section, pos, len, "attributes")?;
+            pos += len.max(1);
+            let vendor = cstr(sub, 4, "attributes vendor")?;
+            if vendor != "riscv" {
+                continue;
             }
-            else if name == "pass" {
-                pass_addr = st_value;
-                if fail_addr != 0 { break };
+            let mut at = 4 + vendor.len() + 1;
+            while at < sub.len() {
+                let start = at;
+                let tag = uleb(sub, &mut at)?;
+                let size = u32_at(slice(sub, at, 4, "attributes")?, 0) as usize;
+                let end = start.checked_add(size).filter(|end| *end > at && *end <= sub.len())
+                    .ok_or_else(|| malformed("size"))?;
+                at += 4;
+                if tag == TAG_FILE {
+                    while at < end {
+                        let tag = uleb(sub, &mut at)?;
+                        if tag & 1 == 0 {
+                            uleb(sub, &mut at)?;
+                            continue;
+                        }
+                        let value = cstr(sub, at, "attribute")?;
+                        at += value.len() + 1;
+                        if tag == TAG_RISCV_ARCH {
+                            return Ok(Some(value));
+                        }
+                    }
+                }
+                at = end;
             }
         }
-        ix += sht_entsize;
+        return Ok(None);
+    }
+
+    // every entry of one symbol table, by index
+    fn symbol_table(&self, symtab: &SectionHeader) -> Result<Vec<Symbol>, ElfError> {
+        let strtab = self.shdrs.get(symtab.sh_link as usize)
+            .ok_or_else(|| ElfError::Malformed(format!("symbol string table index {}", symtab.sh_link)))?;
+        let strings = self.section_data(strtab)?;
+        let mut symbols = Vec::new();
+        for sym in self.section_data(symtab)?.chunks_exact(SYM_SIZE) {
+            symbols.push(symbol(sym, strings)?);
+        }
+        return Ok(symbols);
     }
-    return (pass_addr-START_ADDR, fail_addr-START_ADDR);
 }
 
-fn take4(elf: &Vec<u8>, i: usize) -> u32 {
-    return ((elf[i+3] as u32) << 24)
-        | ((elf[i+2] as u32) << 16)
-        | ((elf[i+1] as u32) << 8)
-        | (elf[i] as u32);
+fn symbol(sym: &[u8], strings: &[u8]) -> Result<Symbol, ElfError> {
+    return Ok(Symbol {
+        name: cstr(strings, u32_at(sym, 0) as usize, "symbol name")?,
+        value: u32_at(sym, 4),
+        size: u32_at(sym, 8),
+        info: sym[12],
+        other: sym[13],
+        shndx: u16_at(sym, 14)
+    });
 }
 
-fn take2(elf: &Vec<u8>, i: usize) -> u32 {
-    return ((elf[i+1] as u32) << 8)
-            | (elf[i] as u32);
+/*
+ * Extensions named in an arch string, lower case and without versions:
+ * "rv32imac" and "rv32i2p1_m2p0_a2p1_c2p0" both give i, m, a and c. "g"
+ * stands for imafd with zicsr and zifencei.
+ */
+pub fn arch_extensions(arch: &str) -> Result<Vec<String>, ElfError> {
+    let malformed = || ElfError::Malformed(format!("arch string {}", arch));
+    let arch = arch.to_ascii_lowercase();
+    let rest = arch.strip_prefix("rv32").ok_or_else(malformed)?;
+    let mut extensions: Vec<String> = Vec::new();
+    for (i, part) in rest.split('_').enumerate() {
+        if part.is_empty() {
+            return Err(malformed());
+        }
+        if i > 0 && matches!(part.as_bytes()[0], b'z' | b's' | b'x') {
+            // multi-letter, the version is whatever digits and p<n> end it
+            let name = part.trim_end_matches(|c: char| c.is_ascii_digit());
+            let name = match name.strip_suffix('p') {
+                Some(major) if name.len() < part.len() && major.ends_with(|c: char| c.is_ascii_digit()) => {
+                    major.trim_end_matches(|c: char| c.is_ascii_digit())
+                },
+                _ => name
+            };
+            add(&mut extensions, name);
+            continue;
+        }
+        // single letters, each with an optional <major>p<minor>
+        let chars: Vec<char> = part.chars().collect();
+        let mut j = 0;
+        while j < chars.len() {
+            let c = chars[j];
+            if !c.is_ascii_lowercase() {
+                return Err(malformed());
+            }
+            j += 1;
+            let digits = chars[j..].iter().take_while(|c| c.is_ascii_digit()).count();
+            j += digits;
+            if digits > 0 && chars.get(j) == Some(&'p') && chars.get(j + 1).is_some_and(|c| c.is_ascii_digit()) {
+                j += 1;
+                j += chars[j..].iter().take_while(|c| c.is_ascii_digit()).count();
+            }
+            if c == 'g' {
+                for ext in ["i", "m", "a", "f", "d", "zicsr", "zifencei"].iter() {
+                    add(&mut extensions, ext);
+                }
+            }
+            else {
+                add(&mut extensions, &c.to_string());
+            }
+        }
+    }
+    return Ok(extensions);
+}
+
+fn add(extensions: &mut Vec<String>, name: &str) {
+    if !extensions.iter().any(|e| e == name) {
+        extensions.push(name.to_string());
+    }
 }
 
-fn read(elf: &Vec<u8>, i: usize) -> String {
-    let mut name = String::from("");
-    let mut index = i;
-    while elf[index] != 0 {
-        name.push(elf[index] as char);
-        index += 1;
+/*
+ * Extensions the e_flags ABI can't do without: compressed instructions
+ * when linked with RVC code, the floating point registers for a hard-float
+ * ABI, and the E base.
+ */
+pub fn abi_extensions(e_flags: u32) -> Vec<&'static str> {
+    let mut extensions = Vec::new();
+    if e_flags & EF_RISCV_RVE != 0 {
+        extensions.push("e");
+    }
+    match e_flags & EF_RISCV_FLOAT_ABI {
+        EF_RISCV_FLOAT_ABI_SINGLE => extensions.push("f"),
+        EF_RISCV_FLOAT_ABI_DOUBLE => extensions.push("d"),
+        EF_RISCV_FLOAT_ABI_QUAD => extensions.push("q"),
+        _ => {}
+    }
+    if e_flags & EF_RISCV_RVC != 0 {
+        extensions.push("c");
     }
-    return name;
+    return extensions;
+}
+
+/*
+ * How far the program is moved from its link addresses: nothing for ET_EXEC,
+ * an ET_DYN image has its lowest page put at base.
+ */
+pub fn load_bias(elf: &ElfFile, base: u32) -> u32 {
+    if elf.header.e_type != ET_DYN {
+        return 0;
+    }
+    let low = elf.phdrs.iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .map(|ph| ph.p_vaddr)
+        .min()
+        .unwrap_or(0);
+    return base.wrapping_sub(low & !(PAGE_SIZE as u32 - 1));
+}
+
+/*
+ * Copy the PT_LOAD segments into memory: p_filesz bytes from the file, the
+ * rest up to p_memsz (the BSS) zeroed. The segments are recorded in the core
+ * with their permissions. Position-independent (ET_DYN) programs are loaded
+ * at base and have their dynamic relocations applied. Returns the load bias.
+ */
+pub fn load_elf(core: &mut Core, elf: &ElfFile, base: u32) -> Result<u32, ElfError> {
+    let bias = load_bias(elf, base);
+    for ph in elf.phdrs.iter().filter(|ph| ph.p_type == PT_LOAD) {
+        let data = elf.segment_data(ph)?;
+        let addr = ph.p_vaddr.wrapping_add(bias);
+        if ph.p_memsz < ph.p_filesz {
+            return Err(ElfError::Malformed(format!("segment at {:#010x} is smaller in memory than in the file", ph.p_vaddr)));
+        }
+        // the end has to fit in a u32 too, the heap starts there
+        let end = addr.checked_add(ph.p_memsz).ok_or(ElfError::Segment(addr))?;
+        if !core.bus.memory.contains(addr, ph.p_memsz as usize) {
+            return Err(ElfError::Segment(addr));
+        }
+        core.bus.memory.load(addr, data);
+        let bss = addr + ph.p_filesz;
+        core.bus.memory.zero(bss, (end - bss) as u64).map_err(|_| ElfError::Segment(addr))?;
+        // loads ignore permissions, so relocations can still be applied below
+        core.bus.memory.protect(addr, ph.p_memsz as u64, ph.perms());
+        core.segments.push(Segment { base: addr, size: ph.p_memsz, perms: ph.perms() });
+    }
+    if elf.header.e_type == ET_DYN {
+        relocate(core, elf, bias)?;
+    }
+    return Ok(bias);
+}
+
+/*
+ * Apply the relocations in the allocated SHT_RELA sections (.rela.dyn and
+ * .rela.plt), or when there are none, as in a stripped static-PIE, the
+ * DT_RELA and DT_JMPREL tables named by PT_DYNAMIC. Symbols are resolved
+ * statically, there is no dynamic linker to defer to.
+ */
+fn relocate(core: &mut Core, elf: &ElfFile, bias: u32) -> Result<(), ElfError> {
+    let sections: Vec<&SectionHeader> = elf.shdrs.iter()
+        .filter(|sh| sh.sh_type == SHT_RELA && sh.sh_flags & SHF_ALLOC != 0)
+        .collect();
+    for rela in sections.iter() {
+        let symbols = match elf.shdrs.get(rela.sh_link as usize) {
+            Some(symtab) if rela.sh_link != 0 => elf.symbol_table(symtab)?,
+            _ => Vec::new()
+        };
+        let lookup = |index: usize| symbols.get(index).cloned()
+            .ok_or_else(|| ElfError::Malformed(format!("relocation symbol index {}", index)));
+        apply_relocations(core, elf.section_data(rela)?, lookup, bias)?;
+    }
+    if !sections.is_empty() {
+        return Ok(());
+    }
+
+    let dynamic = elf.dynamic()?;
+    let tag = |t: u32| dynamic.iter().find(|(d, _)| *d == t).map(|(_, v)| *v);
+    let strings = match (tag(DT_STRTAB), tag(DT_STRSZ)) {
+        (Some(strtab), Some(size)) => elf.vaddr_data(strtab, size)?,
+        _ => &[]
+    };
+    // the dynamic symbol table has no size of its own, read entries as needed
+    let lookup = |index: usize| -> Result<Symbol, ElfError> {
+        let symtab = tag(DT_SYMTAB).ok_or_else(|| ElfError::Malformed("relocation without DT_SYMTAB".to_string()))?;
+        let addr = (index as u32).checked_mul(SYM_SIZE as u32).and_then(|off| symtab.checked_add(off))
+            .ok_or_else(|| ElfError::Malformed(format!("relocation symbol index {}", index)))?;
+        return symbol(elf.vaddr_data(addr, SYM_SIZE as u32)?, strings);
+    };
+    if let Some(rela) = tag(DT_RELA) {
+        apply_relocations(core, elf.vaddr_data(rela, tag(DT_RELASZ).unwrap_or(0))?, lookup, bias)?;
+    }
+    if let Some(jmprel) = tag(DT_JMPREL) {
+        if tag(DT_PLTREL).unwrap_or(DT_RELA) != DT_RELA {
+            return Err(ElfError::Malformed("DT_JMPREL doesn't hold RELA entries".to_string()));
+        }
+        apply_relocations(core, elf.vaddr_data(jmprel, tag(DT_PLTRELSZ).unwrap_or(0))?, lookup, bias)?;
+    }
+    return Ok(());
+}
+
+// one table of Elf32_Rela entries, lookup gives the symbol at an index
+fn apply_relocations<F>(core: &mut Core, entries: &[u8], lookup: F, bias: u32) -> Result<(), ElfError>
+    where F: Fn(usize) -> Result<Symbol, ElfError> {
+    for entry in entries.chunks_exact(RELA_SIZE) {
+        let offset = u32_at(entry, 0);
+        let info = u32_at(entry, 4);
+        let addend = u32_at(entry, 8);
+        let (kind, index) = (info & 0xff, (info >> 8) as usize);
+        // S, the address of the symbol the entry refers to
+        let symbol = || -> Result<u32, ElfError> {
+            if index == 0 {
+                return Ok(0);
+            }
+            let sym = lookup(index)?;
+            return match sym.shndx {
+                SHN_UNDEF if sym.bind() == STB_WEAK => Ok(0),
+                SHN_UNDEF => Err(ElfError::Undefined(sym.name)),
+                SHN_ABS => Ok(sym.value),
+                _ => Ok(sym.value.wrapping_add(bias))
+            };
+        };
+        let value = match kind {
+            R_RISCV_NONE => continue,
+            R_RISCV_RELATIVE => bias.wrapping_add(addend),
+            R_RISCV_32 => symbol()?.wrapping_add(addend),
+            R_RISCV_JUMP_SLOT => symbol()?,
+            _ => return Err(ElfError::Relocation(kind, offset))
+        };
+        let addr = offset.wrapping_add(bias);
+        if !core.bus.memory.contains(addr, 4) {
+            return Err(ElfError::Malformed(format!("relocation at {:#010x} outside the program", offset)));
+        }
+        core.bus.memory.load(addr, &value.to_le_bytes());
+    }
+    return Ok(());
+}
+
+/*
+ * Value of the first symbol called name, None if there isn't one or the
+ * symbol tables can't be read.
+ */
+pub fn get_symbol(elf: &ElfFile, name: &str) -> Option<u32> {
+    let symbols = elf.symbols().ok()?;
+    return symbols.lookup(name).map(|sym| sym.value);
+}
+
+fn slice<'a>(data: &'a [u8], offset: usize, len: usize, what: &'static str) -> Result<&'a [u8], ElfError> {
+    let end = offset.checked_add(len).ok_or(ElfError::Truncated(what))?;
+    return data.get(offset..end).ok_or(ElfError::Truncated(what));
+}
+
+// callers make sure the bytes are there
+fn u32_at(data: &[u8], i: usize) -> u32 {
+    return u32::from_le_bytes([data[i], data[i+1], data[i+2], data[i+3]]);
+}
+
+fn u16_at(data: &[u8], i: usize) -> u16 {
+    return u16::from_le_bytes([data[i], data[i+1]]);
+}
+
+// unsigned LEB128 at pos, pos is moved past it
+fn uleb(data: &[u8], pos: &mut usize) -> Result<u64, ElfError> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = *data.get(*pos).ok_or(ElfError::Truncated("attribute"))?;
+        *pos += 1;
+        if shift < 64 {
+            value |= ((byte & 0x7f) as u64) << shift;
+        }
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+// NUL terminated string at offset in a string table
+fn cstr(table: &[u8], offset: usize, what: &'static str) -> Result<String, ElfError> {
+    let bytes = table.get(offset..).ok_or(ElfError::Truncated(what))?;
+    let len = bytes.iter().position(|b| *b == 0).ok_or(ElfError::Truncated(what))?;
+    return Ok(String::from_utf8_lossy(&bytes[..len]).into_owned());
 }
@@ -0,0 +1,213 @@
+use std::fs;
+use std::io;
+
+use crate::elf::*;
+use crate::memory::PAGE_SIZE;
+use crate::symbols::*;
+
+/*
+ * ELF32 writer
+ *
+ * Turns a program assembled with the ins.rs encoders into an executable
+ * other simulators and tools can load: a .text segment, an optional .data
+ * segment, the entry point and a symbol table.
+ *
+ *   let mut elf = ElfWriter::new(START_ADDR);
+ *   elf.text(&[addi(1, 0, 10), ebreak()])
+ *       .symbol("_start", START_ADDR, 8, STT_FUNC);
+ *   elf.write("prog.elf")?;
+ */
+
+struct SymbolEntry {
+    name: String,
+    value: u32,
+    size: u32,
+    kind: u8
+}
+
+pub struct ElfWriter {
+    entry: u32,
+    text_addr: u32,
+    text: Vec<u8>,
+    data: Option<(u32, Vec<u8>)>,
+    symbols: Vec<SymbolEntry>
+}
+
+impl ElfWriter {
+    // .text starts at text_addr, which is also the entry point unless set
+    pub fn new(text_addr: u32) -> ElfWriter {
+        return ElfWriter {
+            entry: text_addr,
+            text_addr,
+            text: Vec::new(),
+            data: None,
+            symbols: Vec::new()
+        };
+    }
+
+    // append instructions to .text
+    pub fn text(&mut self, ins: &[u32]) -> &mut ElfWriter {
+        for i in ins.iter() {
+            self.text.extend_from_slice(&i.to_le_bytes());
+        }
+        return self;
+    }
+
+    // address the next instruction goes to
+    pub fn here(&self) -> u32 {
+        return self.text_addr + self.text.len() as u32;
+    }
+
+    pub fn data(&mut self, addr: u32, bytes: &[u8]) -> &mut ElfWriter {
+        self.data = Some((addr, bytes.to_vec()));
+        return self;
+    }
+
+    pub fn entry(&mut self, addr: u32) -> &mut ElfWriter {
+        self.entry = addr;
+        return self;
+    }
+
+    // a global symbol, STT_FUNC, STT_OBJECT or STT_NOTYPE
+    pub fn symbol(&mut self, name: &str, value: u32, size: u32, kind: u8) -> &mut ElfWriter {
+        self.symbols.push(SymbolEntry { name: name.to_string(), value, size, kind });
+        return self;
+    }
+
+    // section index of the section value falls in
+    fn shndx(&self, value: u32) -> u16 {
+        if value.wrapping_sub(self.text_addr) < self.text.len().max(1) as u32 {
+            return 1;
+        }
+        if let Some((addr, data)) = &self.data {
+            if value.wrapping_sub(*addr) < data.len().max(1) as u32 {
+                return 2;
+            }
+        }
+        return SHN_ABS;
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let phnum = if self.data.is_some() { 2 } else { 1 };
+        // offsets match the addresses within a page, as loaders expect
+        let text_offset = PAGE_SIZE + self.text_addr as usize % PAGE_SIZE;
+        let (data_addr, data) = match &self.data {
+            Some((addr, data)) => (*addr, data.as_slice()),
+            None => (0, &[][..])
+        };
+        let data_offset = align(text_offset + self.text.len(), PAGE_SIZE) + data_addr as usize % PAGE_SIZE;
+
+        let mut symtab = vec![0; SYM_SIZE];
+        let mut strtab = vec![0];
+        for sym in self.symbols.iter() {
+            push_u32(&mut symtab, strtab.len() as u32);
+            push_u32(&mut symtab, sym.value);
+            push_u32(&mut symtab, sym.size);
+            symtab.push(STB_GLOBAL << 4 | sym.kind);
+            symtab.push(0);
+            push_u16(&mut symtab, self.shndx(sym.value));
+            strtab.extend_from_slice(sym.name.as_bytes());
+            strtab.push(0);
+        }
+        // .data only when there is some, the rest follows it
+        let mut section_names = vec![".text"];
+        if self.data.is_some() {
+            section_names.push(".data");
+        }
+        section_names.extend_from_slice(&[".symtab", ".strtab", ".shstrtab"]);
+        let mut shstrtab = vec![0];
+        let mut names = Vec::new();
+        for name in section_names.iter() {
+            names.push(shstrtab.len() as u32);
+            shstrtab.extend_from_slice(name.as_bytes());
+            shstrtab.push(0);
+        }
+        let symtab_offset = align(data_offset + data.len(), 4);
+        let strtab_offset = symtab_offset + symtab.len();
+        let shstrtab_offset = strtab_offset + strtab.len();
+        let shoff = align(shstrtab_offset + shstrtab.len(), 4);
+
+        // type, flags, addr, offset, size, link, info, addralign, entsize
+        let mut sections = vec![
+            [SHT_PROGBITS, SHF_ALLOC | SHF_EXECINSTR, self.text_addr, text_offset as u32, self.text.len() as u32, 0, 0, 4, 0]
+        ];
+        if self.data.is_some() {
+            sections.push([SHT_PROGBITS, SHF_ALLOC | SHF_WRITE, data_addr, data_offset as u32, data.len() as u32, 0, 0, 4, 0]);
+        }
+        let strtab_index = sections.len() as u32 + 2;
+        sections.push([SHT_SYMTAB, 0, 0, symtab_offset as u32, symtab.len() as u32, strtab_index, 1, 4, SYM_SIZE as u32]);
+        sections.push([SHT_STRTAB, 0, 0, strtab_offset as u32, strtab.len() as u32, 0, 0, 1, 0]);
+        sections.push([SHT_STRTAB, 0, 0, shstrtab_offset as u32, shstrtab.len() as u32, 0, 0, 1, 0]);
+
+        let mut out = header(ET_EXEC, self.entry, phnum, shoff as u32, sections.len() + 1, sections.len());
+        out.extend(phdr(PT_LOAD, text_offset as u32, self.text_addr, self.text.len() as u32, PF_R | PF_X));
+        if self.data.is_some() {
+            out.extend(phdr(PT_LOAD, data_offset as u32, data_addr, data.len() as u32, PF_R | PF_W));
+        }
+        out.resize(text_offset, 0);
+        out.extend_from_slice(&self.text);
+        out.resize(data_offset, 0);
+        out.extend_from_slice(data);
+        out.resize(symtab_offset, 0);
+        out.extend(symtab.iter());
+        out.extend(strtab.iter());
+        out.extend(shstrtab.iter());
+        out.resize(shoff, 0);
+
+        out.extend_from_slice(&[0; SHDR_SIZE]);
+        for (name, sh) in names.iter().zip(sections.iter()) {
+            push_u32(&mut out, *name);
+            for v in sh.iter() {
+                push_u32(&mut out, *v);
+            }
+        }
+        return out;
+    }
+
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        return fs::write(path, self.to_bytes());
+    }
+}
+
+/*
+ * ELF header for a little-endian RISC-V file with program headers right
+ * after it.
+ */
+pub fn header(e_type: u16, entry: u32, phnum: usize, shoff: u32, shnum: usize, shstrndx: usize) -> Vec<u8> {
+    let mut out = vec![0x7f, b'E', b'L', b'F', ELFCLASS32, ELFDATA2LSB, EV_CURRENT as u8];
+    out.resize(16, 0);
+    push_u16(&mut out, e_type);
+    push_u16(&mut out, EM_RISCV);
+    push_u32(&mut out, EV_CURRENT);
+    push_u32(&mut out, entry);
+    push_u32(&mut out, EHDR_SIZE as u32);
+    push_u32(&mut out, shoff);
+    push_u32(&mut out, 0); // flags
+    let shentsize = if shnum > 0 { SHDR_SIZE } else { 0 };
+    for v in [EHDR_SIZE, PHDR_SIZE, phnum, shentsize, shnum, shstrndx].iter() {
+        push_u16(&mut out, *v as u16);
+    }
+    return out;
+}
+
+// program header for a segment the same size in the file and in memory
+pub fn phdr(p_type: u32, offset: u32, addr: u32, size: u32, flags: u32) -> Vec<u8> {
+    let align = if p_type == PT_LOAD { PAGE_SIZE as u32 } else { 4 };
+    let mut out = Vec::new();
+    for v in [p_type, offset, addr, addr, size, size, flags, align].iter() {
+        push_u32(&mut out, *v);
+    }
+    return out;
+}
+
+fn align(n: usize, to: usize) -> usize {
+    return (n + to - 1) & !(to - 1);
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
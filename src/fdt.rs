@@ -0,0 +1,303 @@
+use crate::Core;
+use crate::constants::*;
+use crate::constants::csrs;
+
+/*
+ * Flattened device tree
+ *
+ * The tree is built from Nodes and serialized either as a DTB (version 17)
+ * for the guest or as DTS source for people. The machine tree describes the
+ * hart, RAM and whatever devices are on the bus, device nodes come from
+ * Device::device_tree.
+ */
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_END: u32 = 9;
+
+const HEADER_SIZE: usize = 40;
+
+pub const CPU_INTC_PHANDLE: u32 = 1;
+pub const PLIC_PHANDLE: u32 = 2;
+pub const SYSCON_PHANDLE: u32 = 3;
+
+pub const TIMEBASE_FREQUENCY: u32 = 10_000_000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Empty,
+    Cells(Vec<u32>),
+    Strings(Vec<String>),
+    Bytes(Vec<u8>)
+}
+
+impl Value {
+    fn bytes(&self) -> Vec<u8> {
+        return match self {
+            Value::Empty => Vec::new(),
+            Value::Cells(cells) => cells.iter().flat_map(|c| c.to_be_bytes().to_vec()).collect(),
+            Value::Strings(strs) => strs.iter().flat_map(|s| {
+                let mut b = s.as_bytes().to_vec();
+                b.push(0);
+                b
+            }).collect(),
+            Value::Bytes(bytes) => bytes.clone()
+        };
+    }
+
+    fn dts(&self) -> String {
+        return match self {
+            Value::Empty => String::new(),
+            Value::Cells(cells) => {
+                let cells: Vec<String> = cells.iter().map(|c| format!("{:#04x}", c)).collect();
+                format!(" = <{}>", cells.join(" "))
+            },
+            Value::Strings(strs) => {
+                let strs: Vec<String> = strs.iter().map(|s| format!("\"{}\"", s)).collect();
+                format!(" = {}", strs.join(", "))
+            },
+            Value::Bytes(bytes) => {
+                let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+                format!(" = [{}]", bytes.join(" "))
+            }
+        };
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Node {
+    pub name: String,
+    pub props: Vec<(String, Value)>,
+    pub children: Vec<Node>
+}
+
+impl Node {
+    pub fn new(name: &str) -> Node {
+        return Node { name: name.to_string(), props: Vec::new(), children: Vec::new() };
+    }
+
+    // name@unit-address
+    pub fn at(name: &str, addr: u32) -> Node {
+        return Node::new(&format!("{}@{:x}", name, addr));
+    }
+
+    pub fn prop(&mut self, name: &str, value: Value) -> &mut Node {
+        self.props.push((name.to_string(), value));
+        return self;
+    }
+
+    pub fn empty(&mut self, name: &str) -> &mut Node {
+        return self.prop(name, Value::Empty);
+    }
+
+    pub fn u32(&mut self, name: &str, value: u32) -> &mut Node {
+        return self.prop(name, Value::Cells(vec![value]));
+    }
+
+    pub fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Node {
+        return self.prop(name, Value::Cells(cells.to_vec()));
+    }
+
+    pub fn string(&mut self, name: &str, value: &str) -> &mut Node {
+        return self.strings(name, &[value]);
+    }
+
+    pub fn strings(&mut self, name: &str, values: &[&str]) -> &mut Node {
+        return self.prop(name, Value::Strings(values.iter().map(|s| s.to_string()).collect()));
+    }
+
+    // reg with two address and two size cells, as used throughout the tree
+    pub fn reg(&mut self, base: u32, size: u32) -> &mut Node {
+        return self.cells("reg", &[0, base, 0, size]);
+    }
+
+    pub fn child(&mut self, node: Node) -> &mut Node {
+        self.children.push(node);
+        return self;
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        return self.props.iter().find(|(n, _)| n == name).map(|(_, v)| v);
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Node> {
+        return self.children.iter().find(|c| c.name == name);
+    }
+
+    pub fn find_mut(&mut self, name: &str) -> Option<&mut Node> {
+        return self.children.iter_mut().find(|c| c.name == name);
+    }
+
+    /*
+     * Serialize as a DTB with an empty memory reservation map.
+     */
+    pub fn to_dtb(&self) -> Vec<u8> {
+        let mut structure = Vec::new();
+        let mut strings = Vec::new();
+        self.flatten(&mut structure, &mut strings);
+        push_u32(&mut structure, FDT_END);
+
+        let rsvmap = HEADER_SIZE;
+        let off_struct = rsvmap + 16;
+        let off_strings = off_struct + structure.len();
+        let total = off_strings + strings.len();
+
+        let mut dtb = Vec::with_capacity(total);
+        for word in [
+            FDT_MAGIC,
+            total as u32,
+            off_struct as u32,
+            off_strings as u32,
+            rsvmap as u32,
+            17, // version
+            16, // last compatible version
+            0,  // boot cpu
+            strings.len() as u32,
+            structure.len() as u32
+        ].iter() {
+            push_u32(&mut dtb, *word);
+        }
+        dtb.extend_from_slice(&[0; 16]);
+        dtb.extend(structure);
+        dtb.extend(strings);
+        return dtb;
+    }
+
+    fn flatten(&self, structure: &mut Vec<u8>, strings: &mut Vec<u8>) {
+        push_u32(structure, FDT_BEGIN_NODE);
+        structure.extend_from_slice(self.name.as_bytes());
+        structure.push(0);
+        align(structure);
+        for (name, value) in self.props.iter() {
+            let value = value.bytes();
+            push_u32(structure, FDT_PROP);
+            push_u32(structure, value.len() as u32);
+            push_u32(structure, string_offset(strings, name));
+            structure.extend(value);
+            align(structure);
+        }
+        for child in self.children.iter() {
+            child.flatten(structure, strings);
+        }
+        push_u32(structure, FDT_END_NODE);
+    }
+
+    pub fn to_dts(&self) -> String {
+        let mut dts = String::from("/dts-v1/;\n\n");
+        self.write_dts(&mut dts, 0);
+        return dts;
+    }
+
+    fn write_dts(&self, dts: &mut String, depth: usize) {
+        let indent = "\t".repeat(depth);
+        let name = if self.name.is_empty() { "/" } else { &self.name };
+        dts.push_str(&format!("{}{} {{\n", indent, name));
+        for (name, value) in self.props.iter() {
+            dts.push_str(&format!("{}\t{}{};\n", indent, name, value.dts()));
+        }
+        for child in self.children.iter() {
+            dts.push('\n');
+            child.write_dts(dts, depth+1);
+        }
+        dts.push_str(&format!("{}}};\n", indent));
+    }
+}
+
+fn push_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn align(buf: &mut Vec<u8>) {
+    let padded = (buf.len() + 3) & !3;
+    buf.resize(padded, 0);
+}
+
+// offset of name in the strings block, added if it isn't there yet
+fn string_offset(strings: &mut Vec<u8>, name: &str) -> u32 {
+    let mut offset = 0;
+    for s in strings.split(|b| *b == 0) {
+        if s == name.as_bytes() && offset < strings.len() {
+            return offset as u32;
+        }
+        offset += s.len() + 1;
+    }
+    let offset = strings.len();
+    strings.extend_from_slice(name.as_bytes());
+    strings.push(0);
+    return offset as u32;
+}
+
+/*
+ * ISA string for riscv,isa from the extensions enabled in misa, followed by
+ * the Z extensions eval always implements.
+ */
+pub fn isa_string(core: &Core) -> String {
+    let misa = core.csrs[csrs::MISA] as u32;
+    let mut isa = String::from("rv32");
+    for (i, ext) in "abcdefghijklmnopqrstuvwxyz".chars().enumerate() {
+        if misa & (1 << i) != 0 {
+            isa.push(ext);
+        }
+    }
+    isa.push_str("_zicntr_zicsr_zifencei_zihintpause");
+    return isa;
+}
+
+/*
+ * Describe the machine as it is currently set up.
+ */
+pub fn device_tree(core: &Core) -> Node {
+    let mut root = Node::new("");
+    root.u32("#address-cells", 2)
+        .u32("#size-cells", 2)
+        .string("compatible", "riscv-virtio")
+        .string("model", "rustv");
+
+    let mut chosen = Node::new("chosen");
+    if core.bus.has_device(UART_BASE) {
+        chosen.string("stdout-path", &format!("/soc/serial@{:x}", UART_BASE));
+    }
+    if let Some(bootargs) = &core.bootargs {
+        chosen.string("bootargs", bootargs);
+    }
+    if let Some((start, end)) = core.initrd {
+        chosen.cells("linux,initrd-start", &[0, start])
+            .cells("linux,initrd-end", &[0, end]);
+    }
+    root.child(chosen);
+
+    let mut memory = Node::at("memory", core.bus.ram_base());
+    memory.string("device_type", "memory")
+        .reg(core.bus.ram_base(), core.bus.ram_size() as u32);
+    root.child(memory);
+
+    let mut intc = Node::new("interrupt-controller");
+    intc.u32("#interrupt-cells", 1)
+        .empty("interrupt-controller")
+        .string("compatible", "riscv,cpu-intc")
+        .u32("phandle", CPU_INTC_PHANDLE);
+    let mut cpu = Node::at("cpu", 0);
+    cpu.string("device_type", "cpu")
+        .u32("reg", 0)
+        .string("status", "okay")
+        .string("compatible", "riscv")
+        .string("riscv,isa", &isa_string(core))
+        .child(intc);
+    let mut cpus = Node::new("cpus");
+    cpus.u32("#address-cells", 1)
+        .u32("#size-cells", 0)
+        .u32("timebase-frequency", TIMEBASE_FREQUENCY)
+        .child(cpu);
+    root.child(cpus);
+
+    let mut soc = Node::new("soc");
+    soc.u32("#address-cells", 2)
+        .u32("#size-cells", 2)
+        .string("compatible", "simple-bus")
+        .empty("ranges");
+    core.bus.device_tree(&mut soc);
+    root.child(soc);
+    return root;
+}
@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+use std::io;
+use std::io::Write;
+
+use crate::bus::Bus;
+
+/*
+ * Host-Target Interface
+ *
+ * The guest talks to the host through the 64-bit tohost / fromhost words
+ * found in its symbol table. A tohost write is laid out as
+ *
+ *   63     56 55     48 47                 0
+ *   | device | command |      payload      |
+ *
+ * Device 0 is the syscall proxy: an odd payload means exit with status
+ * payload >> 1, otherwise the payload points to a magic_mem block of eight
+ * dwords holding the syscall number and its arguments. Device 1 is the
+ * console: command 1 writes a character, command 0 requests one.
+ *
+ * The host polls tohost after every instruction, acknowledges a command by
+ * clearing it and answers through fromhost. RV32 guests store the two words
+ * one at a time, usually the lower one first, so a command is only taken
+ * once the guest has stored to both halves since the last one.
+ */
+
+const DEV_SYSCALL: u32 = 0;
+const DEV_CONSOLE: u32 = 1;
+
+const CONSOLE_GETCHAR: u32 = 0;
+const CONSOLE_PUTCHAR: u32 = 1;
+
+const SYS_WRITE: u32 = 64;
+const SYS_EXIT: u32 = 93;
+const ENOSYS: i32 = 38;
+
+// halves of tohost stored to
+const LOW_WORD: u8 = 1;
+const HIGH_WORD: u8 = 2;
+
+pub struct Htif {
+    tohost: u32,
+    written: u8,
+    fromhost: Option<u32>,
+    getchar_pending: bool,
+    input: VecDeque<u8>,
+    output: Box<dyn Write>
+}
+
+impl Htif {
+    pub fn new(tohost: u32, fromhost: Option<u32>) -> Htif {
+        return Htif::with_output(tohost, fromhost, Box::new(io::stdout()));
+    }
+
+    pub fn with_output(tohost: u32, fromhost: Option<u32>, output: Box<dyn Write>) -> Htif {
+        return Htif {
+            tohost,
+            written: 0,
+            fromhost,
+            getchar_pending: false,
+            input: VecDeque::new(),
+            output
+        };
+    }
+
+    // queue console input for the guest
+    pub fn receive(&mut self, bytes: &[u8]) {
+        self.input.extend(bytes);
+    }
+
+    pub fn reset(&mut self) {
+        self.written = 0;
+        self.getchar_pending = false;
+    }
+
+    // the guest stored size bytes at addr
+    pub fn store(&mut self, addr: u32, size: u32) {
+        let (start, end) = (addr as u64, addr as u64 + size as u64);
+        let tohost = self.tohost as u64;
+        if start < tohost + 4 && end > tohost {
+            self.written |= LOW_WORD;
+        }
+        if start < tohost + 8 && end > tohost + 4 {
+            self.written |= HIGH_WORD;
+        }
+    }
+
+    /*
+     * Handle a pending tohost command. Returns the exit status once the
+     * guest has asked to stop.
+     */
+    pub fn poll(&mut self, bus: &mut Bus) -> Option<i32> {
+        self.answer_getchar(bus);
+
+        if self.written != LOW_WORD | HIGH_WORD {
+            return None;
+        }
+        self.written = 0;
+        let lo = bus.read(self.tohost, 4).ok()?;
+        let hi = bus.read(self.tohost+4, 4).ok()?;
+        if lo == 0 && hi == 0 {
+            return None;
+        }
+        let _ = bus.write(self.tohost, 4, 0);
+        let _ = bus.write(self.tohost+4, 4, 0);
+
+        let device = hi >> 24;
+        let command = (hi >> 16) & 0xff;
+        match (device, command) {
+            (DEV_SYSCALL, _) => {
+                if lo & 1 == 1 {
+                    let code = (lo >> 1) as i32;
+                    if code != 0 {
+                        println!("*** FAILED *** (tohost = {})", code);
+                    }
+                    return Some(code);
+                }
+                return self.syscall(bus, lo);
+            },
+            (DEV_CONSOLE, CONSOLE_PUTCHAR) => {
+                let _ = self.output.write_all(&[lo as u8]);
+                let _ = self.output.flush();
+                self.respond(bus, device, command, 0);
+            },
+            (DEV_CONSOLE, CONSOLE_GETCHAR) => {
+                self.getchar_pending = true;
+                self.answer_getchar(bus);
+            },
+            _ => {
+                println!("HTIF: unknown device {} command {}", device, command);
+            }
+        }
+        return None;
+    }
+
+    fn syscall(&mut self, bus: &mut Bus, magic_mem: u32) -> Option<i32> {
+        let arg = |bus: &mut Bus, i: u32| bus.read(magic_mem + 8*i, 4).unwrap_or(0);
+        let which = arg(bus, 0);
+        let ret = match which {
+            SYS_WRITE => {
+                let (buf, len) = (arg(bus, 2), arg(bus, 3));
+                let mut bytes = Vec::new();
+                for i in 0..len {
+                    bytes.push(bus.read(buf+i, 1).unwrap_or(0) as u8);
+                }
+                let _ = self.output.write_all(&bytes);
+                let _ = self.output.flush();
+                len as i32
+            },
+            SYS_EXIT => {
+                return Some(arg(bus, 1) as i32);
+            },
+            _ => {
+                println!("HTIF: unsupported syscall {}", which);
+                -ENOSYS
+            }
+        };
+        let _ = bus.write(magic_mem, 4, ret as u32);
+        let _ = bus.write(magic_mem+4, 4, if ret < 0 { 0xffff_ffff } else { 0 });
+        self.respond(bus, DEV_SYSCALL, 0, 1);
+        return None;
+    }
+
+    fn answer_getchar(&mut self, bus: &mut Bus) {
+        if !self.getchar_pending || self.input.is_empty() {
+            return;
+        }
+        if let Some(ch) = self.input.pop_front() {
+            self.getchar_pending = false;
+            self.respond(bus, DEV_CONSOLE, CONSOLE_GETCHAR, ch as u32);
+        }
+    }
+
+    fn respond(&mut self, bus: &mut Bus, device: u32, command: u32, payload: u32) {
+        if let Some(fromhost) = self.fromhost {
+            let _ = bus.write(fromhost, 4, payload);
+            let _ = bus.write(fromhost+4, 4, (device << 24) | (command << 16));
+        }
+    }
+}
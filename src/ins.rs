@@ -1,5 +1,6 @@
 use crate::constants::opcodes;
 use crate::constants::funct3;
+use crate::constants::funct12;
 
 /*
  * Instruction encoding for creating basic test programs.
@@ -178,6 +179,38 @@ pub fn sw(rs2: u32, imm: i32, rs1: u32) -> u32 {
     return s_type(imm as u32, rs2, rs1, funct3::SW);
 }
 
+/*
+ * Control and Status Register Instructions
+ */
+
+pub fn csrrw(rd: u32, csr: u32, rs1: u32) -> u32 {
+    return i_type(csr, rs1, funct3::CSRRW, rd, opcodes::SYSTEM);
+}
+
+pub fn csrrs(rd: u32, csr: u32, rs1: u32) -> u32 {
+    return i_type(csr, rs1, funct3::CSRRS, rd, opcodes::SYSTEM);
+}
+
+pub fn csrrc(rd: u32, csr: u32, rs1: u32) -> u32 {
+    return i_type(csr, rs1, funct3::CSRRC, rd, opcodes::SYSTEM);
+}
+
+pub fn csrr(rd: u32, csr: u32) -> u32 {
+    return csrrs(rd, csr, 0);
+}
+
+/*
+ * Environment Call and Breakpoint
+ */
+
+pub fn ecall() -> u32 {
+    return i_type(funct12::ECALL, 0, funct3::PRIV, 0, opcodes::SYSTEM);
+}
+
+pub fn ebreak() -> u32 {
+    return i_type(funct12::EBREAK, 0, funct3::PRIV, 0, opcodes::SYSTEM);
+}
+
 /*
  * Instruction types
  */
@@ -205,7 +238,7 @@ fn j_type(imm: u32, rd: u32) -> u32 {
 }
 
 fn u_type(imm: u32, rd: u32, opcode: u32) -> u32 {
-    return (((imm as u32 & 0xfffff) << 12))
+    return ((imm & 0xfffff) << 12)
         | ((rd & 0x1f) << 7)
         | (opcode & 0x7f);
 }
@@ -0,0 +1,316 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+use crate::Core;
+use crate::constants::csrs;
+use crate::dwarf::LineTable;
+use crate::elf::*;
+use crate::htif::Htif;
+
+/*
+ * Program images
+ *
+ * Besides ELF, flash tooling hands out raw binaries, Intel HEX and Motorola
+ * S-records. The text formats are parsed into chunks of bytes at addresses,
+ * with every record's checksum checked, and raw binaries go wherever the
+ * user says. The format is told from the contents unless given.
+ */
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Elf,
+    Binary,
+    IntelHex,
+    Srec
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        return match name {
+            "elf" => Some(Format::Elf),
+            "bin" => Some(Format::Binary),
+            "ihex" => Some(Format::IntelHex),
+            "srec" => Some(Format::Srec),
+            _ => None
+        };
+    }
+
+    /*
+     * ELF by its magic, the text formats by every line being a record.
+     * Anything else is a raw binary.
+     */
+    pub fn detect(data: &[u8]) -> Format {
+        if data.starts_with(b"\x7fELF") {
+            return Format::Elf;
+        }
+        let text = match std::str::from_utf8(data) {
+            Ok(text) => text,
+            Err(_) => return Format::Binary
+        };
+        let mut lines = text.lines().map(|l| l.trim()).filter(|l| !l.is_empty()).peekable();
+        if lines.peek().is_none() {
+            return Format::Binary;
+        }
+        let hex = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit());
+        let records: Vec<&str> = lines.collect();
+        if records.iter().all(|l| l.starts_with(':') && hex(&l[1..])) {
+            return Format::IntelHex;
+        }
+        if records.iter().all(|l| l.len() > 2 && l.starts_with('S') && hex(&l[1..])) {
+            return Format::Srec;
+        }
+        return Format::Binary;
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    Elf(ElfError),
+    Record(usize, String),
+    DoesNotFit(u32),
+    Unimplemented(Vec<String>)
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::Elf(e) => write!(f, "{}", e),
+            LoadError::Record(line, msg) => write!(f, "line {}: {}", line, msg),
+            LoadError::DoesNotFit(addr) => write!(f, "Data at {:#010x} doesn't fit in memory", addr),
+            LoadError::Unimplemented(extensions) => {
+                write!(f, "Program needs extensions that aren't implemented: {}", extensions.join(", "))
+            }
+        }
+    }
+}
+
+impl From<ElfError> for LoadError {
+    fn from(e: ElfError) -> LoadError {
+        return LoadError::Elf(e);
+    }
+}
+
+// extensions eval implements
+pub const IMPLEMENTED: [&str; 6] = ["i", "e", "zicsr", "zifencei", "zihintpause", "zicntr"];
+
+// what a HEX or SREC file holds
+#[derive(Debug, Default, PartialEq)]
+pub struct Image {
+    pub chunks: Vec<(u32, Vec<u8>)>,
+    pub entry: Option<u32>
+}
+
+impl Image {
+    // add data, merging it into the previous chunk when it follows on
+    fn push(&mut self, addr: u32, data: &[u8]) {
+        if let Some((base, chunk)) = self.chunks.last_mut() {
+            if base.wrapping_add(chunk.len() as u32) == addr {
+                chunk.extend_from_slice(data);
+                return;
+            }
+        }
+        self.chunks.push((addr, data.to_vec()));
+    }
+}
+
+/*
+ * Load the program in data, returns its entry point. ELF programs also
+ * bring their symbols, line table and HTIF mailbox, unless an earlier
+ * image already did. Raw binaries and position-independent ELF
+ * programs are put at load_addr, which is also where text formats without a
+ * start address are entered.
+ */
+pub fn load_program(core: &mut Core, data: &[u8], format: Format, load_addr: u32) -> Result<u32, LoadError> {
+    let image = match format {
+        Format::Elf => {
+            let elf = ElfFile::parse(data)?;
+            configure_isa(core, &elf)?;
+            let bias = load_elf(core, &elf, load_addr)?;
+            // with several images the first one's debug info is kept
+            if core.symbols.is_empty() {
+                core.symbols = elf.symbols().unwrap_or_default().relocate(bias);
+            }
+            if core.lines.is_empty() {
+                core.lines = LineTable::from_elf(&elf).ok().flatten().unwrap_or_default();
+                core.lines.relocate(bias);
+            }
+            if core.htif.is_none() {
+                if let Some(tohost) = get_symbol(&elf, "tohost") {
+                    let fromhost = get_symbol(&elf, "fromhost").map(|addr| addr.wrapping_add(bias));
+                    core.htif = Some(Htif::new(tohost.wrapping_add(bias), fromhost));
+                }
+            }
+            return Ok(elf.header.e_entry.wrapping_add(bias));
+        },
+        Format::Binary => Image { chunks: vec![(load_addr, data.to_vec())], entry: Some(load_addr) },
+        Format::IntelHex => parse_ihex(&String::from_utf8_lossy(data))?,
+        Format::Srec => parse_srec(&String::from_utf8_lossy(data))?
+    };
+    for (addr, chunk) in image.chunks.iter() {
+        if !core.bus.memory.contains(*addr, chunk.len()) {
+            return Err(LoadError::DoesNotFit(*addr));
+        }
+        core.bus.memory.load(*addr, chunk);
+    }
+    return Ok(image.entry.unwrap_or(load_addr));
+}
+
+/*
+ * Set the hart up for an ELF program. What its e_flags ABI relies on has to
+ * be implemented. Extensions only named in its arch string get a warning,
+ * compilers are often told about more than the code ends up using, and
+ * their instructions raise illegal instruction traps if it does use them.
+ * misa gets the base ISA and the implemented single-letter extensions.
+ */
+pub fn configure_isa(core: &mut Core, elf: &ElfFile) -> Result<(), LoadError> {
+    let missing: Vec<String> = abi_extensions(elf.header.e_flags).into_iter()
+        .filter(|ext| !IMPLEMENTED.contains(ext))
+        .map(|ext| ext.to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(LoadError::Unimplemented(missing));
+    }
+    let mut extensions = Vec::new();
+    if let Some(arch) = elf.arch()? {
+        extensions = arch_extensions(&arch)?;
+        let missing: Vec<&str> = extensions.iter()
+            .map(|ext| ext.as_str())
+            .filter(|ext| !IMPLEMENTED.contains(ext))
+            .collect();
+        if !missing.is_empty() {
+            eprintln!("Warning: program built for {}, {} not implemented", arch, missing.join(", "));
+        }
+    }
+    if elf.header.e_flags & EF_RISCV_RVE != 0 {
+        extensions.push("e".to_string());
+    }
+    let mut misa = csrs::MISA_MXL_32;
+    misa |= if extensions.iter().any(|ext| ext == "e") { csrs::MISA_E } else { csrs::MISA_I };
+    for ext in extensions.iter().filter(|ext| ext.len() == 1 && *ext != "i" && *ext != "e") {
+        if IMPLEMENTED.contains(&ext.as_str()) {
+            misa |= 1 << (ext.as_bytes()[0] - b'a');
+        }
+    }
+    core.csrs[csrs::MISA] = misa;
+    return Ok(());
+}
+
+/*
+ * Copy an initrd into RAM, returns the range it occupies. Without an
+ * address it goes where QEMU puts it: halfway into RAM, at most 128M in,
+ * clear of the kernel as it unpacks.
+ */
+pub fn load_initrd(core: &mut Core, data: &[u8], addr: Option<u32>) -> Result<(u32, u32), LoadError> {
+    let addr = addr.unwrap_or_else(|| {
+        let offset = (core.bus.ram_size() / 2).min(128 << 20) as u32;
+        (core.bus.ram_base() + offset) & !0xfff
+    });
+    let end = u32::try_from(data.len()).ok()
+        .and_then(|len| addr.checked_add(len))
+        .ok_or(LoadError::DoesNotFit(addr))?;
+    if !core.bus.memory.contains(addr, data.len()) {
+        return Err(LoadError::DoesNotFit(addr));
+    }
+    core.bus.memory.load(addr, data);
+    return Ok((addr, end));
+}
+
+/*
+ * Intel HEX: ":LLAAAATT<data>CC" records, where the bytes sum to zero.
+ * Extended segment and linear address records move the 16-bit addresses
+ * around, start records give the entry point.
+ */
+pub fn parse_ihex(text: &str) -> Result<Image, LoadError> {
+    let mut image = Image::default();
+    let mut base = 0u32;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| LoadError::Record(i+1, msg.to_string());
+        let body = line.strip_prefix(':').ok_or_else(|| err("record doesn't start with ':'"))?;
+        let bytes = hex_bytes(body).ok_or_else(|| err("invalid hex digits"))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(err("record length doesn't match its byte count"));
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(err("checksum mismatch"));
+        }
+        let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data = &bytes[4..bytes.len()-1];
+        match bytes[3] {
+            0x00 => image.push(base.wrapping_add(offset), data),
+            0x01 => return Ok(image),
+            0x02 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 4,
+            0x03 if data.len() == 4 => {
+                let cs = u16::from_be_bytes([data[0], data[1]]) as u32;
+                let ip = u16::from_be_bytes([data[2], data[3]]) as u32;
+                image.entry = Some((cs << 4) + ip);
+            },
+            0x04 if data.len() == 2 => base = (u16::from_be_bytes([data[0], data[1]]) as u32) << 16,
+            0x05 if data.len() == 4 => image.entry = Some(u32::from_be_bytes([data[0], data[1], data[2], data[3]])),
+            kind => return Err(err(&format!("bad record type {:02x}", kind)))
+        }
+    }
+    return Err(LoadError::Record(text.lines().count(), "missing end of file record".to_string()));
+}
+
+/*
+ * Motorola S-records: "S<type><count><address><data><checksum>", the
+ * checksum being the ones' complement of the sum of count, address and
+ * data. S1/S2/S3 carry data with 16, 24 and 32-bit addresses and S9/S8/S7
+ * the matching entry point.
+ */
+pub fn parse_srec(text: &str) -> Result<Image, LoadError> {
+    let mut image = Image::default();
+    let mut data_records = 0;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let err = |msg: &str| LoadError::Record(i+1, msg.to_string());
+        let body = line.strip_prefix('S').ok_or_else(|| err("record doesn't start with 'S'"))?;
+        let kind = body.chars().next().ok_or_else(|| err("missing record type"))?;
+        let bytes = body.get(1..).and_then(hex_bytes).ok_or_else(|| err("invalid hex digits"))?;
+        if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+            return Err(err("record length doesn't match its byte count"));
+        }
+        let sum = bytes[..bytes.len()-1].iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if !sum != bytes[bytes.len()-1] {
+            return Err(err("checksum mismatch"));
+        }
+        let addr_len = match kind {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => return Err(err(&format!("bad record type S{}", kind)))
+        };
+        if bytes.len() < addr_len + 2 {
+            return Err(err("record too short for its address"));
+        }
+        let addr = bytes[1..=addr_len].iter().fold(0u32, |addr, b| addr << 8 | *b as u32);
+        let data = &bytes[addr_len+1..bytes.len()-1];
+        match kind {
+            '1' | '2' | '3' => {
+                image.push(addr, data);
+                data_records += 1;
+            },
+            '5' | '6' if addr != data_records => {
+                return Err(err(&format!("record count {} but {} data records", addr, data_records)));
+            },
+            '7' | '8' | '9' => image.entry = Some(addr),
+            // header and matching counts
+            _ => {}
+        }
+    }
+    return Ok(image);
+}
+
+fn hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if s.len() & 1 != 0 || !s.is_ascii() {
+        return None;
+    }
+    return (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i+2], 16).ok()).collect();
+}
@@ -1,19 +1,63 @@
 #![ allow( dead_code ) ]
+#![ allow( clippy::needless_return ) ]
 
+mod bootrom;
+mod bus;
+mod config;
 mod constants;
+mod coredump;
+mod devices;
+mod dwarf;
 mod elf;
+mod elfwriter;
+mod fdt;
+mod htif;
 mod ins;
+mod loader;
+mod memory;
 mod tests;
 mod riscv_tests;
+mod rng;
+mod semihosting;
+mod symbols;
+mod syscalls;
+mod user;
 
 use std::env;
 use std::fs;
-
+use std::process;
+
+use bootrom::boot_rom;
+use bus::Bus;
+use bus::Device;
+use bus::Event;
+use config::parse_args;
+use config::Config;
+use config::USAGE;
+use constants::*;
+use constants::csrs;
 use constants::funct3;
 use constants::funct12;
-use constants::MEMSIZE;
+use devices::framebuffer::Framebuffer;
+use devices::input::{HostInput, InputSource};
+use devices::plic::Plic;
+use devices::rtc::Rtc;
+use devices::syscon::Syscon;
+use devices::uart::RawMode;
+use devices::uart::Uart;
+use devices::virtio::VirtioMmio;
+use devices::virtio_blk::VirtioBlk;
+use devices::virtio_console::VirtioConsole;
+use devices::virtio_rng::VirtioRng;
+use dwarf::LineTable;
+use htif::Htif;
+use loader::{load_initrd, load_program, Format, LoadError};
+use rng::Rng;
+use semihosting::Semihosting;
+use symbols::SymbolTable;
+use syscalls::{now_ns, Syscalls};
+use user::init_stack;
 use constants::opcodes;
-use constants::REG_NAMES;
 use elf::*;
 use ins::*;
 
@@ -21,28 +65,134 @@ use ins::*;
  * Main structure for core state
  */
 pub struct Core {
-    memory: [u8; MEMSIZE],
+    bus: Bus,
     regs: [i32;33],
-    csrs: [i32;4096]
+    csrs: [i32;4096],
+    // instructions retired, also the cycle count
+    instret: u64,
+    htif: Option<Htif>,
+    // Linux system calls on ECALL, for user programs
+    syscalls: Option<Syscalls>,
+    // host services on marked EBREAKs
+    semihosting: Option<Semihosting>,
+    exit_code: Option<i32>,
+    reset_vector: u32,
+    // where the device tree was placed in guest memory, 0 for none
+    fdt_addr: u32,
+    // published in /chosen
+    bootargs: Option<String>,
+    initrd: Option<(u32, u32)>,
+    // cause and pc of a trap there was no handler for
+    fault: Option<(u32, u32)>,
+    // segments of the loaded program and their permissions
+    segments: Vec<Segment>,
+    // symbols and source lines of the loaded program, for diagnostics
+    symbols: SymbolTable,
+    lines: LineTable
 }
 
+/*
+ * A bare core with RAM at 0, what the unit tests run on.
+ */
 pub fn init() -> Core {
-    return Core { memory: [0;MEMSIZE], regs: [0;33], csrs: [0;4096] };
+    return init_with_memory(0, MEMSIZE);
+}
+
+pub fn init_with_memory(base: u32, size: usize) -> Core {
+    let mut core = Core {
+        bus: Bus::new(base, size),
+        regs: [0;33],
+        csrs: [0;4096],
+        instret: 0,
+        htif: None,
+        syscalls: None,
+        semihosting: None,
+        exit_code: None,
+        reset_vector: 0,
+        fdt_addr: 0,
+        bootargs: None,
+        initrd: None,
+        fault: None,
+        segments: Vec::new(),
+        symbols: SymbolTable::default(),
+        lines: LineTable::default()
+    };
+    core.csrs[csrs::MISA] = csrs::MISA_MXL_32 | csrs::MISA_I;
+    return core;
+}
+
+/*
+ * Reboot: the hart starts over at the reset vector and the bus puts devices
+ * and RAM back to their power-on state.
+ */
+pub fn reset(core: &mut Core) {
+    let misa = core.csrs[csrs::MISA];
+    core.regs = [0;33];
+    core.csrs = [0;4096];
+    core.csrs[csrs::MISA] = misa;
+    core.instret = 0;
+    core.regs[32] = core.reset_vector as i32;
+    core.bus.reset();
+    if let Some(htif) = core.htif.as_mut() {
+        htif.reset();
+    }
 }
 
-fn run(core: &mut Core) {
+/*
+ * Run until the guest stops, returns its exit status: 1 after an
+ * unhandled trap.
+ */
+fn run(core: &mut Core) -> i32 {
     let mut ins_cnt = 0;
     while !step(core) {
         ins_cnt += 1;
     }
     println!("Ran {} instructions.", ins_cnt);
+    let failed = if core.fault.is_some() { 1 } else { 0 };
+    return core.exit_code.unwrap_or(failed);
 }
 
 fn step(core: &mut Core) -> bool {
-    let pc = core.regs[32] as usize;
-    let ins = read_mem_32(core, pc);
-    if ins == 0 { return true; }
+    let pending = core.csrs[csrs::MIP] & core.csrs[csrs::MIE];
+    if core.csrs[csrs::MSTATUS] & csrs::MSTATUS_MIE != 0 && pending & csrs::MEIP != 0 {
+        trap(core, causes::M_EXTERNAL, 0);
+    }
+
+    let pc = core.regs[32] as u32;
+    let ins = match core.bus.fetch(pc) {
+        Ok(ins) => ins,
+        Err(_) => {
+            trap(core, causes::INSTRUCTION_ACCESS_FAULT, pc);
+            return core.fault.is_some();
+        }
+    };
     eval(ins, core);
+    core.instret = core.instret.wrapping_add(1);
+    // the program exited through a system call or a trap had no handler
+    if core.exit_code.is_some() || core.fault.is_some() {
+        return true;
+    }
+    core.bus.tick();
+    if core.bus.external_interrupt() {
+        core.csrs[csrs::MIP] |= csrs::MEIP;
+    }
+    else {
+        core.csrs[csrs::MIP] &= !csrs::MEIP;
+    }
+    match core.bus.take_event() {
+        Some(Event::Poweroff(code)) => {
+            core.exit_code = Some(code);
+            return true;
+        },
+        Some(Event::Reset) => reset(core),
+        None => {}
+    }
+    if let Some(htif) = core.htif.as_mut() {
+        if let Some(code) = htif.poll(&mut core.bus) {
+            core.exit_code = Some(code);
+            return true;
+        }
+    }
     return false;
 }
 
@@ -50,47 +200,155 @@ fn write(core: &mut Core, rd: usize, val: i32) {
     if rd != 0 { core.regs[rd] = val };
 }
 
-fn csr_write_bits(core: &mut Core, csr: usize, mask: i32) -> i32 {
-    return core.csrs[csr] | mask;
+fn csr_write_bits(core: &mut Core, csr: usize, mask: i32) {
+    core.csrs[csr] |= mask;
 }
 
-fn csr_clear_bits(core: &mut Core, csr: usize, mask: i32) -> i32 {
-    return core.csrs[csr] & (!mask);
+fn csr_clear_bits(core: &mut Core, csr: usize, mask: i32) {
+    core.csrs[csr] &= !mask;
 }
 
-fn read_mem_32(core: &Core, addr: usize) -> u32 {
-    return ((core.memory[addr+3] as u32) << 24)
-        | ((core.memory[addr+2] as u32) << 16)
-        | ((core.memory[addr+1] as u32) << 8)
-        | core.memory[addr] as u32;
+/*
+ * The counters aren't kept in csrs: they are copied in before a CSR
+ * instruction reads them and mcycle / minstret writes are taken back out
+ * after. cycle and instret count retired instructions, time is the RTC in
+ * timebase ticks. The user counters are read-only, writes are dropped.
+ */
+fn load_counter(core: &mut Core, csr: usize) {
+    let value = match csr & !csrs::COUNTER_HIGH {
+        csrs::MCYCLE | csrs::MINSTRET | csrs::CYCLE | csrs::INSTRET => core.instret,
+        csrs::TIME => (now_ns(core) as u128 * fdt::TIMEBASE_FREQUENCY as u128 / 1_000_000_000) as u64,
+        _ => return
+    };
+    let high = csr & csrs::COUNTER_HIGH != 0;
+    core.csrs[csr] = if high { (value >> 32) as i32 } else { value as i32 };
 }
 
-fn store_mem_32(mut core: &mut Core, addr: u32, value: u32) {
-    let addr = addr as usize;
-    core.memory[addr] = (value & 0xff) as u8;
-    core.memory[addr+1] = ((value>>8) & 0xff) as u8;
-    core.memory[addr+2] = ((value>>16) & 0xff) as u8;
-    core.memory[addr+3] = ((value>>24) & 0xff) as u8;
+fn store_counter(core: &mut Core, csr: usize) {
+    match csr & !csrs::COUNTER_HIGH {
+        csrs::MCYCLE | csrs::MINSTRET => {},
+        _ => return
+    }
+    let value = core.csrs[csr] as u32 as u64;
+    core.instret = if csr & csrs::COUNTER_HIGH != 0 {
+        (core.instret & 0xffff_ffff) | value << 32
+    }
+    else {
+        (core.instret & !0xffff_ffff) | value
+    };
+}
+
+// symbol and source line an address falls in, for messages
+fn location(core: &Core, addr: u32) -> String {
+    let symbol = core.symbols.describe(addr).unwrap_or_else(|| "??".to_string());
+    return match core.lines.lookup(addr) {
+        Some(loc) => format!("{} at {}:{}", symbol, loc.file, loc.line),
+        None => symbol
+    };
+}
+
+/*
+ * Take a trap into machine mode. The pc still points at the instruction
+ * that caused it, or the next one to execute for interrupts.
+ */
+fn trap(core: &mut Core, cause: u32, tval: u32) {
+    let mtvec = core.csrs[csrs::MTVEC] as u32;
+    let base = mtvec & !0b11;
+    // faulting on the trap handler itself would loop forever, stop with the
+    // trap that led there instead
+    let pc = core.regs[32] as u32;
+    if cause & causes::INTERRUPT == 0 && pc == base {
+        println!("No trap handler at {:#010x}: cause {} ({})", pc, cause, location(core, pc));
+        let epc = core.csrs[csrs::MEPC] as u32;
+        println!("Unhandled trap, cause {} at {:#010x} ({})", core.csrs[csrs::MCAUSE] as u32, epc, location(core, epc));
+        core.fault = Some((core.csrs[csrs::MCAUSE] as u32, epc));
+        return;
+    }
+
+    let mstatus = core.csrs[csrs::MSTATUS];
+    let mpie = if mstatus & csrs::MSTATUS_MIE != 0 { csrs::MSTATUS_MPIE } else { 0 };
+    core.csrs[csrs::MSTATUS] =
+        (mstatus & !(csrs::MSTATUS_MIE | csrs::MSTATUS_MPIE)) | mpie | csrs::MSTATUS_MPP;
+    core.csrs[csrs::MEPC] = core.regs[32];
+    core.csrs[csrs::MCAUSE] = cause as i32;
+    core.csrs[csrs::MTVAL] = tval as i32;
+
+    let vectored = mtvec & 0b11 == 1 && cause & causes::INTERRUPT != 0;
+    core.regs[32] = if vectored {
+        base.wrapping_add(4*(cause & !causes::INTERRUPT)) as i32
+    }
+    else {
+        base as i32
+    };
+}
+
+/*
+ * Host side memory helpers, used for setting up programs and inspecting
+ * results. Guest accesses go through load / store in eval.
+ */
+fn read_mem_8(core: &mut Core, addr: u32) -> u8 {
+    return core.bus.read(addr, 1).expect("read_mem_8") as u8;
+}
+
+fn store_mem_8(core: &mut Core, addr: u32, value: u8) {
+    core.bus.write(addr, 1, value as u32).expect("store_mem_8");
+}
+
+fn read_mem_32(core: &mut Core, addr: u32) -> u32 {
+    return core.bus.read(addr, 4).expect("read_mem_32");
+}
+
+fn store_mem_32(core: &mut Core, addr: u32, value: u32) {
+    core.bus.write(addr, 4, value).expect("store_mem_32");
+}
+
+/*
+ * Guest loads and stores. A failed access raises an access fault with the
+ * address in mtval and leaves the pc at the faulting instruction.
+ */
+fn load(core: &mut Core, addr: u32, size: u32) -> Option<u32> {
+    match core.bus.read(addr, size) {
+        Ok(val) => Some(val),
+        Err(_) => {
+            trap(core, causes::LOAD_ACCESS_FAULT, addr);
+            None
+        }
+    }
+}
+
+fn store(core: &mut Core, addr: u32, size: u32, value: u32) -> bool {
+    if core.bus.write(addr, size, value).is_err() {
+        trap(core, causes::STORE_ACCESS_FAULT, addr);
+        return false;
+    }
+    if let Some(htif) = core.htif.as_mut() {
+        htif.store(addr, size);
+    }
+    return true;
 }
 
 /*
  * dump 10 bytes starting from index
  */
-fn dump_mem(core: &Core, addr: usize) {
+fn dump_mem(core: &mut Core, addr: u32) {
     let range = 10;
     let mut i = 0;
-    println!("{:11} {:5} {:3}", "Memory", "Dec",  "Hex");
-    println!("{:11} {:5} {:3}", "------", "---", "---");
+    println!("{:11} {:5} Hex", "Memory", "Dec");
+    println!("{:11} {:5} ---", "------", "---");
     while i < range {
-        println!("{:#010x}: {:<#5} {:<#02x}", addr+i, core.memory[addr+i], core.memory[addr+i]);
+        let addr = addr.wrapping_add(i);
+        match core.bus.read(addr, 1) {
+            Ok(byte) => println!("{:#010x}: {:<#5} {:<#02x}", addr, byte, byte),
+            Err(e) => println!("{:#010x}: {}", addr, e)
+        }
         i += 1;
     }
 }
 
 fn dump_regs(core: &Core) {
     let regs = core.regs;
-    println!("{:6} {:<12} {}", "Name", "Dec", "Hex");
-    println!("{:6} {:<12} {}", "----", "---", "---");
+    println!("{:6} {:<12} Hex", "Name", "Dec");
+    println!("{:6} {:<12} ---", "----", "---");
     for i in 0..=32 {
         println!("{:6} {:<12} {:#010x}", REG_NAMES[i], regs[i], regs[i]);
     }
@@ -204,7 +462,7 @@ fn sign_extend(ins: u32, bits: u32) -> i32 {
         ins as i32
     }
     else {
-        (u32::pow(2, bits)-ins) as i32 * -1
+        -((u32::pow(2, bits)-ins) as i32)
     };
 }
 
@@ -218,97 +476,49 @@ pub fn eval(ins: u32, core: &mut Core) {
         opcodes::OP_IMM => {
             let IType { imm, rs1, funct3, rd } = get_i_type(ins);
             let signed_imm = sign_extend(imm, 12);
-
-            if rd == 0 { core.regs[32] += 4; return; }
-
-            match funct3 {
-                funct3::ADDI => {
-                    core.regs[rd] = core.regs[rs1].wrapping_add(signed_imm);
-                },
-                funct3::SLTI => {
-                    core.regs[rd] = if core.regs[rs1] < signed_imm {1} else {0};
-                },
-                funct3::SLTIU => {
-                    core.regs[rd] =
-                        if (core.regs[rs1] as u32) < signed_imm as u32 {1} else {0};
-                },
-                funct3::ANDI => {
-                    core.regs[rd] = core.regs[rs1] & signed_imm;
-                },
-                funct3::ORI => {
-                    core.regs[rd] = core.regs[rs1] | signed_imm;
-                },
-                funct3::XORI => {
-                    core.regs[rd] = core.regs[rs1] ^ signed_imm;
-                },
-                funct3::SLLI => {
-                    let shamt = imm & 0b11111;
-                    core.regs[rd] = core.regs[rs1] << shamt;
-                },
-                funct3::SRXI => {
-                    let arithmetic = take_range(30,30, ins);
-                    let shamt = imm & 0b11111;
-                    if arithmetic == 1 { // SRAI
-                        core.regs[rd] = core.regs[rs1] >> shamt;
-                    }
-                    else { // SRLI
-                        let ans = core.regs[rs1] as u32 >> shamt;
-                        core.regs[rd] = ans as i32;
-                    }
-                },
+            // the shifts keep funct7 in the upper immediate bits
+            let (funct7, shamt) = (imm >> 5, imm & 0b11111);
+
+            let val = match (funct3, funct7) {
+                (funct3::ADDI, _) => core.regs[rs1].wrapping_add(signed_imm),
+                (funct3::SLTI, _) => if core.regs[rs1] < signed_imm {1} else {0},
+                (funct3::SLTIU, _) => if (core.regs[rs1] as u32) < signed_imm as u32 {1} else {0},
+                (funct3::ANDI, _) => core.regs[rs1] & signed_imm,
+                (funct3::ORI, _) => core.regs[rs1] | signed_imm,
+                (funct3::XORI, _) => core.regs[rs1] ^ signed_imm,
+                (funct3::SLLI, funct7::BASE) => core.regs[rs1] << shamt,
+                (funct3::SRXI, funct7::BASE) => (core.regs[rs1] as u32 >> shamt) as i32, // SRLI
+                (funct3::SRXI, funct7::SUB_SRA) => core.regs[rs1] >> shamt, // SRAI
                 _ => {
-                    println!("Unknown funct3 in op_imm: {}", funct3);
+                    trap(core, causes::ILLEGAL_INSTRUCTION, ins);
+                    return;
                 }
-            }
+            };
+            write(core, rd, val);
         },
         opcodes::OP => {
             let RType { funct7, rs2, rs1, funct3, rd } = get_r_type(ins);
-
-            if rd == 0 { core.regs[32] += 4; return; }
-
-            match funct3 {
-
-                funct3::ADD_SUB => {
-                    if funct7 == 0 { // add
-                        core.regs[rd] = core.regs[rs1].wrapping_add(core.regs[rs2]);
-                    }
-                    else { // sub
-                        core.regs[rd] = core.regs[rs1].wrapping_sub(core.regs[rs2]);
-                    }
-                },
-                funct3::SLT => {
-                    core.regs[rd] = if core.regs[rs1] < core.regs[rs2] {1} else {0};
-                },
-                funct3::SLTU => {
-                    let (lhs, rhs) = (core.regs[rs1] as u32, core.regs[rs2] as u32);
-                    core.regs[rd] = if lhs < rhs {1} else {0};
-                },
-                funct3::XOR => {
-                    core.regs[rd] = core.regs[rs1] ^ core.regs[rs2];
-                },
-                funct3::SLL => {
-                    let shamt = core.regs[rs2] & 0b11111;
-                    core.regs[rd] = core.regs[rs1] << shamt;
-                },
-                funct3::SRX => {
-                    let shamt = core.regs[rs2] & 0b11111;
-                    core.regs[rd] = if funct7 == 0 { // SRL
-                        (core.regs[rs1] as u32 >> shamt) as i32
-                    }
-                    else { // SRA
-                        core.regs[rs1] >> shamt
-                    };
-                },
-                funct3::OR => {
-                    core.regs[rd] = core.regs[rs1] | core.regs[rs2];
-                },
-                funct3::AND => {
-                    core.regs[rd] = core.regs[rs1] & core.regs[rs2];
-                },
+            let (lhs, rhs) = (core.regs[rs1], core.regs[rs2]);
+            let shamt = rhs & 0b11111;
+
+            // anything else, M extension included, isn't implemented
+            let val = match (funct3, funct7) {
+                (funct3::ADD_SUB, funct7::BASE) => lhs.wrapping_add(rhs),
+                (funct3::ADD_SUB, funct7::SUB_SRA) => lhs.wrapping_sub(rhs),
+                (funct3::SLT, funct7::BASE) => if lhs < rhs {1} else {0},
+                (funct3::SLTU, funct7::BASE) => if (lhs as u32) < rhs as u32 {1} else {0},
+                (funct3::XOR, funct7::BASE) => lhs ^ rhs,
+                (funct3::SLL, funct7::BASE) => lhs << shamt,
+                (funct3::SRX, funct7::BASE) => (lhs as u32 >> shamt) as i32, // SRL
+                (funct3::SRX, funct7::SUB_SRA) => lhs >> shamt, // SRA
+                (funct3::OR, funct7::BASE) => lhs | rhs,
+                (funct3::AND, funct7::BASE) => lhs & rhs,
                 _ => {
-                    println!("Unknown Funct3 in Opcode Op: {}", funct3);
+                    trap(core, causes::ILLEGAL_INSTRUCTION, ins);
+                    return;
                 }
-            }
+            };
+            write(core, rd, val);
         },
         opcodes::LUI => {
             let UType { rd, imm } = get_u_type(ins);
@@ -319,16 +529,16 @@ pub fn eval(ins: u32, core: &mut Core) {
         opcodes::AUIPC => {
             let UType { rd, imm } = get_u_type(ins);
             if rd != 0 {
-                core.regs[rd] = core.regs[32] + ((imm<<12) as i32);
+                core.regs[rd] = core.regs[32].wrapping_add((imm<<12) as i32);
             }
         },
         opcodes::JAL => {
             let JType { imm, rd } = get_j_type(ins);
             let signed = sign_extend(imm, 21);
             if rd != 0 {
-                core.regs[rd] = core.regs[32]+4;
+                core.regs[rd] = core.regs[32].wrapping_add(4);
             }
-            core.regs[32] = core.regs[32]+signed;
+            core.regs[32] = core.regs[32].wrapping_add(signed);
             return;
         },
         opcodes::JALR => {
@@ -338,15 +548,15 @@ pub fn eval(ins: u32, core: &mut Core) {
             let val = imm.wrapping_add(core.regs[rs1]);
 
             if rd != 0 {
-                core.regs[rd] = core.regs[32]+4;
+                core.regs[rd] = core.regs[32].wrapping_add(4);
             }
-            core.regs[32] = if val%2 == 0 {val} else {val-1};
+            core.regs[32] = val & !1;
             return;
         },
         opcodes::BRANCH => {
             let BType { imm, rs2, rs1, funct3 } = get_b_type(ins);
             let imm = sign_extend(imm,13);
-            let target_addr = (((core.regs[32] + imm) as usize)%MEMSIZE) as i32;
+            let target_addr = core.regs[32].wrapping_add(imm);
             match funct3 {
                 funct3::BEQ => {
                     if core.regs[rs1] == core.regs[rs2] {
@@ -385,65 +595,48 @@ pub fn eval(ins: u32, core: &mut Core) {
                     }
                 },
                 _ => {
-                    println!("Unknown Funct3 in Opcode Branch: {}", funct3);
+                    trap(core, causes::ILLEGAL_INSTRUCTION, ins);
+                    return;
                 }
             }
         },
         opcodes::LOAD => {
             let IType { imm, rs1, funct3, rd } = get_i_type(ins);
-
-            if rd == 0 { core.regs[32] += 4; return }
-
-            let target_addr = ((sign_extend(imm,12)+core.regs[rs1]) as usize)%MEMSIZE;
-            match funct3 {
-                funct3::LB => {
-                    core.regs[rd] = (core.memory[target_addr] as i8) as i32;
-                },
-                funct3::LBU => {
-                    core.regs[rd] = core.memory[target_addr] as i32;
-                },
-                funct3::LH => {
-                    let b1 = core.memory[target_addr] as u16;
-                    let b2 = core.memory[target_addr+1] as u16;
-                    core.regs[rd] = (((b2<<8) | b1) as i16) as i32;
-                },
-                funct3::LHU => {
-                    let b1 = core.memory[target_addr] as u16;
-                    let b2 = core.memory[target_addr+1] as u16;
-                    core.regs[rd] = ((b2<<8) | b1) as i32;
-                },
-                funct3::LW => {
-                    let b1 = core.memory[target_addr] as u32;
-                    let b2 = core.memory[target_addr+1] as u32;
-                    let b3 = core.memory[target_addr+2] as u32;
-                    let b4 = core.memory[target_addr+3] as u32;
-                    core.regs[rd] = ((b4<<24) | (b3<<16) | (b2<<8) | b1) as i32;
-                },
+            let target_addr = sign_extend(imm,12).wrapping_add(core.regs[rs1]) as u32;
+            let size = match funct3 {
+                funct3::LB | funct3::LBU => 1,
+                funct3::LH | funct3::LHU => 2,
+                funct3::LW => 4,
                 _ => {
-                    println!("Unknown funct3 in Opcode Load: {}", funct3);
+                    trap(core, causes::ILLEGAL_INSTRUCTION, ins);
+                    return;
                 }
-            }
+            };
+            let val = match load(core, target_addr, size) {
+                Some(val) => val,
+                None => return
+            };
+            let val = match funct3 {
+                funct3::LB => (val as i8) as i32,
+                funct3::LH => (val as i16) as i32,
+                _ => val as i32
+            };
+            write(core, rd, val);
         },
         opcodes::STORE => {
             let SType { imm, rs2, rs1, funct3 } = get_s_type(ins);
-            let target_addr = ((sign_extend(imm,12)+core.regs[rs1]) as usize)%MEMSIZE;
-            match funct3 {
-                funct3::SB => {
-                    core.memory[target_addr] = core.regs[rs2] as u8;
-                },
-                funct3::SH => {
-                    core.memory[target_addr] = core.regs[rs2] as u8;
-                    core.memory[target_addr+1] = (core.regs[rs2]>>8) as u8;
-                },
-                funct3::SW => {
-                    core.memory[target_addr] = core.regs[rs2] as u8;
-                    core.memory[target_addr+1] = (core.regs[rs2]>>8) as u8;
-                    core.memory[target_addr+2] = (core.regs[rs2]>>16) as u8;
-                    core.memory[target_addr+3] = (core.regs[rs2]>>24) as u8;
-                },
+            let target_addr = sign_extend(imm,12).wrapping_add(core.regs[rs1]) as u32;
+            let size = match funct3 {
+                funct3::SB => 1,
+                funct3::SH => 2,
+                funct3::SW => 4,
                 _ => {
-                    println!("Unknown funct3 in Opcode Store: {}", funct3);
+                    trap(core, causes::ILLEGAL_INSTRUCTION, ins);
+                    return;
                 }
+            };
+            if !store(core, target_addr, size, core.regs[rs2] as u32) {
+                return;
             }
         },
         opcodes::MISCMEM => {
@@ -460,24 +653,45 @@ pub fn eval(ins: u32, core: &mut Core) {
                     */
                 }
                 _ => {
-                    println!("Unknown funct3 in Opcode MiscMem: {} ", funct3);
+                    trap(core, causes::ILLEGAL_INSTRUCTION, ins);
+                    return;
                 }
             }
         },
         opcodes::SYSTEM => {
             let IType { imm, rs1, funct3, rd } = get_i_type(ins);
+            if funct3 != funct3::PRIV {
+                load_counter(core, imm as usize);
+            }
             match (imm, rs1, funct3, rd) {
                 (funct12::ECALL, 0x0, funct3::PRIV, 0x0) => {
-                    //
+                    match core.syscalls.take() {
+                        Some(mut syscalls) => {
+                            syscalls.handle(core);
+                            core.syscalls = Some(syscalls);
+                        },
+                        None => {
+                            trap(core, causes::ECALL_M, 0);
+                            return;
+                        }
+                    }
                 }
                 (funct12::EBREAK, 0x0, funct3::PRIV, 0x0) => {
-                    //
+                    let pc = core.regs[32] as u32;
+                    match core.semihosting.take() {
+                        Some(mut semihosting) if Semihosting::is_call(core, pc) => {
+                            semihosting.handle(core);
+                            core.semihosting = Some(semihosting);
+                        },
+                        semihosting => {
+                            core.semihosting = semihosting;
+                            trap(core, causes::BREAKPOINT, pc);
+                            return;
+                        }
+                    }
                 }
                 (csr, _, funct3::CSRRW, _) => {
                     let val_rs1 = core.regs[rs1];
-                    if rd != 0 {
-                        core.regs[rd] = core.csrs[csr as usize];
-                    }
                     write(core, rd, core.csrs[csr as usize]);
                     core.csrs[csr as usize] = val_rs1;
                 },
@@ -491,9 +705,7 @@ pub fn eval(ins: u32, core: &mut Core) {
                     csr_clear_bits(core, csr as usize, core.regs[rs1]);
                 },
                 (csr, imm, funct3::CSRRWI, _) => {
-                    if rd != 0 {
-                        core.regs[rd] = core.csrs[csr as usize];
-                    }
+                    write(core, rd, core.csrs[csr as usize]);
                     core.csrs[csr as usize] = imm as i32;
                 },
                 (csr, imm, funct3::CSRRSI, _) => {
@@ -505,38 +717,42 @@ pub fn eval(ins: u32, core: &mut Core) {
                 (csr, imm, funct3::CSRRCI, _) => {
                     write(core, rd, core.csrs[csr as usize]);
                     if imm != 0 {
-                        csr_clear_bits(core, csr as usize, core.regs[rs1]);
+                        csr_clear_bits(core, csr as usize, imm as i32);
                     }
                 },
                 (funct12::MRET, 0x0, funct3::PRIV, 0x0) => {
-                    println!("mstatus: {}", core.csrs[0x300]);
-
-                    let mie = take_range(3,3,core.csrs[0x300] as u32);
-                    let mpie = take_range(7,7,core.csrs[0x300] as u32);
-                    let mpp = take_range(12,11,core.csrs[0x300] as u32);
-                    println!("mie: {}, mpie: {}, mpp: {}", mie, mpie, mpp);
-
                     // (machine) return from trap
                     // MIE -> MPIE
                     // privilege mode -> MPP
                     // MPIE -> 1
                     // MPP -> M (user-mode not supported)
+                    let mstatus = core.csrs[csrs::MSTATUS];
+                    let mie = if mstatus & csrs::MSTATUS_MPIE != 0 { csrs::MSTATUS_MIE } else { 0 };
+                    core.csrs[csrs::MSTATUS] = (mstatus & !csrs::MSTATUS_MIE)
+                        | mie | csrs::MSTATUS_MPIE | csrs::MSTATUS_MPP;
 
-                    core.csrs[0x300] = core.csrs[0x300] | 0b10000000;
-                    println!("mie: {}, mpie: {}, mpp: {}", mie, mpie, mpp);
-
-                    // set pc to value in mepc
+                    core.regs[32] = core.csrs[csrs::MEPC];
+                    return;
+                },
+                (funct12::WFI, 0x0, funct3::PRIV, 0x0) => {
+                    // interrupts are checked between instructions, nothing to wait for
                 },
                 _ => {
-                    println!("Unknown SYSTEM at {:#x}", core.regs[32]);
+                    trap(core, causes::ILLEGAL_INSTRUCTION, ins);
+                    return;
                 }
             }
+            if funct3 != funct3::PRIV {
+                store_counter(core, imm as usize);
+            }
         },
+        // all-zero words included, as when running into unwritten memory
         _ => {
-            panic!("Unknown opcode: {}", opcode);
+            trap(core, causes::ILLEGAL_INSTRUCTION, ins);
+            return;
         }
     }
-    core.regs[32] = core.regs[32]+4;
+    core.regs[32] = core.regs[32].wrapping_add(4);
 }
 
 fn load_test_program(core: &mut Core) {
@@ -548,19 +764,193 @@ fn load_test_program(core: &mut Core) {
     */
 }
 
-fn main() {
-    let mut core = init();
+/*
+ * Put the DTB at the top of RAM, the boot ROM passes its address on in a1.
+ */
+fn place_device_tree(core: &mut Core, dump: Option<&str>) {
+    let tree = fdt::device_tree(core);
+    let dtb = tree.to_dtb();
+    if let Some(path) = dump {
+        let data = if path.ends_with(".dts") { tree.to_dts().into_bytes() } else { dtb.clone() };
+        fs::write(path, data).expect("Couldn't write device tree");
+    }
+    let size = core.bus.ram_size();
+    if dtb.len() > size / 2 {
+        println!("RAM too small for the device tree, not placing it");
+        return;
+    }
+    let addr = (core.bus.ram_base() + (size - dtb.len()) as u32) & !0x7;
+    if let Some((_, end)) = core.initrd {
+        if end > addr {
+            println!("The initrd reaches into the device tree at {:#010x}, not placing it", addr);
+            return;
+        }
+    }
+    core.bus.memory.load(addr, &dtb);
+    core.fdt_addr = addr;
+}
 
+/*
+ * Read and load an image file, exiting with a message if that fails.
+ * Without an address only images that say where they go can be loaded, a
+ * raw binary would land on whatever was loaded at --load-addr. Returns its
+ * entry point.
+ */
+fn load_image(core: &mut Core, path: &str, format: Option<Format>, addr: Option<u32>) -> u32 {
+    let bytes = fs::read(path).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    });
+    let format = format.unwrap_or_else(|| Format::detect(&bytes));
+    let addr = match (addr, format) {
+        (Some(addr), _) => addr,
+        (None, Format::Binary) => {
+            eprintln!("{}: a raw binary image needs an address, give it as {}@<addr>", path, path);
+            process::exit(1);
+        },
+        (None, _) => core.bus.ram_base()
+    };
+    return load_program(core, &bytes, format, addr).unwrap_or_else(|e| {
+        eprintln!("{}: {}", path, e);
+        process::exit(1);
+    });
+}
+
+/*
+ * Enter a user program directly, with its arguments and environment on
+ * the stack and its ECALLs served as Linux system calls.
+ */
+fn start_user(core: &mut Core, config: &Config) {
+    let bytes = fs::read(&config.program).expect("Couldn't read file");
+    let mut argv = vec![config.program.clone()];
+    argv.extend(config.args.iter().cloned());
+    let mut rng = Rng::from_seed(config.seed);
+    let mut random = [0; 16];
+    rng.fill(&mut random);
+    let result = ElfFile::parse(&bytes)
+        .map_err(LoadError::from)
+        .and_then(|elf| init_stack(core, &elf, load_bias(&elf, config.load_addr), &argv, &config.env, random));
+    if let Err(e) = result {
+        eprintln!("{}: {}", config.program, e);
+        process::exit(1);
+    }
+    core.reset_vector = core.regs[32] as u32;
+    core.syscalls = Some(Syscalls::new(core, rng));
+}
+
+/*
+ * Write a core file if asked to: after an unhandled trap, or whenever the
+ * machine stops with --core-at-exit.
+ */
+fn dump_core(core: &Core, config: &Config) {
+    let path = match &config.core {
+        Some(path) => path,
+        None => return
+    };
+    let (signal, pc) = match core.fault {
+        Some((cause, pc)) => (coredump::signal(cause), pc),
+        None if config.core_at_exit => (0, core.regs[32] as u32),
+        None => return
+    };
+    match coredump::write_core(core, path, signal, pc) {
+        Ok(()) => println!("Wrote core file {}", path),
+        Err(e) => println!("Couldn't write core file {}: {}", path, e)
+    }
+}
+
+fn add_virtio(core: &mut Core, slot: &mut u32, device: Box<dyn Device>) {
+    if *slot >= VIRTIO_COUNT {
+        panic!("Out of virtio-mmio slots");
+    }
+    let base = VIRTIO_BASE + *slot*VIRTIO_SIZE;
+    core.bus.add_device(base, VIRTIO_SIZE, VIRTIO_IRQ + *slot, device);
+    *slot += 1;
+}
+
+fn main() {
     let args: Vec<String> = env::args().collect();
-    let fname = &args[1];
-    let elf: Vec<u8> = fs::read(fname)
-        .expect("Couldn't read file");
-    load_elf(&mut core, &elf);
+    let config = match parse_args(&args[1..]) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            process::exit(1);
+        }
+    };
 
-    run(&mut core);
+    let mut core = init_with_memory(START_ADDR, config.memory);
+    let deterministic = config.seed.is_some();
+    // the program reads stdin through system calls or semihosting instead
+    let uart_source = if config.user || config.semihosting { &InputSource::None } else { &config.uart_input };
+    let uart_input = HostInput::open(uart_source, deterministic)
+        .expect("Couldn't open UART input");
+    let uart = Uart::new(uart_input);
+    core.bus.add_device(SYSCON_BASE, SYSCON_SIZE, 0, Box::new(Syscon::new()));
+    core.bus.set_interrupt_controller(PLIC_BASE, PLIC_SIZE, Box::new(Plic::new()));
+    core.bus.add_device(UART_BASE, UART_SIZE, UART_IRQ, Box::new(uart));
+    core.bus.add_device(RTC_BASE, RTC_SIZE, RTC_IRQ, Box::new(Rtc::new(config.rtc)));
+    let mut virtio_slot = 0;
+    if let Some(disk) = &config.disk {
+        let blk = VirtioBlk::open(disk, config.disk_mode)
+            .expect("Couldn't open disk image");
+        add_virtio(&mut core, &mut virtio_slot, Box::new(VirtioMmio::new(blk)));
+    }
+    if config.virtio_console {
+        let input = HostInput::open(&config.console_input, deterministic)
+            .expect("Couldn't open console input");
+        let console = VirtioConsole::new(input);
+        add_virtio(&mut core, &mut virtio_slot, Box::new(VirtioMmio::new(console)));
+    }
+    if config.virtio_rng {
+        let rng = VirtioRng::new(Rng::from_seed(config.seed));
+        add_virtio(&mut core, &mut virtio_slot, Box::new(VirtioMmio::new(rng)));
+    }
+    if let Some(fb) = &config.fb {
+        let fb = Framebuffer::new(fb.width, fb.height, fb.format, &fb.output, fb.every);
+        core.bus.add_device(FB_BASE, fb.mapping_size(), 0, Box::new(fb));
+    }
 
-    /*
-    dump_regs(&core);
-    dump_mem(&core, 0xf);
-    */
+    let entry = load_image(&mut core, &config.program, config.format, Some(config.load_addr));
+    if config.semihosting {
+        let mut cmdline = vec![config.program.clone()];
+        cmdline.extend(config.args.iter().cloned());
+        core.semihosting = Some(Semihosting::new(&mut core, &config.semihosting_root, cmdline.join(" ")));
+    }
+    if config.user {
+        start_user(&mut core, &config);
+        core.bus.memory.checkpoint();
+        let code = run(&mut core);
+        core.bus.stop();
+        dump_core(&core, &config);
+        process::exit(code);
+    }
+    for image in config.images.iter() {
+        load_image(&mut core, &image.path, image.format, image.addr);
+    }
+    let next_addr = match &config.kernel {
+        Some(kernel) => load_image(&mut core, &kernel.path, kernel.format, Some(kernel.addr.unwrap_or(KERNEL_ADDR))),
+        None => 0
+    };
+    if let Some(initrd) = &config.initrd {
+        let data = fs::read(&initrd.path).expect("Couldn't read initrd");
+        match load_initrd(&mut core, &data, initrd.addr) {
+            Ok(range) => core.initrd = Some(range),
+            Err(e) => {
+                eprintln!("{}: {}", initrd.path, e);
+                process::exit(1);
+            }
+        }
+    }
+    core.bootargs = config.append.clone();
+    place_device_tree(&mut core, config.dtb_dump.as_deref());
+    core.bus.add_rom(BOOT_ROM_BASE, boot_rom(entry, core.fdt_addr, next_addr));
+    core.reset_vector = BOOT_ROM_BASE;
+    core.regs[32] = BOOT_ROM_BASE as i32;
+    core.bus.memory.checkpoint();
+
+    let raw = if config.raw { RawMode::enable() } else { None };
+    let code = run(&mut core);
+    core.bus.stop();
+    drop(raw);
+    dump_core(&core, &config);
+    process::exit(code);
 }
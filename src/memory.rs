@@ -0,0 +1,343 @@
+use std::cell::Cell;
+
+use crate::bus::BusError;
+
+/*
+ * Sparse guest memory
+ *
+ * Memory is a set of mapped regions, each with its own permissions, backed
+ * by 4K pages that are only allocated on first write. Pages sit in a two
+ * level table indexed by the upper 20 bits of the address, so a lookup is
+ * two array accesses. Unwritten pages read as zero.
+ *
+ * Regions never overlap: mapping over part of one replaces that part, which
+ * is how part of RAM is made read-only or execute-only after the fact. They
+ * are kept sorted and found by binary search, trying the last one hit first.
+ */
+
+pub const PAGE_SIZE: usize = 4096;
+const PAGE_SHIFT: u32 = 12;
+const TABLE_BITS: u32 = 10;
+const TABLE_SIZE: usize = 1 << TABLE_BITS;
+
+// region permissions
+pub const R: u8 = 1;
+pub const W: u8 = 2;
+pub const X: u8 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute
+}
+
+type Page = Box<[u8; PAGE_SIZE]>;
+type Table = Vec<Option<Page>>;
+
+#[derive(Clone, Copy)]
+struct Region {
+    base: u32,
+    size: u64,
+    perms: u8
+}
+
+impl Region {
+    fn contains(&self, addr: u32) -> bool {
+        return addr >= self.base && ((addr - self.base) as u64) < self.size;
+    }
+
+    fn end(&self) -> u64 {
+        return self.base as u64 + self.size;
+    }
+}
+
+pub struct Memory {
+    tables: Vec<Option<Table>>,
+    regions: Vec<Region>,
+    // index of the region the last lookup found
+    last: Cell<usize>,
+    initial: Option<(Vec<Option<Table>>, Vec<Region>)>
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        return Memory {
+            tables: empty_tables(),
+            regions: Vec::new(),
+            last: Cell::new(0),
+            initial: None
+        };
+    }
+
+    /*
+     * Make [base, base+size) accessible with perms, replacing whatever was
+     * mapped there. Nothing is allocated until the region is written to.
+     */
+    pub fn map(&mut self, base: u32, size: u64, perms: u8) {
+        let end = (base as u64 + size).min(1 << 32);
+        let mut regions = Vec::with_capacity(self.regions.len() + 2);
+        for r in self.regions.iter() {
+            if r.end() <= base as u64 || r.base as u64 >= end {
+                regions.push(*r);
+                continue;
+            }
+            // keep the parts sticking out on either side
+            if r.base < base {
+                regions.push(Region { base: r.base, size: (base - r.base) as u64, perms: r.perms });
+            }
+            if r.end() > end {
+                regions.push(Region { base: end as u32, size: r.end() - end, perms: r.perms });
+            }
+        }
+        if end > base as u64 {
+            regions.push(Region { base, size: end - base as u64, perms });
+        }
+        regions.sort_by_key(|r| r.base);
+        self.regions = regions;
+        self.last.set(0);
+    }
+
+    // change the permissions of part of an already mapped range
+    pub fn protect(&mut self, base: u32, size: u64, perms: u8) {
+        self.map(base, size, perms);
+    }
+
+    // read-only, executable region holding data
+    pub fn add_rom(&mut self, base: u32, data: &[u8]) {
+        self.map(base, data.len() as u64, R | X);
+        self.load(base, data);
+    }
+
+    /*
+     * Copy data in ignoring permissions, for loaders. Bytes outside any
+     * region are dropped.
+     */
+    pub fn load(&mut self, addr: u32, data: &[u8]) {
+        for (i, b) in data.iter().enumerate() {
+            let addr = addr.wrapping_add(i as u32);
+            if self.region(addr).is_some() {
+                self.store_byte(addr, *b);
+            }
+        }
+    }
+
+    /*
+     * Clear [addr, addr+len), which has to be writable. Whole pages are
+     * dropped instead of written, so a large BSS costs nothing.
+     */
+    pub fn zero(&mut self, addr: u32, len: u64) -> Result<(), BusError> {
+        if addr as u64 + len > 1 << 32 {
+            return Err(BusError::Unmapped(addr));
+        }
+        self.check(addr, len as usize, Some(Access::Write))?;
+        let end = addr as u64 + len;
+        let mut a = addr as u64;
+        while a < end {
+            let offset = a as usize % PAGE_SIZE;
+            let n = ((PAGE_SIZE - offset) as u64).min(end - a) as usize;
+            let page = (a >> PAGE_SHIFT) as usize;
+            if let Some(table) = self.tables[page >> TABLE_BITS].as_mut() {
+                let slot = &mut table[page & (TABLE_SIZE-1)];
+                if n == PAGE_SIZE {
+                    *slot = None;
+                }
+                else if let Some(page) = slot.as_mut() {
+                    page[offset..offset+n].fill(0);
+                }
+            }
+            a += n as u64;
+        }
+        return Ok(());
+    }
+
+    fn region(&self, addr: u32) -> Option<&Region> {
+        if let Some(r) = self.regions.get(self.last.get()) {
+            if r.contains(addr) {
+                return Some(r);
+            }
+        }
+        let i = self.regions.partition_point(|r| r.base <= addr).checked_sub(1)?;
+        let r = &self.regions[i];
+        if !r.contains(addr) {
+            return None;
+        }
+        self.last.set(i);
+        return Some(r);
+    }
+
+    pub fn contains(&self, addr: u32, len: usize) -> bool {
+        return self.check(addr, len, None).is_ok();
+    }
+
+    /*
+     * Every byte of the access has to be mapped with the permission it
+     * needs, None only asks for it to be mapped.
+     */
+    fn check(&self, addr: u32, len: usize, access: Option<Access>) -> Result<(), BusError> {
+        if addr as u64 + len as u64 > 1 << 32 {
+            return Err(BusError::Unmapped(addr));
+        }
+        let mut i = 0;
+        while i < len {
+            let a = addr + i as u32;
+            let region = self.region(a).ok_or(BusError::Unmapped(addr))?;
+            let denied = match access {
+                Some(Access::Read) if region.perms & R == 0 => Some(BusError::NotReadable(addr)),
+                Some(Access::Write) if region.perms & W == 0 => Some(BusError::ReadOnly(addr)),
+                Some(Access::Execute) if region.perms & X == 0 => Some(BusError::NotExecutable(addr)),
+                _ => None
+            };
+            if let Some(e) = denied {
+                return Err(e);
+            }
+            // nothing changes until the end of the region
+            i += (region.end() - a as u64).min((len - i) as u64) as usize;
+        }
+        return Ok(());
+    }
+
+    fn page(&self, addr: u32) -> Option<&Page> {
+        let page = addr >> PAGE_SHIFT;
+        let table = self.tables[(page >> TABLE_BITS) as usize].as_ref()?;
+        return table[page as usize & (TABLE_SIZE-1)].as_ref();
+    }
+
+    fn page_mut(&mut self, addr: u32) -> &mut Page {
+        let page = addr >> PAGE_SHIFT;
+        let table = self.tables[(page >> TABLE_BITS) as usize]
+            .get_or_insert_with(|| (0..TABLE_SIZE).map(|_| None).collect());
+        return table[page as usize & (TABLE_SIZE-1)]
+            .get_or_insert_with(|| Box::new([0; PAGE_SIZE]));
+    }
+
+    fn load_byte(&self, addr: u32) -> u8 {
+        return self.page(addr).map(|p| p[addr as usize % PAGE_SIZE]).unwrap_or(0);
+    }
+
+    fn store_byte(&mut self, addr: u32, value: u8) {
+        self.page_mut(addr)[addr as usize % PAGE_SIZE] = value;
+    }
+
+    // little endian value of size bytes, fast when it doesn't cross a page
+    fn load_value(&self, addr: u32, size: u32) -> u32 {
+        let offset = addr as usize % PAGE_SIZE;
+        if offset + size as usize <= PAGE_SIZE {
+            let page = match self.page(addr) {
+                Some(page) => page,
+                None => return 0
+            };
+            if size == 4 {
+                return u32::from_le_bytes([page[offset], page[offset+1], page[offset+2], page[offset+3]]);
+            }
+            let mut val = 0;
+            for b in 0..size as usize {
+                val |= (page[offset+b] as u32) << (8*b);
+            }
+            return val;
+        }
+        let mut val = 0;
+        for b in 0..size {
+            val |= (self.load_byte(addr + b) as u32) << (8*b);
+        }
+        return val;
+    }
+
+    fn store_value(&mut self, addr: u32, size: u32, value: u32) {
+        let offset = addr as usize % PAGE_SIZE;
+        if offset + size as usize <= PAGE_SIZE {
+            let page = self.page_mut(addr);
+            if size == 4 {
+                page[offset..offset+4].copy_from_slice(&value.to_le_bytes());
+                return;
+            }
+            for b in 0..size as usize {
+                page[offset+b] = (value >> (8*b)) as u8;
+            }
+            return;
+        }
+        for b in 0..size {
+            self.store_byte(addr + b, (value >> (8*b)) as u8);
+        }
+    }
+
+    pub fn read(&self, addr: u32, size: u32) -> Result<u32, BusError> {
+        self.check(addr, size as usize, Some(Access::Read))?;
+        return Ok(self.load_value(addr, size));
+    }
+
+    pub fn fetch(&self, addr: u32) -> Result<u32, BusError> {
+        self.check(addr, 4, Some(Access::Execute))?;
+        return Ok(self.load_value(addr, 4));
+    }
+
+    pub fn write(&mut self, addr: u32, size: u32, value: u32) -> Result<(), BusError> {
+        self.check(addr, size as usize, Some(Access::Write))?;
+        self.store_value(addr, size, value);
+        return Ok(());
+    }
+
+    pub fn read_slice(&self, addr: u32, buf: &mut [u8]) -> Result<(), BusError> {
+        self.check(addr, buf.len(), Some(Access::Read))?;
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = self.load_byte(addr + i as u32);
+        }
+        return Ok(());
+    }
+
+    pub fn write_slice(&mut self, addr: u32, buf: &[u8]) -> Result<(), BusError> {
+        self.check(addr, buf.len(), Some(Access::Write))?;
+        for (i, b) in buf.iter().enumerate() {
+            self.store_byte(addr + i as u32, *b);
+        }
+        return Ok(());
+    }
+
+    // pages backed by host memory and their addresses, lowest first
+    pub fn pages(&self) -> Vec<(u32, &[u8])> {
+        let mut pages = Vec::new();
+        for (i, table) in self.tables.iter().enumerate() {
+            for (j, page) in table.iter().flatten().enumerate() {
+                if let Some(page) = page {
+                    let addr = (((i << TABLE_BITS) | j) << PAGE_SHIFT) as u32;
+                    pages.push((addr, &page[..]));
+                }
+            }
+        }
+        return pages;
+    }
+
+    // number of pages backed by host memory
+    pub fn allocated_pages(&self) -> usize {
+        return self.tables.iter().flatten().flat_map(|t| t.iter()).filter(|p| p.is_some()).count();
+    }
+
+    /*
+     * Remember the current contents and regions, usually the loaded program
+     * with its permissions, for reset.
+     */
+    pub fn checkpoint(&mut self) {
+        self.initial = Some((self.tables.clone(), self.regions.clone()));
+    }
+
+    pub fn reset(&mut self) {
+        match &self.initial {
+            Some((tables, regions)) => {
+                self.tables = tables.clone();
+                self.regions = regions.clone();
+                self.last.set(0);
+            },
+            None => self.tables = empty_tables()
+        }
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Memory {
+        return Memory::new();
+    }
+}
+
+fn empty_tables() -> Vec<Option<Table>> {
+    return (0..TABLE_SIZE).map(|_| None).collect();
+}
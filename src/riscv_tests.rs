@@ -2,18 +2,23 @@
 // - rv32ui-p-*
 // - assumes the tests are compiled into elfs at ./riscv-tests/isa/
 #[cfg(test)]
+#[allow(clippy::module_inception)]
 mod riscv_tests {
     use std::fs;
 
     use colored::*;
 
     use crate::Core;
-    use crate::init;
-    use crate::eval;
+    use crate::init_with_memory;
+    use crate::step;
+    use crate::constants::MEMSIZE;
+    use crate::constants::START_ADDR;
     use crate::elf::*;
+    use crate::htif::Htif;
 
     const FOLDER: &str = "./riscv-tests/isa/";
     const PREFIX: &str = "rv32ui-p-";
+    const MAX_INSTRUCTIONS: u32 = 1_000_000;
 
     #[test]
     fn run_riscv_tests() {
@@ -21,25 +26,23 @@ mod riscv_tests {
         let mut success = 0;
         println!("ATTEMPTING TO RUN TEST SET `RISCV-TESTS`\n");
         let paths = fs::read_dir(FOLDER).unwrap();
-        for item in paths {
-            if let Ok(item) = item {
-                if let Ok(st) = item.file_name().into_string() {
-                    if st.starts_with(PREFIX) {
-                        if let None = item.path().extension() {
-                            let elf: Vec<u8> = fs::read(item.path())
-                                .expect("Couldn't read file");
-                            let mut core = init();
-                            load_elf(&mut core, &elf);
-                            if !(st == "rv32ui-p-simple") { // this has no fail/pass addrs
-                                let (pass_addr, fail_addr) = get_riscv_tests_addrs(&elf);
-                                println!("Running set {}: {}", i+1, st);
-                                let res = execute_riscv_test(&mut core, pass_addr, fail_addr);
-                                assert_eq!(res, 1);
-                                success += execute_riscv_test(&mut core, pass_addr, fail_addr);
-                                i += 1;
-                            }
-                        }
-                    }
+        for item in paths.flatten() {
+            if let Ok(st) = item.file_name().into_string() {
+                if st.starts_with(PREFIX) && item.path().extension().is_none() {
+                    let bytes: Vec<u8> = fs::read(item.path())
+                        .expect("Couldn't read file");
+                    let elf = ElfFile::parse(&bytes).expect("Couldn't parse ELF");
+                    let mut core = init_with_memory(START_ADDR, MEMSIZE);
+                    load_elf(&mut core, &elf, START_ADDR).expect("Couldn't load ELF");
+                    core.regs[32] = elf.header.e_entry as i32;
+                    let tohost = get_symbol(&elf, "tohost").expect("No tohost symbol");
+                    core.htif = Some(Htif::new(tohost, get_symbol(&elf, "fromhost")));
+
+                    println!("Running set {}: {}", i+1, st);
+                    let res = execute_riscv_test(&mut core);
+                    assert_eq!(res, 1);
+                    success += res;
+                    i += 1;
                 }
             }
         }
@@ -50,26 +53,30 @@ mod riscv_tests {
         }
     }
 
-    fn execute_riscv_test(core: &mut Core, pass_addr: u32, fail_addr: u32) -> u32 {
-        loop {
-            let pc = core.regs[32] as usize;
-            let ins = ((core.memory[pc+3] as u32) << 24)
-                | ((core.memory[pc+2] as u32) << 16)
-                | ((core.memory[pc+1] as u32) << 8)
-                | core.memory[pc] as u32;
-            if ins == 0 {
-                println!("- {}", "testset failed: reg[pc] == 0".red());
-                return 0;
-            };
-            eval(ins, core);
-            if pc == pass_addr as usize {
-                println!("- {}", "testset ran successfully!".green());
-                return 1;
-            }
-            else if pc == fail_addr as usize {
-                println!("- {}", "testset failed: reg[pc] == fail_addr".red());
-                return 0;
+    /*
+     * The test reports through HTIF: exit status 0 is a pass, anything else
+     * is the number of the failing test case.
+     */
+    fn execute_riscv_test(core: &mut Core) -> u32 {
+        for _ in 0..MAX_INSTRUCTIONS {
+            if step(core) {
+                return match core.exit_code {
+                    Some(0) => {
+                        println!("- {}", "testset ran successfully!".green());
+                        1
+                    },
+                    Some(n) => {
+                        println!("- {} {}", "testset failed: test".red(), n);
+                        0
+                    },
+                    None => {
+                        println!("- {}", "testset failed: stopped without exit".red());
+                        0
+                    }
+                };
             }
         }
+        println!("- {}", "testset failed: ran out of instructions".red());
+        return 0;
     }
 }
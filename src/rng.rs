@@ -0,0 +1,49 @@
+use std::fs::File;
+use std::io::Read;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/*
+ * Small pseudo random generator (splitmix64) for everything the guest can
+ * observe as randomness. A fixed seed makes runs reproducible.
+ */
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        return Rng { state: seed };
+    }
+
+    // seeded from the host when no seed is configured
+    pub fn from_seed(seed: Option<u64>) -> Rng {
+        return Rng::new(seed.unwrap_or_else(host_seed));
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        return z ^ (z >> 31);
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
+
+fn host_seed() -> u64 {
+    let mut bytes = [0; 8];
+    if let Ok(mut f) = File::open("/dev/urandom") {
+        if f.read_exact(&mut bytes).is_ok() {
+            return u64::from_le_bytes(bytes);
+        }
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    return now.as_nanos() as u64;
+}
@@ -0,0 +1,368 @@
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+
+use crate::Core;
+use crate::constants::STACK_SIZE;
+use crate::memory::PAGE_SIZE;
+use crate::syscalls::{errno, now_ns, EBADF, EFAULT, EINVAL, ENAMETOOLONG, ENOENT, ENOSYS};
+
+/*
+ * RISC-V semihosting
+ *
+ * An EBREAK between the markers
+ *
+ *   slli x0, x0, 0x1f
+ *   ebreak
+ *   srai x0, x0, 7
+ *
+ * asks the host for a service: a0 holds the operation, a1 a parameter or
+ * the address of a block of words holding them, and the result comes back
+ * in a0. Files are opened under the host directory given with
+ * --semihosting-root and can't be reached outside it. The special file
+ * ":tt" is the console, stdin when opened for reading, stdout for writing
+ * and stderr for appending.
+ */
+
+pub const SLLI_MARKER: u32 = 0x01f01013;
+pub const SRAI_MARKER: u32 = 0x40705013;
+
+pub const SYS_OPEN: u32 = 0x01;
+pub const SYS_CLOSE: u32 = 0x02;
+pub const SYS_WRITEC: u32 = 0x03;
+pub const SYS_WRITE0: u32 = 0x04;
+pub const SYS_WRITE: u32 = 0x05;
+pub const SYS_READ: u32 = 0x06;
+pub const SYS_ISTTY: u32 = 0x09;
+pub const SYS_SEEK: u32 = 0x0a;
+pub const SYS_FLEN: u32 = 0x0c;
+pub const SYS_CLOCK: u32 = 0x10;
+pub const SYS_TIME: u32 = 0x11;
+pub const SYS_ERRNO: u32 = 0x13;
+pub const SYS_GET_CMDLINE: u32 = 0x15;
+pub const SYS_HEAPINFO: u32 = 0x16;
+pub const SYS_EXIT: u32 = 0x18;
+pub const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+// SYS_EXIT reason for a normal exit, anything else is a failure
+pub const ADP_STOPPED_APPLICATION_EXIT: u32 = 0x20026;
+
+// what the magic file ":semihosting-features" holds: SYS_EXIT_EXTENDED and
+// separate stdout and stderr through ":tt"
+const FEATURES: [u8; 5] = [b'S', b'H', b'F', b'B', 0b11];
+
+// at most this much is copied per read or write
+const IO_CHUNK: u32 = 1 << 20;
+// longest file name taken, PATH_MAX on Linux
+const PATH_MAX: usize = 4096;
+
+enum Handle {
+    Stdin,
+    Stdout,
+    Stderr,
+    File(File),
+    Data(Cursor<Vec<u8>>)
+}
+
+pub struct Semihosting {
+    root: PathBuf,
+    cmdline: String,
+    stdin: Box<dyn Read>,
+    stdout: Box<dyn Write>,
+    stderr: Box<dyn Write>,
+    // handle n is files[n - 1]
+    files: Vec<Option<Handle>>,
+    errno: i32,
+    start_ns: u64
+}
+
+impl Semihosting {
+    pub fn new(core: &mut Core, root: &str, cmdline: String) -> Semihosting {
+        return Semihosting::with_streams(core, root, cmdline, Box::new(io::stdin()), Box::new(io::stdout()), Box::new(io::stderr()));
+    }
+
+    pub fn with_streams(core: &mut Core, root: &str, cmdline: String, stdin: Box<dyn Read>, stdout: Box<dyn Write>, stderr: Box<dyn Write>) -> Semihosting {
+        return Semihosting {
+            root: PathBuf::from(root),
+            cmdline,
+            stdin,
+            stdout,
+            stderr,
+            files: Vec::new(),
+            errno: 0,
+            start_ns: now_ns(core)
+        };
+    }
+
+    /*
+     * Whether the EBREAK at pc is a semihosting call.
+     */
+    pub fn is_call(core: &mut Core, pc: u32) -> bool {
+        let before = core.bus.fetch(pc.wrapping_sub(4));
+        let after = core.bus.fetch(pc.wrapping_add(4));
+        return before == Ok(SLLI_MARKER) && after == Ok(SRAI_MARKER);
+    }
+
+    /*
+     * Carry out the operation in a0 and put the result there.
+     */
+    pub fn handle(&mut self, core: &mut Core) {
+        let op = core.regs[10] as u32;
+        let param = core.regs[11] as u32;
+        let result = match op {
+            SYS_OPEN => self.open(core, param),
+            SYS_CLOSE => self.close(core, param),
+            SYS_WRITEC => self.writec(core, param),
+            SYS_WRITE0 => self.write0(core, param),
+            SYS_WRITE => self.write(core, param),
+            SYS_READ => self.read(core, param),
+            SYS_ISTTY => self.istty(core, param),
+            SYS_SEEK => self.seek(core, param),
+            SYS_FLEN => self.flen(core, param),
+            SYS_CLOCK => Ok((now_ns(core).wrapping_sub(self.start_ns) / 10_000_000) as u32),
+            SYS_TIME => Ok((now_ns(core) / 1_000_000_000) as u32),
+            SYS_ERRNO => Ok(self.errno as u32),
+            SYS_GET_CMDLINE => self.get_cmdline(core, param),
+            SYS_HEAPINFO => heapinfo(core, param),
+            SYS_EXIT => {
+                core.exit_code = Some(if param == ADP_STOPPED_APPLICATION_EXIT { 0 } else { 1 });
+                Ok(0)
+            },
+            SYS_EXIT_EXTENDED => exit_extended(core, param),
+            _ => {
+                let pc = core.regs[32] as u32;
+                eprintln!("Unimplemented semihosting operation {:#x} at {:#010x} ({})", op, pc, crate::location(core, pc));
+                Err(ENOSYS)
+            }
+        };
+        core.regs[10] = match result {
+            Ok(value) => value as i32,
+            Err(errno) => {
+                self.errno = errno;
+                -1
+            }
+        };
+    }
+
+    fn handle_of(&mut self, handle: u32) -> Result<&mut Handle, i32> {
+        let i = handle.checked_sub(1).ok_or(EBADF)? as usize;
+        return self.files.get_mut(i).and_then(|f| f.as_mut()).ok_or(EBADF);
+    }
+
+    /*
+     * The host path for a guest file name, None if it would lead outside
+     * the root, through ".." or a symbolic link. A dangling link counts as
+     * existing, so it can't be followed to create a file somewhere else.
+     */
+    fn host_path(&self, name: &str) -> Option<PathBuf> {
+        let mut path = self.root.clone();
+        for part in Path::new(name).components() {
+            match part {
+                Component::Normal(part) => path.push(part),
+                Component::RootDir | Component::CurDir => {},
+                _ => return None
+            }
+        }
+        let root = self.root.canonicalize().ok()?;
+        let mut existing = path.as_path();
+        while existing.symlink_metadata().is_err() {
+            existing = existing.parent()?;
+        }
+        if !existing.canonicalize().ok()?.starts_with(&root) {
+            return None;
+        }
+        return Some(path);
+    }
+
+    // [name, mode, name length], mode as for fopen: r, rb, r+, r+b, w, ...
+    fn open(&mut self, core: &mut Core, param: u32) -> Result<u32, i32> {
+        let block = words(core, param, 3)?;
+        let (mode, len) = (block[1], block[2]);
+        if len as usize > PATH_MAX {
+            return Err(ENAMETOOLONG);
+        }
+        let name = String::from_utf8_lossy(&bytes(core, block[0], len)?).into_owned();
+        let handle = match (name.as_str(), mode) {
+            (":tt", 0..=3) => Handle::Stdin,
+            (":tt", 4..=7) => Handle::Stdout,
+            (":tt", 8..=11) => Handle::Stderr,
+            (":semihosting-features", 0..=3) => Handle::Data(Cursor::new(FEATURES.to_vec())),
+            (_, 0..=11) => {
+                let path = self.host_path(&name).ok_or(ENOENT)?;
+                let mut options = OpenOptions::new();
+                match mode / 4 {
+                    0 => options.read(true).write(mode & 2 != 0),
+                    1 => options.write(true).create(true).truncate(true).read(mode & 2 != 0),
+                    _ => options.append(true).create(true).read(mode & 2 != 0)
+                };
+                Handle::File(options.open(path).map_err(|e| errno(&e))?)
+            },
+            _ => return Err(EINVAL)
+        };
+        let handle = Some(handle);
+        return match self.files.iter().position(|f| f.is_none()) {
+            Some(i) => {
+                self.files[i] = handle;
+                Ok(i as u32 + 1)
+            },
+            None => {
+                self.files.push(handle);
+                Ok(self.files.len() as u32)
+            }
+        };
+    }
+
+    fn close(&mut self, core: &mut Core, param: u32) -> Result<u32, i32> {
+        let handle = words(core, param, 1)?[0];
+        self.handle_of(handle)?;
+        self.files[handle as usize - 1] = None;
+        return Ok(0);
+    }
+
+    fn writec(&mut self, core: &mut Core, param: u32) -> Result<u32, i32> {
+        let c = bytes(core, param, 1)?;
+        self.stdout.write_all(&c).and_then(|_| self.stdout.flush()).map_err(|e| errno(&e))?;
+        return Ok(0);
+    }
+
+    fn write0(&mut self, core: &mut Core, param: u32) -> Result<u32, i32> {
+        let mut s = Vec::new();
+        loop {
+            let b = core.bus.memory.read(param.wrapping_add(s.len() as u32), 1).map_err(|_| EFAULT)?;
+            if b == 0 {
+                break;
+            }
+            s.push(b as u8);
+        }
+        self.stdout.write_all(&s).and_then(|_| self.stdout.flush()).map_err(|e| errno(&e))?;
+        return Ok(0);
+    }
+
+    // [handle, buffer, length], returns how many bytes were not written
+    fn write(&mut self, core: &mut Core, param: u32) -> Result<u32, i32> {
+        let block = words(core, param, 3)?;
+        let len = block[2].min(IO_CHUNK);
+        let data = bytes(core, block[1], len)?;
+        let result = match self.handle_of(block[0])? {
+            Handle::Stdout => self.stdout.write_all(&data).and_then(|_| self.stdout.flush()),
+            Handle::Stderr => self.stderr.write_all(&data).and_then(|_| self.stderr.flush()),
+            Handle::File(file) => file.write_all(&data),
+            _ => return Err(EBADF)
+        };
+        result.map_err(|e| errno(&e))?;
+        return Ok(block[2] - len);
+    }
+
+    // [handle, buffer, length], returns how many bytes were not read
+    fn read(&mut self, core: &mut Core, param: u32) -> Result<u32, i32> {
+        let block = words(core, param, 3)?;
+        let len = block[2].min(IO_CHUNK);
+        if !core.bus.memory.contains(block[1], len as usize) {
+            return Err(EFAULT);
+        }
+        let mut data = vec![0; len as usize];
+        let n = match self.handle_of(block[0])? {
+            Handle::Stdin => self.stdin.read(&mut data),
+            Handle::File(file) => file.read(&mut data),
+            Handle::Data(cursor) => cursor.read(&mut data),
+            _ => return Err(EBADF)
+        }.map_err(|e| errno(&e))?;
+        core.bus.memory.write_slice(block[1], &data[..n]).map_err(|_| EFAULT)?;
+        return Ok(block[2] - n as u32);
+    }
+
+    fn istty(&mut self, core: &mut Core, param: u32) -> Result<u32, i32> {
+        let handle = words(core, param, 1)?[0];
+        return match self.handle_of(handle)? {
+            Handle::Stdin | Handle::Stdout | Handle::Stderr => Ok(1),
+            _ => Ok(0)
+        };
+    }
+
+    // [handle, position from the start]
+    fn seek(&mut self, core: &mut Core, param: u32) -> Result<u32, i32> {
+        let block = words(core, param, 2)?;
+        let pos = SeekFrom::Start(block[1] as u64);
+        match self.handle_of(block[0])? {
+            Handle::File(file) => file.seek(pos),
+            Handle::Data(cursor) => cursor.seek(pos),
+            _ => return Err(EINVAL)
+        }.map_err(|e| errno(&e))?;
+        return Ok(0);
+    }
+
+    fn flen(&mut self, core: &mut Core, param: u32) -> Result<u32, i32> {
+        let handle = words(core, param, 1)?[0];
+        return match self.handle_of(handle)? {
+            Handle::File(file) => Ok(file.metadata().map_err(|e| errno(&e))?.len() as u32),
+            Handle::Data(cursor) => Ok(cursor.get_ref().len() as u32),
+            _ => Err(EINVAL)
+        };
+    }
+
+    // [buffer, length], the length is updated to that of the command line
+    fn get_cmdline(&mut self, core: &mut Core, param: u32) -> Result<u32, i32> {
+        let block = words(core, param, 2)?;
+        let len = self.cmdline.len() as u32;
+        if len >= block[1] {
+            return Err(EINVAL);
+        }
+        let mut data = self.cmdline.clone().into_bytes();
+        data.push(0);
+        core.bus.memory.write_slice(block[0], &data).map_err(|_| EFAULT)?;
+        core.bus.memory.write(param + 4, 4, len).map_err(|_| EFAULT)?;
+        return Ok(0);
+    }
+}
+
+// [reason, exit status]
+fn exit_extended(core: &mut Core, param: u32) -> Result<u32, i32> {
+    let block = words(core, param, 2)?;
+    core.exit_code = Some(if block[0] == ADP_STOPPED_APPLICATION_EXIT { block[1] as i32 } else { 1 });
+    return Ok(0);
+}
+
+/*
+ * The parameter points to the address of a block of four words that gets
+ * heap base and limit, then stack base and limit. The heap goes from the
+ * end of the program up to the stack, which takes the top of RAM below the
+ * device tree.
+ */
+fn heapinfo(core: &mut Core, param: u32) -> Result<u32, i32> {
+    let block = words(core, param, 1)?[0];
+    let page = PAGE_SIZE as u32;
+    let end = core.segments.iter().map(|s| s.base.wrapping_add(s.size)).max()
+        .unwrap_or(core.bus.ram_base());
+    let heap_base = end.wrapping_add(page - 1) & !(page - 1);
+    let top = core.bus.ram_base().wrapping_add(core.bus.ram_size() as u32);
+    let stack_base = if core.fdt_addr != 0 { core.fdt_addr & !0xf } else { top };
+    let stack_limit = stack_base.wrapping_sub((core.bus.ram_size() as u32 / 8).min(STACK_SIZE)).max(heap_base);
+    for (i, value) in [heap_base, stack_limit, stack_base, stack_limit].iter().enumerate() {
+        core.bus.memory.write(block.wrapping_add(4 * i as u32), 4, *value).map_err(|_| EFAULT)?;
+    }
+    return Ok(0);
+}
+
+// n parameter words at addr
+fn words(core: &Core, addr: u32, n: u32) -> Result<Vec<u32>, i32> {
+    let mut words = Vec::new();
+    for i in 0..n {
+        words.push(core.bus.memory.read(addr.wrapping_add(4 * i), 4).map_err(|_| EFAULT)?);
+    }
+    return Ok(words);
+}
+
+fn bytes(core: &Core, addr: u32, len: u32) -> Result<Vec<u8>, i32> {
+    if !core.bus.memory.contains(addr, len as usize) {
+        return Err(EFAULT);
+    }
+    let mut data = vec![0; len as usize];
+    core.bus.memory.read_slice(addr, &mut data).map_err(|_| EFAULT)?;
+    return Ok(data);
+}
@@ -0,0 +1,143 @@
+/*
+ * Symbol table
+ *
+ * Symbols from a program's symbol tables, looked up by name or by address.
+ * Address lookups go by st_size, so an address resolves to the function or
+ * object it falls in. Symbols without a size, like labels in hand written
+ * assembly, only catch addresses that no sized symbol covers.
+ */
+
+pub const STT_NOTYPE: u8 = 0;
+pub const STT_OBJECT: u8 = 1;
+pub const STT_FUNC: u8 = 2;
+pub const STT_SECTION: u8 = 3;
+pub const STT_FILE: u8 = 4;
+
+pub const STB_LOCAL: u8 = 0;
+pub const STB_GLOBAL: u8 = 1;
+pub const STB_WEAK: u8 = 2;
+
+pub const SHN_UNDEF: u16 = 0;
+pub const SHN_ABS: u16 = 0xfff1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Symbol {
+    pub name: String,
+    pub value: u32,
+    pub size: u32,
+    pub info: u8,
+    pub other: u8,
+    pub shndx: u16
+}
+
+impl Symbol {
+    pub fn kind(&self) -> u8 {
+        return self.info & 0xf;
+    }
+
+    pub fn bind(&self) -> u8 {
+        return self.info >> 4;
+    }
+
+    pub fn is_defined(&self) -> bool {
+        return self.shndx != SHN_UNDEF;
+    }
+
+    // something code or data addresses can be resolved to
+    fn is_location(&self) -> bool {
+        return self.is_defined() && self.shndx != SHN_ABS && !self.name.is_empty()
+            && matches!(self.kind(), STT_NOTYPE | STT_OBJECT | STT_FUNC);
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+    // locations sorted by address
+    by_addr: Vec<usize>,
+    max_size: u32
+}
+
+impl SymbolTable {
+    pub fn new(symbols: Vec<Symbol>) -> SymbolTable {
+        let mut by_addr: Vec<usize> = (0..symbols.len())
+            .filter(|i| symbols[*i].is_location())
+            .collect();
+        by_addr.sort_by_key(|i| symbols[*i].value);
+        let max_size = by_addr.iter().map(|i| symbols[*i].size).max().unwrap_or(0);
+        return SymbolTable { symbols, by_addr, max_size };
+    }
+
+    // the same symbols with their addresses moved by bias
+    pub fn relocate(self, bias: u32) -> SymbolTable {
+        let symbols = self.symbols.into_iter().map(|mut sym| {
+            if sym.is_defined() && sym.shndx != SHN_ABS {
+                sym.value = sym.value.wrapping_add(bias);
+            }
+            return sym;
+        }).collect();
+        return SymbolTable::new(symbols);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        return self.symbols.iter();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.symbols.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.symbols.is_empty();
+    }
+
+    /*
+     * First defined symbol called name. With both .symtab and .dynsym
+     * present the same name usually shows up in each, with the same value.
+     */
+    pub fn lookup(&self, name: &str) -> Option<&Symbol> {
+        return self.symbols.iter().find(|sym| sym.is_defined() && sym.name == name);
+    }
+
+    /*
+     * Symbol addr falls in and the offset into it. Functions win over
+     * other symbols covering the same address, sized symbols over labels.
+     */
+    pub fn find(&self, addr: u32) -> Option<(&Symbol, u32)> {
+        let end = self.by_addr.partition_point(|i| self.symbols[*i].value <= addr);
+        let mut best: Option<&Symbol> = None;
+        let mut label: Option<&Symbol> = None;
+        for i in self.by_addr[..end].iter().rev() {
+            let sym = &self.symbols[*i];
+            // nothing further down is big enough to reach addr
+            if addr - sym.value >= self.max_size && (best.is_some() || label.is_some()) {
+                break;
+            }
+            if sym.size == 0 {
+                if label.is_none() {
+                    label = Some(sym);
+                }
+                continue;
+            }
+            if addr - sym.value < sym.size {
+                match best {
+                    Some(b) if b.kind() == STT_FUNC || sym.kind() != STT_FUNC => {},
+                    _ => best = Some(sym)
+                }
+            }
+        }
+        return best.or(label).map(|sym| (sym, addr - sym.value));
+    }
+
+    // "function+0x10", or just the name right at the symbol
+    pub fn describe(&self, addr: u32) -> Option<String> {
+        return self.find(addr).map(|(sym, offset)| {
+            if offset == 0 {
+                sym.name.clone()
+            }
+            else {
+                format!("{}+{:#x}", sym.name, offset)
+            }
+        });
+    }
+}
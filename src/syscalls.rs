@@ -0,0 +1,574 @@
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::Core;
+use crate::constants::{RTC_BASE, STACK_SIZE};
+use crate::devices::rtc::{TIME_HIGH, TIME_LOW};
+use crate::memory::PAGE_SIZE;
+use crate::rng::Rng;
+
+/*
+ * Linux system calls for user programs
+ *
+ * With --user an ECALL is a system call, as under qemu-user: a7 holds the
+ * number, a0-a5 the arguments and the result goes back in a0, -errno when
+ * it fails. Files are host files. The program break grows up from the end
+ * of the program and mappings are handed out downwards from below the
+ * stack, both in RAM. The clock is the RTC's, so --seed runs see the same
+ * time on every run.
+ *
+ * The numbers are the rv32 ones: lseek is _llseek, with the offset split in
+ * two words and the result stored through a pointer, and mmap is mmap2,
+ * with the offset in pages.
+ */
+
+pub const SYS_IOCTL: u32 = 29;
+pub const SYS_OPENAT: u32 = 56;
+pub const SYS_CLOSE: u32 = 57;
+pub const SYS_LSEEK: u32 = 62;
+pub const SYS_READ: u32 = 63;
+pub const SYS_WRITE: u32 = 64;
+pub const SYS_READV: u32 = 65;
+pub const SYS_WRITEV: u32 = 66;
+pub const SYS_FSTAT: u32 = 80;
+pub const SYS_EXIT: u32 = 93;
+pub const SYS_EXIT_GROUP: u32 = 94;
+pub const SYS_SET_TID_ADDRESS: u32 = 96;
+pub const SYS_CLOCK_GETTIME: u32 = 113;
+pub const SYS_UNAME: u32 = 160;
+pub const SYS_BRK: u32 = 214;
+pub const SYS_MUNMAP: u32 = 215;
+pub const SYS_MMAP: u32 = 222;
+pub const SYS_GETRANDOM: u32 = 278;
+pub const SYS_STATX: u32 = 291;
+pub const SYS_CLOCK_GETTIME64: u32 = 403;
+
+pub const ENOENT: i32 = 2;
+pub const EIO: i32 = 5;
+pub const EBADF: i32 = 9;
+pub const ENOMEM: i32 = 12;
+pub const EACCES: i32 = 13;
+pub const EFAULT: i32 = 14;
+pub const EEXIST: i32 = 17;
+pub const EISDIR: i32 = 21;
+pub const EINVAL: i32 = 22;
+pub const ENOTTY: i32 = 25;
+pub const ESPIPE: i32 = 29;
+pub const ENAMETOOLONG: i32 = 36;
+pub const ENOSYS: i32 = 38;
+
+pub const AT_FDCWD: i32 = -100;
+pub const AT_EMPTY_PATH: u32 = 0x1000;
+
+// openat flags
+const O_ACCMODE: u32 = 0o3;
+const O_WRONLY: u32 = 0o1;
+const O_RDWR: u32 = 0o2;
+const O_CREAT: u32 = 0o100;
+const O_EXCL: u32 = 0o200;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+// mmap flags
+pub const MAP_FIXED: u32 = 0x10;
+pub const MAP_ANONYMOUS: u32 = 0x20;
+
+const CLOCK_REALTIME: u32 = 0;
+const CLOCK_REALTIME_COARSE: u32 = 5;
+const CLOCK_BOOTTIME: u32 = 7;
+
+const S_IFCHR: u32 = 0o020000;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+
+// libgloss struct kernel_stat and struct statx
+const STAT_SIZE: usize = 128;
+const STATX_SIZE: usize = 256;
+const STATX_BASIC_STATS: u32 = 0x7ff;
+
+// at most this much is copied per read or write
+const IO_CHUNK: u32 = 1 << 20;
+// most iovecs readv and writev take
+const IOV_MAX: u32 = 1024;
+
+const UTSNAME_FIELD: usize = 65;
+
+enum Handle {
+    Input(Box<dyn Read>),
+    Output(Box<dyn Write>),
+    File(File)
+}
+
+// what fstat and statx report
+struct Stat {
+    mode: u32,
+    size: u64,
+    mtime: (i64, u32)
+}
+
+pub struct Syscalls {
+    files: Vec<Option<Handle>>,
+    rng: Rng,
+    // program break, where the heap starts and where it ends now
+    brk_start: u32,
+    brk: u32,
+    // mappings are placed below here, (addr, len) sorted by address
+    mmap_top: u32,
+    mappings: Vec<(u32, u32)>,
+    // the clock when the program started, CLOCK_MONOTONIC counts from it
+    start_ns: u64
+}
+
+impl Syscalls {
+    pub fn new(core: &mut Core, rng: Rng) -> Syscalls {
+        return Syscalls::with_streams(core, rng, Box::new(io::stdin()), Box::new(io::stdout()), Box::new(io::stderr()));
+    }
+
+    /*
+     * Set up for the program loaded in core, whose stack is already in
+     * place. The streams become file descriptors 0, 1 and 2.
+     */
+    pub fn with_streams(core: &mut Core, rng: Rng, stdin: Box<dyn Read>, stdout: Box<dyn Write>, stderr: Box<dyn Write>) -> Syscalls {
+        let page = PAGE_SIZE as u32;
+        let end = core.segments.iter().map(|s| s.base.wrapping_add(s.size)).max()
+            .unwrap_or(core.bus.ram_base());
+        let brk = end.wrapping_add(page - 1) & !(page - 1);
+        let top = core.bus.ram_base().wrapping_add(core.bus.ram_size() as u32);
+        let stack = (core.bus.ram_size() as u32 / 8).min(STACK_SIZE);
+        let sp = core.regs[2] as u32;
+        let mmap_top = top.wrapping_sub(stack).min(sp) & !(page - 1);
+        return Syscalls {
+            files: vec![Some(Handle::Input(stdin)), Some(Handle::Output(stdout)), Some(Handle::Output(stderr))],
+            rng,
+            brk_start: brk,
+            brk,
+            mmap_top: mmap_top.max(brk),
+            mappings: Vec::new(),
+            start_ns: now_ns(core)
+        };
+    }
+
+    /*
+     * Carry out the system call in a7 and put the result in a0.
+     */
+    pub fn handle(&mut self, core: &mut Core) {
+        let arg = |i: usize| core.regs[10 + i] as u32;
+        let args = [arg(0), arg(1), arg(2), arg(3), arg(4), arg(5)];
+        let number = core.regs[17] as u32;
+        let result = match number {
+            SYS_READ => self.read(core, args[0], args[1], args[2]),
+            SYS_WRITE => self.write(core, args[0], args[1], args[2]),
+            SYS_READV => self.vectored(core, args, Syscalls::read),
+            SYS_WRITEV => self.vectored(core, args, Syscalls::write),
+            SYS_OPENAT => self.openat(core, args[0] as i32, args[1], args[2]),
+            SYS_CLOSE => self.close(args[0]),
+            SYS_LSEEK => self.llseek(core, args),
+            SYS_FSTAT => self.fstat(core, args[0], args[1]),
+            SYS_STATX => self.statx(core, args),
+            SYS_IOCTL => self.handle_of(args[0]).and(Err(ENOTTY)),
+            SYS_BRK => Ok(self.set_brk(core, args[0])),
+            SYS_MMAP => self.mmap(core, args),
+            SYS_MUNMAP => self.munmap(args[0], args[1]),
+            SYS_EXIT | SYS_EXIT_GROUP => {
+                core.exit_code = Some(args[0] as i32 & 0xff);
+                Ok(0)
+            },
+            SYS_SET_TID_ADDRESS => Ok(1),
+            SYS_CLOCK_GETTIME => self.clock_gettime(core, args[0], args[1], false),
+            SYS_CLOCK_GETTIME64 => self.clock_gettime(core, args[0], args[1], true),
+            SYS_GETRANDOM => self.getrandom(core, args[0], args[1]),
+            SYS_UNAME => uname(core, args[0]),
+            _ => {
+                let pc = core.regs[32] as u32;
+                eprintln!("Unimplemented syscall {} at {:#010x} ({})", number, pc, crate::location(core, pc));
+                Err(ENOSYS)
+            }
+        };
+        core.regs[10] = match result {
+            Ok(value) => value as i32,
+            Err(errno) => -errno
+        };
+    }
+
+    fn handle_of(&mut self, fd: u32) -> Result<&mut Handle, i32> {
+        return self.files.get_mut(fd as usize).and_then(|f| f.as_mut()).ok_or(EBADF);
+    }
+
+    fn read(&mut self, core: &mut Core, fd: u32, buf: u32, count: u32) -> Result<u32, i32> {
+        let count = count.min(IO_CHUNK);
+        if !core.bus.memory.contains(buf, count as usize) {
+            return Err(EFAULT);
+        }
+        let mut data = vec![0; count as usize];
+        let n = match self.handle_of(fd)? {
+            Handle::Input(input) => input.read(&mut data),
+            Handle::File(file) => file.read(&mut data),
+            Handle::Output(_) => return Err(EBADF)
+        }.map_err(|e| errno(&e))?;
+        core.bus.memory.write_slice(buf, &data[..n]).map_err(|_| EFAULT)?;
+        return Ok(n as u32);
+    }
+
+    fn write(&mut self, core: &mut Core, fd: u32, buf: u32, count: u32) -> Result<u32, i32> {
+        let data = read_bytes(core, buf, count.min(IO_CHUNK))?;
+        match self.handle_of(fd)? {
+            Handle::Output(output) => output.write_all(&data).and_then(|_| output.flush()),
+            Handle::File(file) => file.write_all(&data),
+            Handle::Input(_) => return Err(EBADF)
+        }.map_err(|e| errno(&e))?;
+        return Ok(data.len() as u32);
+    }
+
+    // readv and writev, one iovec after the other until one comes up short
+    fn vectored(&mut self, core: &mut Core, args: [u32; 6], op: fn(&mut Syscalls, &mut Core, u32, u32, u32) -> Result<u32, i32>) -> Result<u32, i32> {
+        let (fd, iov, count) = (args[0], args[1], args[2]);
+        if count > IOV_MAX {
+            return Err(EINVAL);
+        }
+        let mut total: u32 = 0;
+        for i in 0..count {
+            let entry = read_bytes(core, iov.wrapping_add(8*i), 8)?;
+            let base = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+            let len = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+            let n = match op(self, core, fd, base, len) {
+                Ok(n) => n,
+                Err(e) if total == 0 => return Err(e),
+                Err(_) => break
+            };
+            total = total.wrapping_add(n);
+            if n < len {
+                break;
+            }
+        }
+        return Ok(total);
+    }
+
+    fn openat(&mut self, core: &mut Core, dirfd: i32, path: u32, flags: u32) -> Result<u32, i32> {
+        let path = read_cstr(core, path)?;
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            return Err(EBADF);
+        }
+        let mut options = OpenOptions::new();
+        match flags & O_ACCMODE {
+            O_WRONLY => options.write(true),
+            O_RDWR => options.read(true).write(true),
+            _ => options.read(true)
+        };
+        if flags & O_APPEND != 0 {
+            options.append(true);
+        }
+        if flags & O_TRUNC != 0 {
+            options.truncate(true);
+        }
+        if flags & O_CREAT != 0 && flags & O_EXCL != 0 {
+            options.create_new(true);
+        }
+        else if flags & O_CREAT != 0 {
+            options.create(true);
+        }
+        let file = options.open(&path).map_err(|e| errno(&e))?;
+        let handle = Some(Handle::File(file));
+        return match self.files.iter().position(|f| f.is_none()) {
+            Some(fd) => {
+                self.files[fd] = handle;
+                Ok(fd as u32)
+            },
+            None => {
+                self.files.push(handle);
+                Ok(self.files.len() as u32 - 1)
+            }
+        };
+    }
+
+    fn close(&mut self, fd: u32) -> Result<u32, i32> {
+        self.handle_of(fd)?;
+        self.files[fd as usize] = None;
+        return Ok(0);
+    }
+
+    // _llseek(fd, offset_high, offset_low, result, whence)
+    fn llseek(&mut self, core: &mut Core, args: [u32; 6]) -> Result<u32, i32> {
+        let offset = ((args[1] as u64) << 32 | args[2] as u64) as i64;
+        let pos = match args[4] {
+            0 if offset >= 0 => SeekFrom::Start(offset as u64),
+            1 => SeekFrom::Current(offset),
+            2 => SeekFrom::End(offset),
+            _ => return Err(EINVAL)
+        };
+        let file = match self.handle_of(args[0])? {
+            Handle::File(file) => file,
+            _ => return Err(ESPIPE)
+        };
+        let result = file.seek(pos).map_err(|e| errno(&e))?;
+        core.bus.memory.write_slice(args[3], &result.to_le_bytes()).map_err(|_| EFAULT)?;
+        return Ok(0);
+    }
+
+    fn stat(&mut self, fd: u32) -> Result<Stat, i32> {
+        let file = match self.handle_of(fd)? {
+            Handle::File(file) => file,
+            _ => return Ok(Stat { mode: S_IFCHR | 0o620, size: 0, mtime: (0, 0) })
+        };
+        return Ok(file_stat(&file.metadata().map_err(|e| errno(&e))?));
+    }
+
+    fn fstat(&mut self, core: &mut Core, fd: u32, buf: u32) -> Result<u32, i32> {
+        let stat = self.stat(fd)?;
+        let mut out = vec![0; STAT_SIZE];
+        put(&mut out, 16, &stat.mode.to_le_bytes());
+        put(&mut out, 20, &1u32.to_le_bytes()); // st_nlink
+        put(&mut out, 48, &stat.size.to_le_bytes());
+        put(&mut out, 56, &(PAGE_SIZE as u32).to_le_bytes());
+        put(&mut out, 64, &stat.size.div_ceil(512).to_le_bytes());
+        // st_atim, st_mtim and st_ctim
+        for at in [72, 88, 104].iter() {
+            put(&mut out, *at, &stat.mtime.0.to_le_bytes());
+            put(&mut out, at + 8, &stat.mtime.1.to_le_bytes());
+        }
+        core.bus.memory.write_slice(buf, &out).map_err(|_| EFAULT)?;
+        return Ok(0);
+    }
+
+    // statx(dirfd, path, flags, mask, buf), what musl's fstat and stat use
+    fn statx(&mut self, core: &mut Core, args: [u32; 6]) -> Result<u32, i32> {
+        let path = read_cstr(core, args[1])?;
+        let stat = if path.is_empty() && args[2] & AT_EMPTY_PATH != 0 {
+            self.stat(args[0])?
+        }
+        else if args[0] as i32 == AT_FDCWD || path.starts_with('/') {
+            file_stat(&fs::metadata(&path).map_err(|e| errno(&e))?)
+        }
+        else {
+            return Err(EBADF);
+        };
+        let mut out = vec![0; STATX_SIZE];
+        put(&mut out, 0, &STATX_BASIC_STATS.to_le_bytes());
+        put(&mut out, 4, &(PAGE_SIZE as u32).to_le_bytes());
+        put(&mut out, 16, &1u32.to_le_bytes()); // stx_nlink
+        put(&mut out, 28, &(stat.mode as u16).to_le_bytes());
+        put(&mut out, 40, &stat.size.to_le_bytes());
+        put(&mut out, 48, &stat.size.div_ceil(512).to_le_bytes());
+        // stx_atime, stx_btime, stx_ctime and stx_mtime
+        for at in [64, 80, 96, 112].iter() {
+            put(&mut out, *at, &stat.mtime.0.to_le_bytes());
+            put(&mut out, at + 8, &stat.mtime.1.to_le_bytes());
+        }
+        core.bus.memory.write_slice(args[4], &out).map_err(|_| EFAULT)?;
+        return Ok(0);
+    }
+
+    /*
+     * Move the program break, up to the lowest mapping. Returns the new
+     * break, or the old one when it can't be moved.
+     */
+    fn set_brk(&mut self, core: &mut Core, addr: u32) -> u32 {
+        let limit = self.mappings.first().map(|(base, _)| *base).unwrap_or(self.mmap_top).min(self.mmap_top);
+        if addr < self.brk_start || addr > limit {
+            return self.brk;
+        }
+        if addr > self.brk && core.bus.memory.zero(self.brk, (addr - self.brk) as u64).is_err() {
+            return self.brk;
+        }
+        self.brk = addr;
+        return addr;
+    }
+
+    // mmap2(addr, len, prot, flags, fd, pgoff)
+    fn mmap(&mut self, core: &mut Core, args: [u32; 6]) -> Result<u32, i32> {
+        let page = PAGE_SIZE as u32;
+        let (hint, flags, fd, pgoff) = (args[0], args[3], args[4], args[5]);
+        if args[1] == 0 || args[1] > u32::MAX - page {
+            return Err(EINVAL);
+        }
+        let len = (args[1] + page - 1) & !(page - 1);
+        let fixed = flags & MAP_FIXED != 0;
+        if fixed && (hint & (page - 1) != 0 || !core.bus.memory.contains(hint, len as usize)) {
+            return Err(EINVAL);
+        }
+
+        // read the file first, so a failing call leaves the old mappings alone
+        let mut data = Vec::new();
+        if flags & MAP_ANONYMOUS == 0 {
+            let file = match self.handle_of(fd)? {
+                Handle::File(file) => file,
+                _ => return Err(EACCES)
+            };
+            let offset = pgoff as u64 * page as u64;
+            let size = file.metadata().map_err(|e| errno(&e))?.len();
+            data.resize(size.saturating_sub(offset).min(len as u64) as usize, 0);
+            read_at(file, offset, &mut data).map_err(|e| errno(&e))?;
+        }
+        let addr = if fixed { hint } else { self.find_gap(len).ok_or(ENOMEM)? };
+        core.bus.memory.zero(addr, len as u64).map_err(|_| EACCES)?;
+        if fixed {
+            self.unmap(addr, len);
+        }
+        core.bus.memory.load(addr, &data);
+        let i = self.mappings.partition_point(|(base, _)| *base < addr);
+        self.mappings.insert(i, (addr, len));
+        return Ok(addr);
+    }
+
+    fn munmap(&mut self, addr: u32, len: u32) -> Result<u32, i32> {
+        let page = PAGE_SIZE as u32;
+        if addr & (page - 1) != 0 || len == 0 {
+            return Err(EINVAL);
+        }
+        self.unmap(addr, len.saturating_add(page - 1) & !(page - 1));
+        return Ok(0);
+    }
+
+    // forget the mappings in [addr, addr + len), splitting the ones it cuts
+    fn unmap(&mut self, addr: u32, len: u32) {
+        let end = addr as u64 + len as u64;
+        let mut kept = Vec::new();
+        for (base, size) in self.mappings.iter() {
+            let map_end = *base as u64 + *size as u64;
+            if map_end <= addr as u64 || *base as u64 >= end {
+                kept.push((*base, *size));
+                continue;
+            }
+            if *base < addr {
+                kept.push((*base, addr - base));
+            }
+            if map_end > end {
+                kept.push((end as u32, (map_end - end) as u32));
+            }
+        }
+        self.mappings = kept;
+    }
+
+    // the highest free range of len bytes below mmap_top and above the break
+    fn find_gap(&self, len: u32) -> Option<u32> {
+        let mut top = self.mmap_top;
+        for (base, size) in self.mappings.iter().rev() {
+            let end = base + size;
+            if end <= top && top - end >= len {
+                break;
+            }
+            top = top.min(*base);
+        }
+        let addr = top.checked_sub(len)?;
+        if addr < self.brk {
+            return None;
+        }
+        return Some(addr);
+    }
+
+    // clock_gettime with a 32 or 64-bit struct timespec
+    fn clock_gettime(&mut self, core: &mut Core, clock: u32, buf: u32, time64: bool) -> Result<u32, i32> {
+        let now = now_ns(core);
+        let ns = match clock {
+            CLOCK_REALTIME | CLOCK_REALTIME_COARSE => now,
+            c if c <= CLOCK_BOOTTIME => now.wrapping_sub(self.start_ns),
+            _ => return Err(EINVAL)
+        };
+        let (sec, nsec) = (ns / 1_000_000_000, ns % 1_000_000_000);
+        let data = if time64 {
+            [sec.to_le_bytes(), nsec.to_le_bytes()].concat()
+        }
+        else {
+            [(sec as u32).to_le_bytes(), (nsec as u32).to_le_bytes()].concat()
+        };
+        core.bus.memory.write_slice(buf, &data).map_err(|_| EFAULT)?;
+        return Ok(0);
+    }
+
+    fn getrandom(&mut self, core: &mut Core, buf: u32, len: u32) -> Result<u32, i32> {
+        let mut data = vec![0; len.min(IO_CHUNK) as usize];
+        self.rng.fill(&mut data);
+        core.bus.memory.write_slice(buf, &data).map_err(|_| EFAULT)?;
+        return Ok(data.len() as u32);
+    }
+}
+
+fn uname(core: &mut Core, buf: u32) -> Result<u32, i32> {
+    let mut out = vec![0; 6 * UTSNAME_FIELD];
+    let fields = ["Linux", "rustv", "6.1.0", "#1", "riscv32", "(none)"];
+    for (i, field) in fields.iter().enumerate() {
+        put(&mut out, i * UTSNAME_FIELD, field.as_bytes());
+    }
+    core.bus.memory.write_slice(buf, &out).map_err(|_| EFAULT)?;
+    return Ok(0);
+}
+
+// nanoseconds since the epoch by the RTC, the host clock without one
+pub fn now_ns(core: &mut Core) -> u64 {
+    let low = core.bus.read(RTC_BASE + TIME_LOW, 4);
+    let high = core.bus.read(RTC_BASE + TIME_HIGH, 4);
+    if let (Ok(low), Ok(high)) = (low, high) {
+        return (high as u64) << 32 | low as u64;
+    }
+    return SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+}
+
+fn file_stat(meta: &fs::Metadata) -> Stat {
+    let mode = if meta.is_dir() { S_IFDIR | 0o755 } else { S_IFREG | 0o644 };
+    let mtime = meta.modified().ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| (d.as_secs() as i64, d.subsec_nanos()))
+        .unwrap_or((0, 0));
+    return Stat { mode, size: meta.len(), mtime };
+}
+
+// fill buf from offset, leaving the file position where it was
+fn read_at(file: &mut File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let pos = file.stream_position()?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = file.read(&mut buf[filled..])?;
+        if n == 0 {
+            break;
+        }
+        filled += n;
+    }
+    file.seek(SeekFrom::Start(pos))?;
+    return Ok(());
+}
+
+fn read_bytes(core: &Core, addr: u32, len: u32) -> Result<Vec<u8>, i32> {
+    let mut data = vec![0; len as usize];
+    core.bus.memory.read_slice(addr, &mut data).map_err(|_| EFAULT)?;
+    return Ok(data);
+}
+
+// NUL terminated path, at most a page long
+fn read_cstr(core: &Core, addr: u32) -> Result<String, i32> {
+    let mut bytes = Vec::new();
+    loop {
+        let b = core.bus.memory.read(addr.wrapping_add(bytes.len() as u32), 1).map_err(|_| EFAULT)?;
+        if b == 0 {
+            break;
+        }
+        if bytes.len() == PAGE_SIZE {
+            return Err(ENAMETOOLONG);
+        }
+        bytes.push(b as u8);
+    }
+    return Ok(String::from_utf8_lossy(&bytes).into_owned());
+}
+
+fn put(buf: &mut [u8], at: usize, data: &[u8]) {
+    buf[at..at + data.len()].copy_from_slice(data);
+}
+
+// errno for a host error
+pub fn errno(e: &io::Error) -> i32 {
+    return match e.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::InvalidInput => EINVAL,
+        _ if e.raw_os_error() == Some(EISDIR) => EISDIR,
+        _ => EIO
+    };
+}
@@ -4,6 +4,8 @@ mod unit_tests {
     mod core_tests {
         use crate::init;
         use crate::eval;
+        use crate::read_mem_8;
+        use crate::store_mem_8;
 
         #[test]
         fn addi_sp_sp_minus_one() {
@@ -438,13 +440,13 @@ mod unit_tests {
             let mut core = init();
             core.regs[1] = 4;
             core.regs[14] = 0;
-            core.memory[4] = 127; // => 0b01111111
+            store_mem_8(&mut core, 4, 127); // => 0b01111111
             eval(0x00008703, &mut core);
             assert_eq!(127, core.regs[14]);
 
             core.regs[1] = 4;
             core.regs[14] = 0;
-            core.memory[4] = 255; // 0b11111111 => -1
+            store_mem_8(&mut core, 4, 255); // 0b11111111 => -1
             eval(0x00008703, &mut core);
             assert_eq!(-1, core.regs[14]);
         }
@@ -454,13 +456,13 @@ mod unit_tests {
             let mut core = init();
             core.regs[1] = 4;
             core.regs[14] = 0;
-            core.memory[4] = 127;
+            store_mem_8(&mut core, 4, 127);
             eval(0x0000c703, &mut core);
             assert_eq!(127, core.regs[14]);
 
             core.regs[1] = 4;
             core.regs[14] = 0;
-            core.memory[4] = 255;
+            store_mem_8(&mut core, 4, 255);
             eval(0x0000c703, &mut core);
             assert_eq!(255, core.regs[14]);
         }
@@ -470,15 +472,15 @@ mod unit_tests {
             let mut core = init();
             core.regs[1] = 2;
             core.regs[14] = 0;
-            core.memory[4] = 0b00001110;
-            core.memory[5] = 0b1; // mem[4-5] = 00000001 00001110 = 270
+            store_mem_8(&mut core, 4, 0b00001110);
+            store_mem_8(&mut core, 5, 0b1); // mem[4-5] = 00000001 00001110 = 270
             eval(0x00209703, &mut core);
             assert_eq!(270, core.regs[14]);
 
             core.regs[1] = 2;
             core.regs[14] = 0;
-            core.memory[4] = 0b11111111;
-            core.memory[5] = 0b11111111; // => mem[4-5] = 0xffff = -1
+            store_mem_8(&mut core, 4, 0b11111111);
+            store_mem_8(&mut core, 5, 0b11111111); // => mem[4-5] = 0xffff = -1
             eval(0x00209703, &mut core);
             assert_eq!(-1, core.regs[14]);
         }
@@ -488,15 +490,15 @@ mod unit_tests {
             let mut core = init();
             core.regs[1] = 2;
             core.regs[14] = 0;
-            core.memory[4] = 0b00001110;
-            core.memory[5] = 0b1;
+            store_mem_8(&mut core, 4, 0b00001110);
+            store_mem_8(&mut core, 5, 0b1);
             eval(0x0020d703, &mut core);
             assert_eq!(270, core.regs[14]);
 
             core.regs[1] = 2;
             core.regs[14] = 0;
-            core.memory[4] = 0b11111111;
-            core.memory[5] = 0b11111111;
+            store_mem_8(&mut core, 4, 0b11111111);
+            store_mem_8(&mut core, 5, 0b11111111);
             eval(0x0020d703, &mut core);
             assert_eq!(0xffff, core.regs[14]);
         }
@@ -506,19 +508,19 @@ mod unit_tests {
             let mut core = init();
             core.regs[1] = 0;
             core.regs[14] = -1;
-            core.memory[8] = 0b1;
-            core.memory[9] = 0b1;
-            core.memory[10] = 0b1;
-            core.memory[11] = 0b1; // => mem[8-11] = 0x1010101
+            store_mem_8(&mut core, 8, 0b1);
+            store_mem_8(&mut core, 9, 0b1);
+            store_mem_8(&mut core, 10, 0b1);
+            store_mem_8(&mut core, 11, 0b1); // => mem[8-11] = 0x1010101
             eval(0x0080a703, &mut core);
             assert_eq!(0x1010101, core.regs[14]);
 
             core.regs[1] = 0;
             core.regs[14] = -1;
-            core.memory[8] = 0xff;
-            core.memory[9] = 0xff;
-            core.memory[10] = 0xff;
-            core.memory[11] = 0xff; // => mem[8-11] = 0xffffffff = -1
+            store_mem_8(&mut core, 8, 0xff);
+            store_mem_8(&mut core, 9, 0xff);
+            store_mem_8(&mut core, 10, 0xff);
+            store_mem_8(&mut core, 11, 0xff); // => mem[8-11] = 0xffffffff = -1
             eval(0x0080a703, &mut core);
             assert_eq!(-1, core.regs[14]);
         }
@@ -529,12 +531,12 @@ mod unit_tests {
             core.regs[1] = 4;
             core.regs[2] = 1;
             eval(0x00208023, &mut core);
-            assert_eq!(1, core.memory[4]);
+            assert_eq!(1, read_mem_8(&mut core, 4));
 
             core.regs[1] = 4;
             core.regs[2] = -1;
             eval(0x00208023, &mut core);
-            assert_eq!(0xff, core.memory[4]);
+            assert_eq!(0xff, read_mem_8(&mut core, 4));
         }
 
         #[test]
@@ -543,14 +545,14 @@ mod unit_tests {
             core.regs[1] = 0;
             core.regs[2] = 1048575; // 2**20 -1
             eval(0x00209223, &mut core);
-            assert_eq!(0xff, core.memory[4]);
-            assert_eq!(0xff, core.memory[5]);
+            assert_eq!(0xff, read_mem_8(&mut core, 4));
+            assert_eq!(0xff, read_mem_8(&mut core, 5));
 
             core.regs[1] = 0;
             core.regs[2] = -2;
             eval(0x00209223, &mut core);
-            assert_eq!(0xfe, core.memory[4]);
-            assert_eq!(0xff, core.memory[5]);
+            assert_eq!(0xfe, read_mem_8(&mut core, 4));
+            assert_eq!(0xff, read_mem_8(&mut core, 5));
         }
 
         #[test]
@@ -559,10 +561,75 @@ mod unit_tests {
             core.regs[1] = 0;
             core.regs[2] = 2490785; // = 00100110 00000001 10100001
             eval(0x0020a423, &mut core);
-            assert_eq!(0b10100001, core.memory[8]);
-            assert_eq!(0b1, core.memory[9]);
-            assert_eq!(0b100110, core.memory[10]);
-            assert_eq!(0b0, core.memory[11]);
+            assert_eq!(0b10100001, read_mem_8(&mut core, 8));
+            assert_eq!(0b1, read_mem_8(&mut core, 9));
+            assert_eq!(0b100110, read_mem_8(&mut core, 10));
+            assert_eq!(0b0, read_mem_8(&mut core, 11));
+        }
+
+        #[test]
+        fn csrrs_csrrc_mscratch() {
+            let mut core = init();
+            core.regs[1] = 0b1010;
+            eval(0x3400a173, &mut core); // csrrs sp, mscratch, ra
+            assert_eq!(0, core.regs[2]);
+            assert_eq!(0b1010, core.csrs[0x340]);
+
+            core.regs[1] = 0b0010;
+            eval(0x3400b173, &mut core); // csrrc sp, mscratch, ra
+            assert_eq!(0b1010, core.regs[2]);
+            assert_eq!(0b1000, core.csrs[0x340]);
+        }
+
+        #[test]
+        fn load_access_fault() {
+            let mut core = init();
+            core.csrs[0x305] = 0x100; // mtvec
+            core.regs[32] = 0x40;
+            core.regs[1] = 0x7fff_0000;
+            eval(0x0000a703, &mut core); // lw a4, 0(ra)
+            assert_eq!(0x100, core.regs[32]);
+            assert_eq!(0x40, core.csrs[0x341]); // mepc
+            assert_eq!(5, core.csrs[0x342]); // mcause
+            assert_eq!(0x7fff_0000, core.csrs[0x343]); // mtval
+
+            // the last bytes of a word past the end of RAM fault as well
+            core.regs[32] = 0x40;
+            core.regs[1] = 0x3ffe;
+            eval(0x0000a703, &mut core);
+            assert_eq!(0x3ffe, core.csrs[0x343]);
+        }
+
+        #[test]
+        fn store_access_fault() {
+            let mut core = init();
+            core.csrs[0x305] = 0x100;
+            core.bus.add_rom(0x1000_0000, vec![0; 4]);
+            core.regs[32] = 0x40;
+            core.regs[1] = 0x1000_0000;
+            core.regs[2] = 0x55;
+            eval(0x0020a023, &mut core); // sw sp, 0(ra)
+            assert_eq!(0x100, core.regs[32]);
+            assert_eq!(7, core.csrs[0x342]);
+            assert_eq!(0x1000_0000, core.csrs[0x343]);
+        }
+
+        #[test]
+        fn ecall_and_mret() {
+            let mut core = init();
+            core.csrs[0x305] = 0x100; // mtvec
+            core.csrs[0x300] = 0b1000; // mstatus.MIE
+            core.regs[32] = 0x40;
+            eval(0x00000073, &mut core); // ecall
+            assert_eq!(0x100, core.regs[32]);
+            assert_eq!(0x40, core.csrs[0x341]); // mepc
+            assert_eq!(11, core.csrs[0x342]); // mcause
+            assert_eq!(0b1100010000000, core.csrs[0x300]); // MPP=M, MPIE=1, MIE=0
+
+            core.csrs[0x341] = 0x44;
+            eval(0x30200073, &mut core); // mret
+            assert_eq!(0x44, core.regs[32]);
+            assert_eq!(0b1100010001000, core.csrs[0x300]);
         }
     }
 
@@ -570,6 +637,13 @@ mod unit_tests {
     mod encoding_tests {
         use crate::ins::*;
 
+        #[test]
+        fn csr_instructions() {
+            assert_eq!(0x3400a173, csrrs(2, 0x340, 1));
+            assert_eq!(0x3400b173, csrrc(2, 0x340, 1));
+            assert_eq!(0xf1402573, csrr(10, 0xf14));
+        }
+
         #[test]
         fn lui_sp_minus_1() {
             /*
@@ -643,4 +717,2126 @@ mod unit_tests {
         }
 
     }
+
+    #[cfg(test)]
+    mod bus_tests {
+        use crate::init;
+        use crate::eval;
+        use crate::bus::*;
+        use crate::ins::*;
+
+        struct Latch {
+            value: u32
+        }
+
+        impl Device for Latch {
+            fn read(&mut self, _offset: u32, _size: u32) -> u32 {
+                return self.value;
+            }
+            fn write(&mut self, _offset: u32, _size: u32, value: u32) {
+                self.value = value;
+            }
+            fn interrupt(&self) -> bool {
+                return self.value != 0;
+            }
+        }
+
+        #[test]
+        fn ram_and_rom() {
+            let mut bus = Bus::new(0x1000, 0x100);
+            bus.add_rom(0x0, vec![0x13, 0x00, 0x00, 0x00]);
+            assert_eq!(Ok(0x13), bus.read(0x0, 4));
+            assert_eq!(Err(BusError::ReadOnly(0x0)), bus.write(0x0, 1, 0));
+            assert_eq!(Ok(()), bus.write(0x10fc, 4, 0xdeadbeef));
+            assert_eq!(Ok(0xbeef), bus.read(0x10fc, 2));
+            assert_eq!(Err(BusError::Unmapped(0x10fe)), bus.read(0x10fe, 4));
+            assert_eq!(Err(BusError::Unmapped(0x2000)), bus.write(0x2000, 1, 0));
+        }
+
+        #[test]
+        fn device_irq_line() {
+            let mut bus = Bus::new(0, 0x100);
+            bus.add_device(0x1000_0000, 0x8, 3, Box::new(Latch { value: 0 }));
+            bus.tick();
+            assert_eq!(0, bus.irq_lines());
+            bus.write(0x1000_0004, 4, 1).unwrap();
+            bus.tick();
+            assert_eq!(1 << 3, bus.irq_lines());
+        }
+
+        #[test]
+        fn store_and_load_through_device() {
+            let mut core = init();
+            core.bus.add_device(0x1000_0000, 0x8, 0, Box::new(Latch { value: 0 }));
+            core.regs[1] = 0x1000_0000;
+            core.regs[2] = 0x41;
+            eval(sb(2,0,1), &mut core);
+            eval(lw(14,4,1), &mut core);
+            assert_eq!(0x41, core.regs[14]);
+        }
+    }
+
+    #[cfg(test)]
+    mod config_tests {
+        use crate::config::parse_args;
+
+        fn parse(args: &[&str]) -> Result<crate::config::Config, String> {
+            return parse_args(&args.iter().map(|s| s.to_string()).collect::<Vec<String>>());
+        }
+
+        #[test]
+        fn sizes_that_overflow() {
+            assert_eq!(1 << 30, parse(&["--memory", "1G", "prog"]).ok().unwrap().memory);
+            // (1 << 44) + 1 megabytes used to wrap around to 1M
+            assert!(parse(&["--memory", "0x100000000001M", "prog"]).is_err());
+            assert!(parse(&["--memory", "0xffffffffffffffffK", "prog"]).is_err());
+            assert!(parse(&["--fb", "4x4", "--fb-every", "0x100000001", "prog"]).is_err());
+        }
+    }
+
+    #[cfg(test)]
+    mod memory_tests {
+        use crate::bus::BusError;
+        use crate::memory::*;
+
+        #[test]
+        fn sparse_gigabytes() {
+            let mut mem = Memory::new();
+            mem.map(0x8000_0000, 1 << 31, R | W | X);
+            assert_eq!(0, mem.allocated_pages());
+            assert_eq!(Ok(0), mem.read(0x9000_0000, 4));
+            mem.write(0xffff_fffc, 4, 0xdead_beef).unwrap();
+            assert_eq!(Ok(0xdead_beef), mem.read(0xffff_fffc, 4));
+            assert_eq!(Ok(0xbeef), mem.read(0xffff_fffc, 2));
+            assert_eq!(1, mem.allocated_pages());
+            assert_eq!(Err(BusError::Unmapped(0xffff_fffe)), mem.read(0xffff_fffe, 4));
+        }
+
+        #[test]
+        fn zero_without_allocating() {
+            let mut mem = Memory::new();
+            mem.map(0x8000_0000, 1 << 31, R | W);
+            mem.load(0x8000_0ff0, &[0xff; 0x20]);
+            mem.zero(0x8000_0ff8, 1 << 30).unwrap();
+            assert_eq!(1, mem.allocated_pages());
+            assert_eq!(Ok(0xffff_ffff), mem.read(0x8000_0ff4, 4));
+            assert_eq!(Ok(0), mem.read(0x8000_0ff8, 4));
+            assert_eq!(Ok(0), mem.read(0x8000_1000, 4));
+        }
+
+        #[test]
+        fn access_across_pages() {
+            let mut mem = Memory::new();
+            mem.map(0x8000_0000, 0x2000, R | W);
+            mem.write(0x8000_0ffe, 4, 0x1122_3344).unwrap();
+            assert_eq!(Ok(0x1122_3344), mem.read(0x8000_0ffe, 4));
+            assert_eq!(Ok(0x1122), mem.read(0x8000_1000, 2));
+            assert_eq!(2, mem.allocated_pages());
+            assert_eq!(Err(BusError::Unmapped(0x8000_1ffe)), mem.write(0x8000_1ffe, 4, 0));
+        }
+
+        #[test]
+        fn permissions() {
+            let mut mem = Memory::new();
+            mem.add_rom(0x1000, &[0x13, 0, 0, 0]);
+            assert_eq!(Ok(0x13), mem.fetch(0x1000));
+            assert_eq!(Ok(0x13), mem.read(0x1000, 4));
+            assert_eq!(Err(BusError::ReadOnly(0x1000)), mem.write(0x1000, 1, 0));
+
+            mem.map(0x8000_0000, 0x4000, R | W);
+            assert_eq!(Err(BusError::NotExecutable(0x8000_0000)), mem.fetch(0x8000_0000));
+
+            // execute-only text in the middle of RAM
+            mem.protect(0x8000_1000, 0x1000, X);
+            assert!(mem.fetch(0x8000_1000).is_ok());
+            assert_eq!(Err(BusError::NotReadable(0x8000_1ffe)), mem.read(0x8000_1ffe, 2));
+            assert_eq!(Err(BusError::ReadOnly(0x8000_0ffc)), mem.write_slice(0x8000_0ffc, &[0; 8]));
+            assert!(mem.write(0x8000_2000, 4, 1).is_ok());
+
+            // loaders ignore permissions
+            mem.load(0x8000_1000, &[0x6f]);
+            assert_eq!(Ok(0x6f), mem.fetch(0x8000_1000));
+        }
+
+        #[test]
+        fn protect_replaces_regions() {
+            let mut mem = Memory::new();
+            mem.map(0x8000_0000, 0x4000, R | W);
+            mem.protect(0x8000_1000, 0x2000, R);
+            mem.protect(0x8000_2000, 0x2000, R | X);
+            assert!(mem.write(0x8000_0ffc, 4, 1).is_ok());
+            assert_eq!(Err(BusError::ReadOnly(0x8000_1ffe)), mem.write(0x8000_1ffe, 4, 0));
+            assert!(mem.fetch(0x8000_3ffc).is_ok());
+            assert_eq!(Err(BusError::NotExecutable(0x8000_1000)), mem.fetch(0x8000_1000));
+
+            // putting RAM back leaves nothing of the old regions behind
+            mem.protect(0x8000_0000, 0x4000, R | W);
+            assert!(mem.write_slice(0x8000_0000, &[1; 0x4000]).is_ok());
+            assert_eq!(Err(BusError::Unmapped(0x8000_4000)), mem.read(0x8000_4000, 1));
+        }
+
+        #[test]
+        fn zero_checks_permissions() {
+            let mut mem = Memory::new();
+            mem.map(0x8000_0000, 0x2000, R | W);
+            mem.load(0x8000_1000, &[0xff; 4]);
+            mem.protect(0x8000_1000, 0x1000, R);
+            assert_eq!(Err(BusError::ReadOnly(0x8000_0ff0)), mem.zero(0x8000_0ff0, 0x20));
+            assert_eq!(Ok(0xffff_ffff), mem.read(0x8000_1000, 4));
+            assert_eq!(Err(BusError::Unmapped(0x7fff_fff0)), mem.zero(0x7fff_fff0, 0x20));
+        }
+
+        #[test]
+        fn checkpoint_and_reset() {
+            let mut mem = Memory::new();
+            mem.map(0, 0x10000, R | W);
+            mem.write(0x10, 4, 1).unwrap();
+            mem.checkpoint();
+            mem.write(0x10, 4, 2).unwrap();
+            mem.write(0x8000, 4, 3).unwrap();
+            mem.reset();
+            assert_eq!(Ok(1), mem.read(0x10, 4));
+            assert_eq!(Ok(0), mem.read(0x8000, 4));
+
+            // and the permissions the program was loaded with
+            mem.protect(0, 0x1000, R);
+            mem.checkpoint();
+            mem.protect(0, 0x1000, R | W);
+            mem.map(0x20000, 0x1000, R | W);
+            mem.reset();
+            assert_eq!(Err(BusError::ReadOnly(0x10)), mem.write(0x10, 4, 0));
+            assert_eq!(Err(BusError::Unmapped(0x20000)), mem.read(0x20000, 4));
+        }
+    }
+
+    #[cfg(test)]
+    mod device_tests {
+        use std::cell::RefCell;
+        use std::env;
+        use std::fs;
+        use std::io;
+        use std::io::Write;
+        use std::process;
+        use std::rc::Rc;
+
+        use crate::init;
+        use crate::init_with_memory;
+        use crate::step;
+        use crate::read_mem_32;
+        use crate::store_mem_32;
+        use crate::bus::Bus;
+        use crate::bootrom::boot_rom;
+        use crate::bus::Device;
+        use crate::constants::*;
+        use crate::devices::framebuffer::*;
+        use crate::devices::plic::Plic;
+        use crate::devices::rtc::*;
+        use crate::devices::syscon::Syscon;
+        use crate::devices::uart::Uart;
+        use crate::htif::Htif;
+        use crate::ins::*;
+
+        #[derive(Clone)]
+        pub struct Sink(pub Rc<RefCell<Vec<u8>>>);
+
+        impl Sink {
+            pub fn new() -> Sink {
+                return Sink(Rc::new(RefCell::new(Vec::new())));
+            }
+        }
+
+        impl Write for Sink {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                return Ok(buf.len());
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                return Ok(());
+            }
+        }
+
+        #[test]
+        fn uart_transmit_and_receive() {
+            let sink = Sink::new();
+            let mut uart = Uart::with_output(Box::new(sink.clone()));
+            uart.write(0, 1, 'h' as u32);
+            uart.write(0, 1, 'i' as u32);
+            assert_eq!(b"hi".to_vec(), *sink.0.borrow());
+
+            assert_eq!(0x60, uart.read(5, 1)); // LSR: empty, no data
+            uart.receive(b"ok");
+            assert_eq!(0x61, uart.read(5, 1));
+            assert_eq!('o' as u32, uart.read(0, 1));
+            assert_eq!('k' as u32, uart.read(0, 1));
+            assert_eq!(0x60, uart.read(5, 1));
+        }
+
+        #[test]
+        fn uart_receive_interrupt() {
+            let mut uart = Uart::with_output(Box::new(io::sink()));
+            uart.receive(b"x");
+            assert!(!uart.interrupt());
+            uart.write(1, 1, 0x1); // IER: received data available
+            assert!(uart.interrupt());
+            assert_eq!(0x4, uart.read(2, 1)); // IIR
+            uart.read(0, 1);
+            assert!(!uart.interrupt());
+            assert_eq!(0x1, uart.read(2, 1));
+        }
+
+        #[test]
+        fn plic_claim_and_complete() {
+            let mut plic = Plic::new();
+            plic.write(4*UART_IRQ, 4, 1); // priority
+            plic.update_irq_lines(1 << UART_IRQ);
+            assert!(!plic.interrupt());
+            plic.write(0x2000, 4, 1 << UART_IRQ); // enable for context 0
+            assert!(plic.interrupt());
+            assert_eq!(1 << UART_IRQ, plic.read(0x1000, 4));
+
+            assert_eq!(UART_IRQ, plic.read(0x20_0004, 4));
+            assert!(!plic.interrupt());
+            // line still high but source is being serviced
+            plic.update_irq_lines(1 << UART_IRQ);
+            assert!(!plic.interrupt());
+            plic.write(0x20_0004, 4, UART_IRQ);
+            plic.update_irq_lines(1 << UART_IRQ);
+            assert!(plic.interrupt());
+
+            plic.write(0x20_0000, 4, 1); // threshold
+            assert!(!plic.interrupt());
+        }
+
+        #[test]
+        fn uart_interrupt_sets_meip() {
+            let mut core = init();
+            let mut uart = Uart::with_output(Box::new(io::sink()));
+            uart.write(1, 1, 0x1);
+            uart.receive(b"x");
+            core.bus.set_interrupt_controller(PLIC_BASE, PLIC_SIZE, Box::new(Plic::new()));
+            core.bus.add_device(UART_BASE, UART_SIZE, UART_IRQ, Box::new(uart));
+            store_mem_32(&mut core, PLIC_BASE + 4*UART_IRQ, 1);
+            store_mem_32(&mut core, PLIC_BASE + 0x2000, 1 << UART_IRQ);
+            store_mem_32(&mut core, 0, nop());
+            step(&mut core);
+            assert_eq!(csrs::MEIP, core.csrs[csrs::MIP] & csrs::MEIP);
+        }
+
+        #[test]
+        fn htif_exit_status() {
+            let mut core = init();
+            core.htif = Some(Htif::with_output(0x100, Some(0x108), Box::new(io::sink())));
+            store_mem_32(&mut core, 0, addi(1,0,0x100));
+            store_mem_32(&mut core, 4, addi(2,0,(3<<1)|1));
+            store_mem_32(&mut core, 8, sw(2,0,1));
+            store_mem_32(&mut core, 12, sw(0,4,1));
+            assert!(!step(&mut core));
+            assert!(!step(&mut core));
+            // nothing happens until the upper word is stored too
+            assert!(!step(&mut core));
+            assert!(step(&mut core));
+            assert_eq!(Some(3), core.exit_code);
+        }
+
+        #[test]
+        fn htif_syscall_write_and_console() {
+            let sink = Sink::new();
+            let mut bus = Bus::new(0, 0x400);
+            let mut htif = Htif::with_output(0x100, Some(0x108), Box::new(sink.clone()));
+            // the guest stores the lower word of tohost, then the upper one
+            let tohost = |bus: &mut Bus, htif: &mut Htif, hi: u32, lo: u32| {
+                bus.write(0x100, 4, lo).unwrap();
+                htif.store(0x100, 4);
+                bus.write(0x104, 4, hi).unwrap();
+                htif.store(0x104, 4);
+            };
+
+            // magic_mem at 0x200: write(1, 0x300, 2)
+            for (i, val) in [64, 1, 0x300, 2].iter().enumerate() {
+                bus.write(0x200 + 8*i as u32, 4, *val).unwrap();
+            }
+            bus.write(0x300, 2, 0x6968).unwrap();
+            tohost(&mut bus, &mut htif, 0, 0x200);
+            assert_eq!(None, htif.poll(&mut bus));
+            assert_eq!(b"hi".to_vec(), *sink.0.borrow());
+            assert_eq!(Ok(2), bus.read(0x200, 4));
+            assert_eq!(Ok(1), bus.read(0x108, 4));
+            assert_eq!(Ok(0), bus.read(0x100, 4));
+
+            // console putchar, not a syscall while only the lower word is there
+            bus.write(0x100, 4, '!' as u32).unwrap();
+            htif.store(0x100, 4);
+            assert_eq!(None, htif.poll(&mut bus));
+            assert_eq!(b"hi".to_vec(), *sink.0.borrow());
+            bus.write(0x104, 4, 0x0101_0000).unwrap();
+            htif.store(0x104, 4);
+            assert_eq!(None, htif.poll(&mut bus));
+            assert_eq!(b"hi!".to_vec(), *sink.0.borrow());
+
+            // console getchar is answered once input arrives
+            bus.write(0x108, 4, 0).unwrap();
+            bus.write(0x10c, 4, 0).unwrap();
+            tohost(&mut bus, &mut htif, 0x0100_0000, 0x1);
+            htif.poll(&mut bus);
+            assert_eq!(Ok(0), bus.read(0x10c, 4));
+            htif.receive(b"a");
+            htif.poll(&mut bus);
+            assert_eq!(Ok('a' as u32), bus.read(0x108, 4));
+            assert_eq!(Ok(0x0100_0000), bus.read(0x10c, 4));
+        }
+
+        #[test]
+        fn boot_rom_jumps_to_payload() {
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            core.bus.add_rom(BOOT_ROM_BASE, boot_rom(START_ADDR, START_ADDR + 0x3000, 0));
+            core.regs[32] = BOOT_ROM_BASE as i32;
+            store_mem_32(&mut core, START_ADDR, nop());
+            for _ in 0..6 {
+                assert!(!step(&mut core));
+            }
+            assert_eq!(START_ADDR as i32, core.regs[32]);
+            assert_eq!(0, core.regs[10]);
+            assert_eq!((START_ADDR + 0x3000) as i32, core.regs[11]);
+            assert_eq!(0x1028, core.regs[12]);
+            assert_eq!(0x4942_534f, read_mem_32(&mut core, 0x1028));
+        }
+
+        #[test]
+        fn pc_arithmetic_above_2g() {
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            core.regs[32] = START_ADDR as i32;
+            // la a0, 0x10000000 from 0x80000000
+            store_mem_32(&mut core, START_ADDR, auipc(10, 0x90000));
+            store_mem_32(&mut core, START_ADDR + 4, jal(1, -8));
+            assert!(!step(&mut core));
+            assert_eq!(0x1000_0000, core.regs[10]);
+            assert!(!step(&mut core));
+            assert_eq!((START_ADDR + 8) as i32, core.regs[1]);
+            assert_eq!((START_ADDR - 4) as i32, core.regs[32]);
+        }
+
+        #[test]
+        fn instruction_access_fault() {
+            let mut core = init();
+            core.csrs[csrs::MTVEC] = 0x100;
+            store_mem_32(&mut core, 0, jalr(0,0,-4)); // jump to 0xfffffffc
+            store_mem_32(&mut core, 0x100, nop());
+            assert!(!step(&mut core));
+            assert!(!step(&mut core));
+            assert_eq!(0x100, core.regs[32]);
+            assert_eq!(1, core.csrs[csrs::MCAUSE]);
+            assert_eq!(-4, core.csrs[csrs::MTVAL]);
+
+            // no handler to go to, stop instead of faulting forever
+            core.csrs[csrs::MTVEC] = 0x7000_0000;
+            core.regs[32] = 0x7000_0000;
+            assert!(step(&mut core));
+            assert_eq!(Some((1, 0xffff_fffc)), core.fault);
+        }
+
+        #[test]
+        fn counters() {
+            let mut core = init();
+            core.bus.add_device(RTC_BASE, RTC_SIZE, RTC_IRQ, Box::new(Rtc::new(Clock::Virtual)));
+            let program = [
+                nop(),
+                csrr(10, csrs::CYCLE as u32),
+                csrr(11, (csrs::INSTRET | csrs::COUNTER_HIGH) as u32),
+                csrr(12, csrs::TIME as u32),
+                csrrw(0, csrs::MINSTRET as u32, 13),
+                csrr(14, csrs::INSTRET as u32),
+                csrrw(0, csrs::INSTRET as u32, 0)
+            ];
+            for (i, ins) in program.iter().enumerate() {
+                store_mem_32(&mut core, 4*i as u32, *ins);
+            }
+            core.regs[13] = 100;
+            for _ in 0..program.len() {
+                assert!(!step(&mut core));
+            }
+            assert_eq!(1, core.regs[10]);
+            assert_eq!(0, core.regs[11]);
+            // 10 MHz timebase, the virtual RTC only moves 10 ns per instruction
+            let ticks = (core.regs[12] as u32).wrapping_sub((VIRTUAL_EPOCH / 100) as u32);
+            assert!(ticks < 10);
+            assert_eq!(101, core.regs[14]);
+            // the user counters are read-only
+            assert_eq!(103, core.instret);
+        }
+
+        #[test]
+        fn illegal_instruction() {
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            core.regs[32] = START_ADDR as i32;
+            core.csrs[csrs::MTVEC] = (START_ADDR + 0x100) as i32;
+            store_mem_32(&mut core, START_ADDR, 0xffff_ffff);
+            store_mem_32(&mut core, START_ADDR + 0x100, nop());
+            assert!(!step(&mut core));
+            assert_eq!((START_ADDR + 0x100) as i32, core.regs[32]);
+            assert_eq!(causes::ILLEGAL_INSTRUCTION as i32, core.csrs[csrs::MCAUSE]);
+            assert_eq!(-1, core.csrs[csrs::MTVAL]);
+            assert_eq!(START_ADDR as i32, core.csrs[csrs::MEPC]);
+        }
+
+        #[test]
+        fn illegal_encodings() {
+            let words = [
+                0x022081b3, // mul x3, x1, x2
+                0x4020c033, // xor x0, x1, x2 with funct7 0x20
+                0x40209013, // slli x0, x1, 2 with imm[11:5] 0x20
+                0x10200073, // sret
+            ];
+            for word in words.iter() {
+                let mut core = init_with_memory(START_ADDR, MEMSIZE);
+                core.regs[32] = START_ADDR as i32;
+                core.regs[1] = 6;
+                core.regs[2] = 7;
+                core.csrs[csrs::MTVEC] = (START_ADDR + 0x100) as i32;
+                store_mem_32(&mut core, START_ADDR, *word);
+                assert!(!step(&mut core));
+                assert_eq!(causes::ILLEGAL_INSTRUCTION as i32, core.csrs[csrs::MCAUSE]);
+                assert_eq!(*word as i32, core.csrs[csrs::MTVAL]);
+                assert_eq!(0, core.regs[3]);
+            }
+
+            // wfi is a nop
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            core.regs[32] = START_ADDR as i32;
+            store_mem_32(&mut core, START_ADDR, 0x10500073);
+            assert!(!step(&mut core));
+            assert_eq!(START_ADDR as i32 + 4, core.regs[32]);
+        }
+
+        #[test]
+        fn run_into_zeroed_memory() {
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            core.regs[32] = START_ADDR as i32;
+            store_mem_32(&mut core, START_ADDR, nop());
+            assert!(!step(&mut core));
+            // mtvec is 0, where nothing is mapped
+            assert!(!step(&mut core));
+            assert_eq!(0, core.regs[32]);
+            assert!(step(&mut core));
+            assert_eq!(Some((causes::ILLEGAL_INSTRUCTION, START_ADDR + 4)), core.fault);
+
+            // a handler in zeroed memory doesn't loop
+            core.fault = None;
+            core.csrs[csrs::MTVEC] = (START_ADDR + 0x100) as i32;
+            core.regs[32] = START_ADDR as i32 + 4;
+            assert!(!step(&mut core));
+            assert!(step(&mut core));
+            assert_eq!(Some((causes::ILLEGAL_INSTRUCTION, START_ADDR + 4)), core.fault);
+        }
+
+        #[test]
+        fn syscon_poweroff_with_status() {
+            let mut core = init();
+            core.bus.add_device(SYSCON_BASE, SYSCON_SIZE, 0, Box::new(Syscon::new()));
+            store_mem_32(&mut core, 0, lui(1, 0x100));
+            store_mem_32(&mut core, 4, lui(2, 0x33));
+            store_mem_32(&mut core, 8, addi(2,2,0x333));
+            store_mem_32(&mut core, 12, sw(2,0,1));
+            assert!(!step(&mut core));
+            assert!(!step(&mut core));
+            assert!(!step(&mut core));
+            assert!(step(&mut core));
+            assert_eq!(Some(3), core.exit_code);
+        }
+
+        #[test]
+        fn syscon_reboot_restores_ram() {
+            let mut core = init();
+            core.bus.add_device(SYSCON_BASE, SYSCON_SIZE, 0, Box::new(Syscon::new()));
+            store_mem_32(&mut core, 0, lui(1, 0x100));
+            store_mem_32(&mut core, 4, lui(2, 0x7));
+            store_mem_32(&mut core, 8, addi(2,2,0x777));
+            store_mem_32(&mut core, 12, sw(2,0x100,0));
+            store_mem_32(&mut core, 16, sw(2,0,1));
+            core.bus.memory.checkpoint();
+            for _ in 0..5 {
+                assert!(!step(&mut core));
+            }
+            assert_eq!(0, core.regs[32]);
+            assert_eq!(0, core.regs[1]);
+            assert_eq!(0, read_mem_32(&mut core, 0x100));
+        }
+
+        #[test]
+        fn rtc_virtual_clock_and_alarm() {
+            let mut rtc = Rtc::new(Clock::Virtual);
+            for _ in 0..100 {
+                rtc.tick();
+            }
+            let now = VIRTUAL_EPOCH + 100*NS_PER_TICK;
+            assert_eq!(now as u32, rtc.read(0x00, 4));
+            assert_eq!((now >> 32) as u32, rtc.read(0x04, 4));
+
+            // set the time, then arm an alarm 50 ns later
+            rtc.write(0x04, 4, 0);
+            rtc.write(0x00, 4, 1000);
+            assert_eq!(1000, rtc.read(0x00, 4));
+            rtc.write(0x10, 4, 1);
+            rtc.write(0x0c, 4, 0);
+            rtc.write(0x08, 4, 1050);
+            assert_eq!(1, rtc.read(0x18, 4));
+            for _ in 0..4 {
+                rtc.tick();
+            }
+            assert!(!rtc.interrupt());
+            rtc.tick();
+            assert!(rtc.interrupt());
+            assert_eq!(0, rtc.read(0x18, 4));
+            rtc.write(0x1c, 4, 1);
+            assert!(!rtc.interrupt());
+        }
+
+        #[test]
+        fn framebuffer_flush_to_ppm() {
+            let path = env::temp_dir().join(format!("rustv-fb-flush-{}.ppm", process::id()));
+            let path = path.to_str().unwrap();
+            let mut fb = Framebuffer::new(2, 1, PixelFormat::X8R8G8B8, path, 0);
+            let ctrl = fb.control();
+            assert_eq!(0x1000, ctrl);
+            assert_eq!(2, fb.read(ctrl + 0x4, 4));
+            assert_eq!(1, fb.read(ctrl + 0x8, 4));
+            assert_eq!(8, fb.read(ctrl + 0xc, 4));
+            fb.write(0, 4, 0x00ff_8000);
+            fb.write(4, 2, 0x00ff);
+            assert_eq!(0x8000, fb.read(0, 2));
+
+            fb.write(ctrl, 4, 1);
+            let mut expected = b"P6\n2 1\n255\n".to_vec();
+            expected.extend_from_slice(&[0xff, 0x80, 0x00, 0x00, 0x00, 0xff]);
+            assert_eq!(expected, fs::read(path).unwrap());
+            fs::remove_file(path).unwrap();
+        }
+
+        #[test]
+        fn framebuffer_png_every_frame() {
+            let path = env::temp_dir().join(format!("rustv-fb-{}-{{n}}.png", process::id()));
+            let path = path.to_str().unwrap();
+            let mut fb = Framebuffer::new(1, 1, PixelFormat::R5G6B5, path, 1);
+            fb.write(0, 2, 0xf800);
+            for _ in 0..FRAME_TICKS {
+                fb.tick();
+            }
+            let png = fs::read(path.replace("{n}", "0")).unwrap();
+            assert_eq!(b"\x89PNG\r\n\x1a\n", &png[..8]);
+            // IHDR: 1x1, 8 bit RGB, with its crc
+            assert_eq!(&[0, 0, 0, 13], &png[8..12]);
+            assert_eq!(&[0, 0, 0, 1, 0, 0, 0, 1, 8, 2, 0, 0, 0], &png[16..29]);
+            assert_eq!(&[0x90, 0x77, 0x53, 0xde], &png[29..33]);
+            // stored block with filter byte and one red pixel
+            assert_eq!(&[0x78, 0x01, 1, 4, 0, 0xfb, 0xff, 0, 0xff, 0, 0], &png[41..52]);
+            fs::remove_file(path.replace("{n}", "0")).unwrap();
+        }
+    }
+
+    #[cfg(test)]
+    mod virtio_tests {
+        use std::env;
+        use std::fs;
+        use std::process;
+
+        use super::device_tests::Sink;
+        use crate::bus::Bus;
+        use crate::bus::Device;
+        use crate::devices::input::HostInput;
+        use crate::devices::virtio::VirtioMmio;
+        use crate::devices::virtio_blk::*;
+        use crate::devices::virtio_console::VirtioConsole;
+        use crate::devices::virtio_rng::VirtioRng;
+        use crate::rng::Rng;
+
+        const BASE: u32 = 0x1000_1000;
+
+        // descriptor table, available and used ring of queue
+        fn rings(queue: u32) -> (u32, u32, u32) {
+            let base = 0x1000 + 0x3000*queue;
+            return (base, base + 0x1000, base + 0x2000);
+        }
+
+        fn attach(device: Box<dyn Device>, queues: u32) -> Bus {
+            let mut bus = Bus::new(0, 0x10000);
+            bus.add_device(BASE, 0x1000, 1, device);
+            assert_eq!(Ok(0x74726976), bus.read(BASE, 4));
+            for q in 0..queues {
+                let (desc, avail, used) = rings(q);
+                bus.write(BASE + 0x30, 4, q).unwrap(); // QueueSel
+                bus.write(BASE + 0x38, 4, 8).unwrap(); // QueueNum
+                bus.write(BASE + 0x80, 4, desc).unwrap();
+                bus.write(BASE + 0x90, 4, avail).unwrap();
+                bus.write(BASE + 0xa0, 4, used).unwrap();
+                bus.write(BASE + 0x44, 4, 1).unwrap(); // QueueReady
+            }
+            bus.write(BASE + 0x70, 4, 0xf).unwrap(); // DRIVER_OK
+            return bus;
+        }
+
+        /*
+         * Make a chain of (addr, len, device writable) buffers available on
+         * queue and notify the device. Returns the used ring entry's length.
+         */
+        fn submit(bus: &mut Bus, queue: u32, bufs: &[(u32, u32, bool)]) -> u32 {
+            let (desc, avail, used) = rings(queue);
+            for (i, (addr, len, write)) in bufs.iter().enumerate() {
+                let d = desc + 16*i as u32;
+                let next = if i+1 < bufs.len() { 1 } else { 0 };
+                let flags = next | if *write { 2 } else { 0 };
+                bus.write(d, 4, *addr).unwrap();
+                bus.write(d + 4, 4, 0).unwrap();
+                bus.write(d + 8, 4, *len).unwrap();
+                bus.write(d + 12, 2, flags).unwrap();
+                bus.write(d + 14, 2, i as u32 + 1).unwrap();
+            }
+            let idx = bus.read(avail + 2, 2).unwrap();
+            bus.write(avail + 4 + 2*(idx % 8), 2, 0).unwrap();
+            bus.write(avail + 2, 2, idx + 1).unwrap();
+            bus.write(BASE + 0x50, 4, queue).unwrap(); // QueueNotify
+            assert_eq!(Ok(idx + 1), bus.read(used + 2, 2));
+            return bus.read(used + 8 + 8*(idx % 8), 4).unwrap();
+        }
+
+        // a two sector image, name made unique to this test run
+        fn image(name: &str) -> String {
+            let path = env::temp_dir().join(format!("rustv-{}-{}.img", name, process::id()));
+            let mut data = vec![0xaa; 512];
+            data.extend(vec![0xbb; 512]);
+            fs::write(&path, data).unwrap();
+            return path.to_str().unwrap().to_string();
+        }
+
+        fn setup(path: &str, mode: DiskMode) -> Bus {
+            let blk = VirtioBlk::open(path, mode).unwrap();
+            let mut bus = attach(Box::new(VirtioMmio::new(blk)), 1);
+            assert_eq!(Ok(2), bus.read(BASE + 0x8, 4));
+            return bus;
+        }
+
+        /*
+         * Submit header, data and status as one chain and return the status.
+         */
+        fn request(bus: &mut Bus, kind: u32, sector: u32, write: bool) -> u8 {
+            bus.write(0xa000, 4, kind).unwrap();
+            bus.write(0xa008, 4, sector).unwrap();
+            bus.write(0xa00c, 4, 0).unwrap();
+            let len = submit(bus, 0, &[(0xa000, 16, false), (0xb000, 512, !write), (0xc000, 1, true)]);
+            assert_eq!(if write { 1 } else { 513 }, len);
+            return bus.read(0xc000, 1).unwrap() as u8;
+        }
+
+        #[test]
+        fn blk_read_sector() {
+            let path = image("blk-read");
+            let mut bus = setup(&path, DiskMode::CopyOnWrite);
+            assert_eq!(Ok(2), bus.read(BASE + 0x100, 4)); // capacity
+            assert_eq!(0, request(&mut bus, 0, 1, false));
+            assert_eq!(Ok(0xbbbbbbbb), bus.read(0xb000, 4));
+
+            assert_eq!(Ok(1), bus.read(BASE + 0x60, 4)); // InterruptStatus
+            bus.tick();
+            assert_eq!(1 << 1, bus.irq_lines());
+            bus.write(BASE + 0x64, 4, 1).unwrap(); // InterruptACK
+            bus.tick();
+            assert_eq!(0, bus.irq_lines());
+
+            assert_eq!(1, request(&mut bus, 0, 2, false)); // past the end
+            fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn blk_oversized_chain() {
+            let path = image("blk-oversized");
+            let mut bus = setup(&path, DiskMode::CopyOnWrite);
+            bus.write(0xa000, 4, 0).unwrap();
+            bus.write(0xa008, 4, 0).unwrap();
+            bus.write(0xc000, 1, 0xff).unwrap();
+            bus.write(0xd000, 1, 0xff).unwrap();
+            // lengths that overflow a u32 sum, far outside RAM
+            let huge = [(0xa000, 16, false), (0xb000, 0x8000_0000, true), (0xc000, 0x8000_0000, true)];
+            assert_eq!(0, submit(&mut bus, 0, &huge));
+            assert_eq!(Ok(0xff), bus.read(0xc000, 1));
+            // in RAM but longer than the disk, the status says so
+            assert_eq!(1, submit(&mut bus, 0, &[(0xa000, 16, false), (0xb000, 0x2000, true), (0xd000, 1, true)]));
+            assert_eq!(Ok(1), bus.read(0xd000, 1)); // VIRTIO_BLK_S_IOERR
+            // a header too short for a request
+            bus.write(0xd000, 1, 0xff).unwrap();
+            assert_eq!(1, submit(&mut bus, 0, &[(0xa000, 8, false), (0xd000, 1, true)]));
+            assert_eq!(Ok(1), bus.read(0xd000, 1));
+            // the device still serves requests
+            assert_eq!(0, request(&mut bus, 0, 1, false));
+            fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn blk_copy_on_write() {
+            let path = image("blk-cow");
+            let mut bus = setup(&path, DiskMode::CopyOnWrite);
+            bus.write(0xb000, 4, 0x11223344).unwrap();
+            assert_eq!(0, request(&mut bus, 1, 0, true));
+            bus.write(0xb000, 4, 0).unwrap();
+            assert_eq!(0, request(&mut bus, 0, 0, false));
+            assert_eq!(Ok(0x11223344), bus.read(0xb000, 4));
+            assert_eq!(Ok(0), bus.read(0xb004, 4));
+            assert_eq!(0, request(&mut bus, 0, 1, false));
+            assert_eq!(Ok(0xbbbbbbbb), bus.read(0xb000, 4));
+            // the image itself is untouched
+            assert_eq!(0xaa, fs::read(&path).unwrap()[0]);
+            fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn blk_read_only() {
+            let path = image("blk-ro");
+            let mut bus = setup(&path, DiskMode::ReadOnly);
+            assert_eq!(Ok(1 << 5 | 1 << 9), bus.read(BASE + 0x10, 4)); // RO | FLUSH
+            assert_eq!(1, request(&mut bus, 1, 0, true));
+            assert_eq!(0, request(&mut bus, 4, 0, true)); // flush
+            fs::remove_file(&path).unwrap();
+        }
+
+        #[test]
+        fn console_transmit_and_receive() {
+            let sink = Sink::new();
+            let mut input = HostInput::empty();
+            input.push(b"abc");
+            let console = VirtioConsole::with_output(input, Box::new(sink.clone()));
+            let mut bus = attach(Box::new(VirtioMmio::new(console)), 2);
+            assert_eq!(Ok(3), bus.read(BASE + 0x8, 4));
+
+            bus.write(0xa000, 2, 0x6968).unwrap();
+            submit(&mut bus, 1, &[(0xa000, 2, false)]);
+            assert_eq!(b"hi".to_vec(), *sink.0.borrow());
+            // all of RAM in one buffer is still passed on
+            submit(&mut bus, 1, &[(0, 0x10000, false)]);
+            assert_eq!(2 + 0x10000, sink.0.borrow().len());
+
+            assert_eq!(2, submit(&mut bus, 0, &[(0xb000, 2, true)]));
+            assert_eq!(Ok(0x6261), bus.read(0xb000, 2));
+            assert_eq!(1, submit(&mut bus, 0, &[(0xb000, 2, true)]));
+            assert_eq!(Ok('c' as u32), bus.read(0xb000, 1));
+        }
+
+        fn random_bytes(seed: u64) -> u32 {
+            let rng = VirtioRng::new(Rng::new(seed));
+            let mut bus = attach(Box::new(VirtioMmio::new(rng)), 1);
+            assert_eq!(Ok(4), bus.read(BASE + 0x8, 4));
+            assert_eq!(16, submit(&mut bus, 0, &[(0xa000, 16, true)]));
+            return bus.read(0xa00c, 4).unwrap();
+        }
+
+        #[test]
+        fn rng_is_deterministic_with_seed() {
+            assert_eq!(random_bytes(42), random_bytes(42));
+            assert_ne!(random_bytes(42), random_bytes(43));
+        }
+    }
+
+    #[cfg(test)]
+    mod fdt_tests {
+        use crate::init;
+        use crate::bus::Device;
+        use crate::constants::*;
+        use crate::devices::plic::Plic;
+        use crate::devices::syscon::Syscon;
+        use crate::devices::uart::Uart;
+        use crate::fdt::*;
+
+        fn tiny() -> Node {
+            let mut child = Node::at("c", 0x10);
+            child.string("a", "x");
+            let mut root = Node::new("");
+            root.u32("a", 1).child(child);
+            return root;
+        }
+
+        fn word(dtb: &[u8], i: usize) -> u32 {
+            return u32::from_be_bytes([dtb[i], dtb[i+1], dtb[i+2], dtb[i+3]]);
+        }
+
+        #[test]
+        fn dtb_layout() {
+            let dtb = tiny().to_dtb();
+            assert_eq!(122, dtb.len());
+            // magic, totalsize, struct, strings, rsvmap, version, last comp
+            let header = [0xd00d_feed, 122, 56, 120, 40, 17, 16, 0, 2, 64];
+            for (i, val) in header.iter().enumerate() {
+                assert_eq!(*val, word(&dtb, 4*i));
+            }
+            let structure = [
+                1, 0,
+                3, 4, 0, 1,
+                1, u32::from_be_bytes(*b"c@10"), 0,
+                3, 2, 0, 0x7800_0000,
+                2, 2, 9
+            ];
+            for (i, val) in structure.iter().enumerate() {
+                assert_eq!(*val, word(&dtb, 56 + 4*i));
+            }
+            // the property name is only stored once
+            assert_eq!(b"a\0", &dtb[120..]);
+        }
+
+        #[test]
+        fn dts_source() {
+            let expected = "/dts-v1/;\n\n/ {\n\ta = <0x01>;\n\n\tc@10 {\n\t\ta = \"x\";\n\t};\n};\n";
+            assert_eq!(expected, tiny().to_dts());
+        }
+
+        #[test]
+        fn machine_tree() {
+            let mut core = init();
+            core.bus.add_device(SYSCON_BASE, SYSCON_SIZE, 0, Box::new(Syscon::new()));
+            core.bus.set_interrupt_controller(PLIC_BASE, PLIC_SIZE, Box::new(Plic::new()));
+            let uart: Box<dyn Device> = Box::new(Uart::with_output(Box::new(std::io::sink())));
+            core.bus.add_device(UART_BASE, UART_SIZE, UART_IRQ, uart);
+            let tree = device_tree(&core);
+
+            let memory = tree.find("memory@0").unwrap();
+            assert_eq!(Some(&Value::Cells(vec![0, 0, 0, MEMSIZE as u32])), memory.get("reg"));
+            let cpu = tree.find("cpus").unwrap().find("cpu@0").unwrap();
+            assert_eq!(Some(&Value::Strings(vec!["rv32i_zicntr_zicsr_zifencei_zihintpause".to_string()])), cpu.get("riscv,isa"));
+
+            let soc = tree.find("soc").unwrap();
+            let serial = soc.find("serial@10000000").unwrap();
+            assert_eq!(Some(&Value::Cells(vec![UART_IRQ])), serial.get("interrupts"));
+            assert_eq!(Some(&Value::Cells(vec![PLIC_PHANDLE])), serial.get("interrupt-parent"));
+            assert!(soc.find("plic@c000000").unwrap().get("interrupts").is_none());
+            assert!(soc.find("test@100000").unwrap().find("poweroff").is_some());
+            assert_eq!(
+                Some(&Value::Strings(vec!["/soc/serial@10000000".to_string()])),
+                tree.find("chosen").unwrap().get("stdout-path")
+            );
+        }
+
+        #[test]
+        fn chosen_boot_info() {
+            let mut core = init();
+            assert!(device_tree(&core).find("chosen").unwrap().props.is_empty());
+            core.bootargs = Some("console=ttyS0 root=/dev/vda".to_string());
+            core.initrd = Some((0x8400_0000, 0x8412_3456));
+            let tree = device_tree(&core);
+            let chosen = tree.find("chosen").unwrap();
+            assert_eq!(Some(&Value::Strings(vec!["console=ttyS0 root=/dev/vda".to_string()])), chosen.get("bootargs"));
+            assert_eq!(Some(&Value::Cells(vec![0, 0x8400_0000])), chosen.get("linux,initrd-start"));
+            assert_eq!(Some(&Value::Cells(vec![0, 0x8412_3456])), chosen.get("linux,initrd-end"));
+        }
+    }
+
+    mod elf_tests {
+        use crate::init_with_memory;
+        use crate::bus::BusError;
+        use crate::constants::*;
+        use crate::memory;
+        use crate::elf::*;
+        use crate::elfwriter::{header, phdr};
+        use crate::loader::{load_program, Format, LoadError};
+        use crate::symbols::*;
+
+        fn half(buf: &mut Vec<u8>, v: u16) {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+
+        fn word(buf: &mut Vec<u8>, v: u32) {
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+
+        /*
+         * One loadable segment with two instructions at START_ADDR and a
+         * symbol table holding "tohost".
+         */
+        pub fn tiny() -> Vec<u8> {
+            let shstrtab = b"\0.text\0.symtab\0.strtab\0.shstrtab\0";
+            let mut elf = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+            half(&mut elf, 2); // ET_EXEC
+            half(&mut elf, 243);
+            word(&mut elf, 1);
+            word(&mut elf, START_ADDR);
+            word(&mut elf, 52); // phoff
+            word(&mut elf, 168); // shoff
+            word(&mut elf, 0);
+            for v in [52, 32, 1, 40, 5, 4].iter() {
+                half(&mut elf, *v);
+            }
+            // program header
+            for v in [1, 84, START_ADDR, START_ADDR, 8, 8, 5, 4].iter() {
+                word(&mut elf, *v);
+            }
+            // .text
+            word(&mut elf, 0x00a00093); // addi x1, x0, 10
+            word(&mut elf, 0x00108113); // addi x2, x1, 1
+            // .symtab, the null symbol and tohost
+            elf.extend_from_slice(&[0; 16]);
+            for v in [1, START_ADDR + 0x1000, 8, 0x0001_0011].iter() {
+                word(&mut elf, *v);
+            }
+            // .strtab
+            elf.extend_from_slice(b"\0tohost\0");
+            elf.extend_from_slice(shstrtab);
+            elf.resize(168, 0);
+            // section headers
+            elf.extend_from_slice(&[0; 40]);
+            for sh in [
+                [1, 1, 6, START_ADDR, 84, 8, 0, 0, 4, 0],
+                [7, SHT_SYMTAB, 0, 0, 92, 32, 3, 1, 4, 16],
+                [15, SHT_STRTAB, 0, 0, 124, 8, 0, 0, 1, 0],
+                [23, SHT_STRTAB, 0, 0, 132, shstrtab.len() as u32, 0, 0, 1, 0]
+            ].iter() {
+                for v in sh.iter() {
+                    word(&mut elf, *v);
+                }
+            }
+            return elf;
+        }
+
+        #[test]
+        fn parse_headers() {
+            let data = tiny();
+            let elf = ElfFile::parse(&data).unwrap();
+            assert_eq!(START_ADDR, elf.header.e_entry);
+            assert_eq!(1, elf.phdrs.len());
+            assert_eq!(8, elf.phdrs[0].p_filesz);
+            let names: Vec<&str> = elf.shdrs.iter().map(|sh| sh.name.as_str()).collect();
+            assert_eq!(vec!["", ".text", ".symtab", ".strtab", ".shstrtab"], names);
+            let text = elf.section(".text").unwrap();
+            assert_eq!(&data[84..92], elf.section_data(text).unwrap());
+            assert_eq!(Some(START_ADDR + 0x1000), get_symbol(&elf, "tohost"));
+            assert_eq!(None, get_symbol(&elf, "fromhost"));
+        }
+
+        #[test]
+        fn symbol_table() {
+            let data = tiny();
+            let elf = ElfFile::parse(&data).unwrap();
+            let symbols = elf.symbols().unwrap();
+            assert_eq!(1, symbols.len());
+            let tohost = symbols.iter().next().unwrap();
+            assert_eq!(("tohost", 8, STT_OBJECT, STB_GLOBAL), (tohost.name.as_str(), tohost.size, tohost.kind(), tohost.bind()));
+            assert_eq!(Some("tohost+0x4".to_string()), symbols.describe(START_ADDR + 0x1004));
+            assert_eq!(None, symbols.describe(START_ADDR + 0x1008));
+        }
+
+        fn symbol(name: &str, value: u32, size: u32, kind: u8) -> Symbol {
+            return Symbol { name: name.to_string(), value, size, info: STB_GLOBAL << 4 | kind, other: 0, shndx: 1 };
+        }
+
+        #[test]
+        fn address_lookup() {
+            let symbols = SymbolTable::new(vec![
+                symbol("_start", 0x100, 0, STT_NOTYPE),
+                symbol("main", 0x110, 0x20, STT_FUNC),
+                symbol("inner", 0x118, 0x4, STT_NOTYPE),
+                symbol("buf", 0x200, 0x100, STT_OBJECT),
+                symbol("main.c", 0, 0, STT_FILE),
+                // the same function again from a second symbol table
+                symbol("main", 0x110, 0x20, STT_FUNC),
+                Symbol { shndx: SHN_UNDEF, ..symbol("puts", 0, 0, STT_FUNC) }
+            ]);
+            assert_eq!(Some("_start".to_string()), symbols.describe(0x100));
+            assert_eq!(Some("_start+0xc".to_string()), symbols.describe(0x10c));
+            assert_eq!(Some("main+0x8".to_string()), symbols.describe(0x118));
+            assert_eq!(Some("main+0x1c".to_string()), symbols.describe(0x12c));
+            // past main, only the label is left
+            assert_eq!(Some("_start+0x30".to_string()), symbols.describe(0x130));
+            assert_eq!(Some("buf+0xff".to_string()), symbols.describe(0x2ff));
+            assert_eq!(None, symbols.describe(0xff));
+            assert_eq!(0x110, symbols.lookup("main").unwrap().value);
+            assert!(symbols.lookup("puts").is_none());
+            assert!(symbols.lookup("main.c").is_some());
+        }
+
+        #[test]
+        fn load_segments() {
+            let data = tiny();
+            let elf = ElfFile::parse(&data).unwrap();
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            load_elf(&mut core, &elf, START_ADDR).unwrap();
+            assert_eq!(Ok(0x00a00093), core.bus.memory.read(START_ADDR, 4));
+
+            assert_eq!(vec![Segment { base: START_ADDR, size: 8, perms: memory::R | memory::X }], core.segments);
+            // the text is read-only from then on, the rest of RAM isn't
+            assert_eq!(Err(BusError::ReadOnly(START_ADDR + 4)), core.bus.memory.write(START_ADDR + 4, 4, 0));
+            assert_eq!(Ok(()), core.bus.memory.write(START_ADDR + 8, 4, 0));
+
+            let mut core = init_with_memory(0, MEMSIZE);
+            assert_eq!(Err(ElfError::Segment(START_ADDR)), load_elf(&mut core, &elf, START_ADDR));
+
+            // ending at the top of the address space, with RAM up to there
+            let mut data = tiny();
+            data[60..64].copy_from_slice(&0xffff_fff8u32.to_le_bytes());
+            let elf = ElfFile::parse(&data).unwrap();
+            let mut core = init_with_memory(START_ADDR, 2 << 30);
+            assert_eq!(Err(ElfError::Segment(0xffff_fff8)), load_elf(&mut core, &elf, START_ADDR));
+        }
+
+        #[test]
+        fn zero_bss() {
+            let mut data = tiny();
+            data[52+0x14] = 16; // p_memsz
+            let elf = ElfFile::parse(&data).unwrap();
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            core.bus.memory.load(START_ADDR, &[0xff; 32]);
+            load_elf(&mut core, &elf, START_ADDR).unwrap();
+            assert_eq!(Ok(0x00108113), core.bus.memory.read(START_ADDR + 4, 4));
+            assert_eq!(Ok(0), core.bus.memory.read(START_ADDR + 8, 4));
+            assert_eq!(Ok(0), core.bus.memory.read(START_ADDR + 12, 4));
+            assert_eq!(Ok(0xffffffff), core.bus.memory.read(START_ADDR + 16, 4));
+            assert_eq!(16, core.segments[0].size);
+        }
+
+        #[test]
+        fn only_load_segments() {
+            let mut data = tiny();
+            data[52] = 4; // PT_NOTE
+            let elf = ElfFile::parse(&data).unwrap();
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            load_elf(&mut core, &elf, START_ADDR).unwrap();
+            assert_eq!(Ok(0), core.bus.memory.read(START_ADDR, 4));
+            assert!(core.segments.is_empty());
+        }
+
+        #[test]
+        fn reject_other_targets() {
+            let mut data = tiny();
+            data[4] = 2;
+            assert_eq!(Some(ElfError::Class(2)), ElfFile::parse(&data).err());
+            let mut data = tiny();
+            data[5] = 2;
+            assert_eq!(Some(ElfError::Endianness(2)), ElfFile::parse(&data).err());
+            let mut data = tiny();
+            data[0x12] = 62;
+            assert_eq!(Some(ElfError::Machine(62)), ElfFile::parse(&data).err());
+            let mut data = tiny();
+            data[0x14] = 2;
+            assert_eq!(Some(ElfError::Version(2)), ElfFile::parse(&data).err());
+            assert_eq!(Some(ElfError::NotElf), ElfFile::parse(b"#!/bin/sh").err());
+        }
+
+        #[test]
+        fn malformed_never_panics() {
+            let data = tiny();
+            for len in 0..data.len() {
+                assert!(ElfFile::parse(&data[..len]).is_err(), "prefix of {} bytes", len);
+            }
+            // offsets and sizes pointing anywhere
+            for i in 0x18..data.len() {
+                let mut bad = data.clone();
+                bad[i] = 0xff;
+                if let Ok(elf) = ElfFile::parse(&bad) {
+                    let mut core = init_with_memory(START_ADDR, MEMSIZE);
+                    let _ = load_elf(&mut core, &elf, START_ADDR);
+                    let _ = elf.symbols();
+                }
+            }
+            let mut bad = data.clone();
+            bad[0x32] = 9; // shstrndx
+            assert!(matches!(ElfFile::parse(&bad), Err(ElfError::Malformed(_))));
+        }
+
+        // tiny() with a .riscv.attributes section holding attrs
+        fn with_attributes(attrs: &[u8]) -> Vec<u8> {
+            let mut data = tiny();
+            let offset = data.len() as u32;
+            data.extend_from_slice(attrs);
+            data.resize((data.len() + 3) & !3, 0);
+            let shoff = data.len() as u32;
+            let shdrs = data[168..].to_vec();
+            data.extend_from_slice(&shdrs[..5*40]);
+            for v in [0, SHT_RISCV_ATTRIBUTES, 0, 0, offset, attrs.len() as u32, 0, 0, 1, 0].iter() {
+                word(&mut data, *v);
+            }
+            data[0x20..0x24].copy_from_slice(&shoff.to_le_bytes());
+            data[0x30] = 6;
+            return data;
+        }
+
+        // as llvm-mc writes them, with Tag_RISCV_stack_align first
+        pub fn attributes(arch: &str) -> Vec<u8> {
+            let mut file = vec![1, 0, 0, 0, 0, 4, 16, 5];
+            file.extend_from_slice(arch.as_bytes());
+            file.push(0);
+            let size = file.len() as u32;
+            file[1..5].copy_from_slice(&size.to_le_bytes());
+            let mut attrs = vec![b'A'];
+            word(&mut attrs, 4 + 6 + size);
+            attrs.extend_from_slice(b"riscv\0");
+            attrs.extend(file);
+            return attrs;
+        }
+
+        #[test]
+        fn arch_attribute() {
+            let data = with_attributes(&attributes("rv32i2p0_m2p0_c2p0"));
+            let elf = ElfFile::parse(&data).unwrap();
+            assert_eq!(Ok(Some("rv32i2p0_m2p0_c2p0".to_string())), elf.arch());
+            let data = tiny();
+            assert_eq!(Ok(None), ElfFile::parse(&data).unwrap().arch());
+
+            let mut attrs = attributes("rv32i");
+            attrs[0] = b'B';
+            let data = with_attributes(&attrs);
+            assert!(matches!(ElfFile::parse(&data).unwrap().arch(), Err(ElfError::Malformed(_))));
+            // just the version is an empty section
+            let attrs = attributes("rv32i");
+            assert_eq!(Ok(None), ElfFile::parse(&with_attributes(&attrs[..1])).unwrap().arch());
+            for len in 2..attrs.len() {
+                let data = with_attributes(&attrs[..len]);
+                assert!(ElfFile::parse(&data).unwrap().arch().is_err(), "prefix of {} bytes", len);
+            }
+        }
+
+        #[test]
+        fn parse_arch_strings() {
+            let names = |arch: &str| arch_extensions(arch).unwrap().join(",");
+            assert_eq!("i,m,a,c", names("rv32imac"));
+            assert_eq!("i,m,zicsr,zifencei", names("rv32i2p1_m2p0_zicsr2p0_zifencei2p0"));
+            assert_eq!("i,m,a,f,d,zicsr,zifencei", names("rv32g_zicsr"));
+            assert_eq!("e,zve32x,zvl128b", names("rv32e1p9_zve32x1p0_zvl128b"));
+            assert_eq!("i,p", names("RV32IP"));
+            assert!(arch_extensions("rv64i").is_err());
+            assert!(arch_extensions("rv32i__m").is_err());
+            assert!(arch_extensions("rv32i+m").is_err());
+
+            assert_eq!(vec!["c"], abi_extensions(EF_RISCV_RVC));
+            assert_eq!(vec!["d", "c"], abi_extensions(EF_RISCV_RVC | EF_RISCV_FLOAT_ABI_DOUBLE));
+            assert_eq!(vec!["e"], abi_extensions(EF_RISCV_RVE));
+            assert!(abi_extensions(0).is_empty());
+        }
+
+        #[test]
+        fn configure_hart() {
+            // soft-float with RVC can't run
+            let mut data = tiny();
+            data[0x24] = EF_RISCV_RVC as u8;
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            let err = load_program(&mut core, &data, Format::Elf, START_ADDR).unwrap_err();
+            assert_eq!(LoadError::Unimplemented(vec!["c".to_string()]), err);
+            assert_eq!("Program needs extensions that aren't implemented: c", err.to_string());
+
+            data[0x24] = EF_RISCV_RVE as u8;
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            assert_eq!(Ok(START_ADDR), load_program(&mut core, &data, Format::Elf, START_ADDR));
+            assert_eq!(csrs::MISA_MXL_32 | csrs::MISA_E, core.csrs[csrs::MISA]);
+
+            // only named in the arch string, a warning
+            let data = with_attributes(&attributes("rv32im"));
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            assert_eq!(Ok(START_ADDR), load_program(&mut core, &data, Format::Elf, START_ADDR));
+            assert_eq!(csrs::MISA_MXL_32 | csrs::MISA_I, core.csrs[csrs::MISA]);
+
+            // the base ISA comes from the arch string too
+            let data = with_attributes(&attributes("rv32e2p0"));
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            assert_eq!(Ok(START_ADDR), load_program(&mut core, &data, Format::Elf, START_ADDR));
+            assert_eq!(csrs::MISA_MXL_32 | csrs::MISA_E, core.csrs[csrs::MISA]);
+        }
+
+        /*
+         * A position-independent program linked at 0, one segment holding
+         * everything: two instructions at 84, three words at 92 filled in
+         * by .rela.dyn, a relative pointer, a pointer to func + 4 and func's
+         * jump slot.
+         */
+        fn pie() -> Vec<u8> {
+            let shstrtab = b"\0.text\0.data\0.dynsym\0.dynstr\0.rela.dyn\0.shstrtab\0";
+            let mut elf = header(ET_DYN, 84, 1, 228, 7, 6);
+            elf.extend(phdr(PT_LOAD, 0, 0, 180 + shstrtab.len() as u32, PF_R | PF_W | PF_X));
+            // .text
+            word(&mut elf, 0x00a00093); // addi x1, x0, 10
+            word(&mut elf, 0x00108113); // addi x2, x1, 1
+            // .data
+            elf.extend_from_slice(&[0; 12]);
+            // .dynsym, the null symbol and func
+            elf.extend_from_slice(&[0; 16]);
+            for v in [1, 84, 8, 0x0001_0012].iter() {
+                word(&mut elf, *v);
+            }
+            // .dynstr
+            elf.extend_from_slice(b"\0func\0\0\0");
+            // .rela.dyn
+            for v in [92, R_RISCV_RELATIVE, 84, 96, 1 << 8 | R_RISCV_32, 4, 100, 1 << 8 | R_RISCV_JUMP_SLOT, 0].iter() {
+                word(&mut elf, *v);
+            }
+            elf.extend_from_slice(shstrtab);
+            elf.resize(228, 0);
+            // section headers
+            elf.extend_from_slice(&[0; 40]);
+            for sh in [
+                [1, SHT_PROGBITS, 6, 84, 84, 8, 0, 0, 4, 0],
+                [7, SHT_PROGBITS, 3, 92, 92, 12, 0, 0, 4, 0],
+                [13, SHT_DYNSYM, 2, 104, 104, 32, 4, 1, 4, 16],
+                [21, SHT_STRTAB, 2, 136, 136, 6, 0, 0, 1, 0],
+                [29, SHT_RELA, 2, 144, 144, 36, 3, 0, 4, 12],
+                [39, SHT_STRTAB, 0, 0, 180, shstrtab.len() as u32, 0, 0, 1, 0]
+            ].iter() {
+                for v in sh.iter() {
+                    word(&mut elf, *v);
+                }
+            }
+            return elf;
+        }
+
+        #[test]
+        fn relocate_pie() {
+            let data = pie();
+            let elf = ElfFile::parse(&data).unwrap();
+            let base = START_ADDR + 0x1000;
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            assert_eq!(Ok(base), load_elf(&mut core, &elf, base));
+            assert_eq!(Ok(0x00a00093), core.bus.memory.read(base + 84, 4));
+            assert_eq!(Ok(base + 84), core.bus.memory.read(base + 92, 4));
+            assert_eq!(Ok(base + 88), core.bus.memory.read(base + 96, 4));
+            assert_eq!(Ok(base + 84), core.bus.memory.read(base + 100, 4));
+            assert_eq!(base, core.segments[0].base);
+
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            assert_eq!(Ok(base + 84), load_program(&mut core, &data, Format::Elf, base));
+            assert_eq!(Some("func+0x4".to_string()), core.symbols.describe(base + 88));
+        }
+
+        /*
+         * pie() with its section headers dropped and the relocations found
+         * through PT_DYNAMIC instead, the jump slot in DT_JMPREL. The
+         * dynamic array and the program headers go at the end of the file.
+         */
+        fn stripped_pie() -> Vec<u8> {
+            let mut elf = pie();
+            let dynamic = elf.len() as u32;
+            for v in [
+                DT_RELA, 144, DT_RELASZ, 24, DT_JMPREL, 168, DT_PLTRELSZ, 12, DT_PLTREL, DT_RELA,
+                DT_SYMTAB, 104, DT_STRTAB, 136, DT_STRSZ, 6, DT_NULL, 0
+            ].iter() {
+                word(&mut elf, *v);
+            }
+            let phoff = elf.len() as u32;
+            let load = elf[52..84].to_vec();
+            elf.extend(load);
+            elf.extend(phdr(PT_DYNAMIC, dynamic, dynamic, 72, PF_R | PF_W));
+            elf[0x1c..0x24].copy_from_slice(&[phoff.to_le_bytes(), [0; 4]].concat());
+            elf[0x2c..0x34].copy_from_slice(&[2, 0, 40, 0, 0, 0, 0, 0]);
+            return elf;
+        }
+
+        #[test]
+        fn relocate_stripped_pie() {
+            let data = stripped_pie();
+            let elf = ElfFile::parse(&data).unwrap();
+            assert!(elf.shdrs.is_empty());
+            let base = START_ADDR + 0x1000;
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            assert_eq!(Ok(base), load_elf(&mut core, &elf, base));
+            assert_eq!(Ok(base + 84), core.bus.memory.read(base + 92, 4));
+            assert_eq!(Ok(base + 88), core.bus.memory.read(base + 96, 4));
+            assert_eq!(Ok(base + 84), core.bus.memory.read(base + 100, 4));
+
+            // the jump slot's symbol is read through DT_SYMTAB
+            let mut data = stripped_pie();
+            data[104 + 16 + 14] = 0;
+            let elf = ElfFile::parse(&data).unwrap();
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            assert_eq!(Err(ElfError::Undefined("func".to_string())), load_elf(&mut core, &elf, base));
+        }
+
+        #[test]
+        fn relocation_errors() {
+            let mut data = pie();
+            data[144 + 12 + 4] = 58; // R_RISCV_IRELATIVE
+            let elf = ElfFile::parse(&data).unwrap();
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            let err = load_elf(&mut core, &elf, START_ADDR).unwrap_err();
+            assert_eq!(ElfError::Relocation(58, 96), err);
+            assert_eq!("Unsupported relocation type 58 at 0x00000060", err.to_string());
+
+            let mut data = pie();
+            data[104 + 16 + 14] = 0; // func undefined
+            let elf = ElfFile::parse(&data).unwrap();
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            assert_eq!(Err(ElfError::Undefined("func".to_string())), load_elf(&mut core, &elf, START_ADDR));
+
+            // relocations in an ET_EXEC are left to the linker
+            let mut data = pie();
+            data[0x10] = 2;
+            let elf = ElfFile::parse(&data).unwrap();
+            let mut core = init_with_memory(0, MEMSIZE);
+            assert_eq!(Ok(0), load_elf(&mut core, &elf, START_ADDR));
+            assert_eq!(Ok(0), core.bus.memory.read(92, 4));
+        }
+    }
+
+    mod dwarf_tests {
+        use crate::dwarf::*;
+
+        // lengths of the standard opcodes, as in DWARF 4
+        const OPCODE_LENGTHS: [u8; 12] = [0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1];
+
+        fn unit(version: u16, header: &[u8], program: &[u8]) -> Vec<u8> {
+            let mut body = version.to_le_bytes().to_vec();
+            if version >= 5 {
+                body.extend_from_slice(&[4, 0]);
+            }
+            let mut params = vec![1];
+            if version >= 4 {
+                params.push(1);
+            }
+            params.extend_from_slice(&[1, -5i8 as u8, 14, 13]);
+            params.extend_from_slice(&OPCODE_LENGTHS);
+            params.extend_from_slice(header);
+            body.extend_from_slice(&(params.len() as u32).to_le_bytes());
+            body.extend(params);
+            body.extend_from_slice(program);
+            let mut unit = (body.len() as u32).to_le_bytes().to_vec();
+            unit.extend(body);
+            return unit;
+        }
+
+        fn set_address(addr: u32) -> Vec<u8> {
+            let mut op = vec![0, 5, 2];
+            op.extend_from_slice(&addr.to_le_bytes());
+            return op;
+        }
+
+        fn v4() -> Vec<u8> {
+            let header = b"src\0\0main.c\0\x01\0\0util.h\0\0\0\0\0";
+            let mut program = set_address(0x8000_0000);
+            program.extend_from_slice(&[
+                3, 9,       // advance_line to 10
+                1,          // copy
+                131,        // special: address +8, line +1
+                4, 2,       // set_file util.h
+                3, 0x7a,    // advance_line -6
+                9, 4, 0,    // fixed_advance_pc 4
+                1,
+                2, 8,       // advance_pc 8
+                0, 1, 1     // end_sequence
+            ]);
+            return unit(4, header, &program);
+        }
+
+        fn at(file: &str, line: u32) -> Option<SourceLocation> {
+            return Some(SourceLocation { file: file.to_string(), line, column: 0 });
+        }
+
+        #[test]
+        fn line_program() {
+            let table = LineTable::parse(&v4(), &[], &[]).unwrap();
+            assert_eq!(at("src/main.c", 10), table.lookup(0x8000_0000));
+            assert_eq!(at("src/main.c", 10), table.lookup(0x8000_0007));
+            assert_eq!(at("src/main.c", 11), table.lookup(0x8000_0008));
+            assert_eq!(at("util.h", 5), table.lookup(0x8000_000c));
+            assert_eq!(at("util.h", 5), table.lookup(0x8000_0013));
+            assert_eq!(None, table.lookup(0x8000_0014));
+            assert_eq!(None, table.lookup(0x7fff_fffc));
+        }
+
+        #[test]
+        fn dwarf5_entry_formats() {
+            let mut header = vec![1, 1, 0x08, 2];
+            header.extend_from_slice(b"/build\0inc\0");
+            // path as line_strp, directory index and an MD5 to skip
+            header.extend_from_slice(&[3, 1, 0x1f, 2, 0x0b, 5, 0x1e, 1]);
+            header.extend_from_slice(&7u32.to_le_bytes());
+            header.push(1);
+            header.extend_from_slice(&[0xaa; 16]);
+            let mut program = set_address(0x8000_1000);
+            program.extend_from_slice(&[4, 0, 5, 3, 1, 2, 4, 0, 1, 1]);
+
+            let mut data = v4();
+            data.extend(unit(5, &header, &program));
+            let table = LineTable::parse(&data, b"main.c\0lib.c\0", &[]).unwrap();
+            assert_eq!(
+                Some(SourceLocation { file: "inc/lib.c".to_string(), line: 1, column: 3 }),
+                table.lookup(0x8000_1002)
+            );
+            assert_eq!(at("src/main.c", 11), table.lookup(0x8000_0008));
+        }
+
+        #[test]
+        fn malformed_line_table() {
+            let data = v4();
+            for len in 1..data.len() {
+                assert!(LineTable::parse(&data[..len], &[], &[]).is_err(), "prefix of {} bytes", len);
+            }
+            for i in 0..data.len() {
+                let mut bad = data.clone();
+                bad[i] ^= 0xff;
+                if let Ok(table) = LineTable::parse(&bad, &[], &[]) {
+                    let _ = table.lookup(0x8000_0008);
+                }
+            }
+
+            // advance_line by i64::MAX twice wraps instead of panicking
+            let mut program = set_address(0x8000_0000);
+            for _ in 0..2 {
+                program.push(3);
+                program.extend_from_slice(&[0xff; 9]);
+                program.push(0);
+            }
+            program.extend_from_slice(&[131, 2, 8, 0, 1, 1]);
+            let table = LineTable::parse(&unit(4, b"\0main.c\0\0\0\0\0", &program), &[], &[]).unwrap();
+            assert!(table.lookup(0x8000_000c).is_some());
+        }
+    }
+
+    mod loader_tests {
+        use crate::init_with_memory;
+        use crate::constants::*;
+        use crate::loader::*;
+
+        // addi x1, x0, 10; addi x2, x1, 1; ebreak, entered at the second
+        const CODE: [u8; 12] = [0x93, 0x00, 0xa0, 0x00, 0x13, 0x81, 0x10, 0x00, 0x73, 0x00, 0x10, 0x00];
+
+        const HEX: &str = "\
+:0200000480007A
+:0C0000009300A00013811000730010009A
+:040000058000000473
+:00000001FF
+";
+
+        const SREC: &str = "\
+S0090000702E73726563AB
+S311800000009300A000138110007300100014
+S7058000000476
+";
+
+        fn image() -> Image {
+            return Image { chunks: vec![(START_ADDR, CODE.to_vec())], entry: Some(START_ADDR + 4) };
+        }
+
+        #[test]
+        fn intel_hex() {
+            assert_eq!(Ok(image()), parse_ihex(HEX));
+            let bad = HEX.replace(":0C0000009300A00013811000730010009A", ":0C0000009300A00013811000730010009B");
+            assert_eq!(Err(LoadError::Record(2, "checksum mismatch".to_string())), parse_ihex(&bad));
+            assert!(matches!(parse_ihex(":0200000480007A\n"), Err(LoadError::Record(1, _))));
+            assert!(parse_ihex(":0C00000093\n").is_err());
+            // 16-bit segment addressing
+            let segment = ":020000021000EC\n:01000400AA51\n:00000001FF\n";
+            assert_eq!(vec![(0x10004, vec![0xaa])], parse_ihex(segment).unwrap().chunks);
+        }
+
+        #[test]
+        fn s_records() {
+            assert_eq!(Ok(image()), parse_srec(SREC));
+            let bad = SREC.replace("S7058000000476", "S7058000000477");
+            assert_eq!(Err(LoadError::Record(3, "checksum mismatch".to_string())), parse_srec(&bad));
+            // S1 data with a 16-bit address and a record count
+            let short = "S1050010AABB85\nS5030001FB\nS9030000FC\n";
+            assert_eq!(Ok(Image { chunks: vec![(0x10, vec![0xaa, 0xbb])], entry: Some(0) }), parse_srec(short));
+            let miscounted = "S1050010AABB85\nS5030002FA\n";
+            assert!(matches!(parse_srec(miscounted), Err(LoadError::Record(2, _))));
+            assert!(parse_srec("S\u{e9}00\n").is_err());
+        }
+
+        #[test]
+        fn detect_format() {
+            assert_eq!(Format::IntelHex, Format::detect(HEX.as_bytes()));
+            assert_eq!(Format::Srec, Format::detect(SREC.as_bytes()));
+            assert_eq!(Format::Elf, Format::detect(b"\x7fELF\x01\x01\x01"));
+            assert_eq!(Format::Binary, Format::detect(&CODE));
+            assert_eq!(Format::Binary, Format::detect(b""));
+            assert_eq!(Format::Binary, Format::detect(b"Some notes\n"));
+        }
+
+        #[test]
+        fn load_every_format() {
+            for (data, format) in [(HEX.as_bytes(), Format::IntelHex), (SREC.as_bytes(), Format::Srec)].iter() {
+                let mut core = init_with_memory(START_ADDR, MEMSIZE);
+                assert_eq!(Ok(START_ADDR + 4), load_program(&mut core, data, *format, START_ADDR));
+                assert_eq!(Ok(0x00108113), core.bus.memory.read(START_ADDR + 4, 4));
+            }
+
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            let addr = START_ADDR + 0x100;
+            assert_eq!(Ok(addr), load_program(&mut core, &CODE, Format::Binary, addr));
+            assert_eq!(Ok(0x00a00093), core.bus.memory.read(addr, 4));
+            assert_eq!(Err(LoadError::DoesNotFit(0)), load_program(&mut core, &CODE, Format::Binary, 0));
+        }
+
+        #[test]
+        fn initrd_placement() {
+            let mut core = init_with_memory(START_ADDR, 64 << 20);
+            let start = START_ADDR + (32 << 20);
+            assert_eq!(Ok((start, start + 12)), load_initrd(&mut core, &CODE, None));
+            assert_eq!(Ok(0x00a00093), core.bus.memory.read(start, 4));
+
+            let mut core = init_with_memory(START_ADDR, 1 << 30);
+            let start = START_ADDR + (128 << 20);
+            assert_eq!(Ok((start, start + 12)), load_initrd(&mut core, &CODE, None));
+            assert_eq!(Ok((START_ADDR, START_ADDR + 12)), load_initrd(&mut core, &CODE, Some(START_ADDR)));
+            assert_eq!(Err(LoadError::DoesNotFit(0x1000)), load_initrd(&mut core, &CODE, Some(0x1000)));
+            // up to the top of the address space, where the end doesn't fit
+            let mut core = init_with_memory(START_ADDR, 2 << 30);
+            assert_eq!(Err(LoadError::DoesNotFit(0xffff_fff4)), load_initrd(&mut core, &CODE, Some(0xffff_fff4)));
+        }
+    }
+
+    mod user_tests {
+        use crate::init_with_memory;
+        use crate::constants::*;
+        use crate::elf::*;
+        use crate::memory::Memory;
+        use crate::user::*;
+        use super::elf_tests::tiny;
+
+        fn string(memory: &Memory, addr: u32) -> String {
+            let mut s = Vec::new();
+            while let Ok(b) = memory.read(addr + s.len() as u32, 1) {
+                if b == 0 {
+                    break;
+                }
+                s.push(b as u8);
+            }
+            return String::from_utf8(s).unwrap();
+        }
+
+        #[test]
+        fn initial_stack() {
+            let mut data = tiny();
+            // load the file from the start, headers included
+            data[52+4] = 0;
+            data[52+0x10] = 92;
+            data[52+0x14] = 92;
+            let elf = ElfFile::parse(&data).unwrap();
+            assert_eq!(Some(START_ADDR + 52), phdr_addr(&elf));
+
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            load_elf(&mut core, &elf, START_ADDR).unwrap();
+            let argv = vec!["prog".to_string(), "-v".to_string()];
+            let envp = vec!["HOME=/".to_string()];
+            let random: Vec<u8> = (1..=16).collect();
+            let mut bytes = [0; 16];
+            bytes.copy_from_slice(&random);
+            let sp = init_stack(&mut core, &elf, 0, &argv, &envp, bytes).unwrap();
+
+            assert_eq!(0, sp & 0xf);
+            assert_eq!(sp as i32, core.regs[2]);
+            assert_eq!(START_ADDR as i32, core.regs[32]);
+            let memory = &core.bus.memory;
+            let word = |i: u32| memory.read(sp + 4*i, 4).unwrap();
+            assert_eq!(2, word(0));
+            assert_eq!("prog", string(memory, word(1)));
+            assert_eq!("-v", string(memory, word(2)));
+            assert_eq!(0, word(3));
+            assert_eq!("HOME=/", string(memory, word(4)));
+            assert_eq!(0, word(5));
+
+            let mut auxv = Vec::new();
+            let mut i = 6;
+            while word(i) != AT_NULL {
+                auxv.push((word(i), word(i+1)));
+                i += 2;
+            }
+            let at = |key: u32| auxv.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+            assert_eq!(Some(START_ADDR + 52), at(AT_PHDR));
+            assert_eq!(Some(32), at(AT_PHENT));
+            assert_eq!(Some(1), at(AT_PHNUM));
+            assert_eq!(Some(4096), at(AT_PAGESZ));
+            assert_eq!(Some(START_ADDR), at(AT_ENTRY));
+            let mut stored = [0; 16];
+            memory.read_slice(at(AT_RANDOM).unwrap(), &mut stored).unwrap();
+            assert_eq!(random, stored.to_vec());
+            assert!(at(AT_RANDOM).unwrap() as usize + 16 <= START_ADDR as usize + MEMSIZE);
+        }
+    }
+
+    mod coredump_tests {
+        use crate::{init_with_memory, run};
+        use crate::constants::*;
+        use crate::coredump::*;
+        use crate::elf::*;
+        use crate::ins::*;
+
+        #[test]
+        fn core_file() {
+            let mut core = init_with_memory(START_ADDR, 64 * 1024);
+            core.bus.memory.load(START_ADDR, &[0x93, 0x00, 0xa0, 0x00]);
+            core.bus.memory.load(START_ADDR + 0x1000, &[1]);
+            core.bus.memory.load(START_ADDR + 0x8000, &[2]);
+            core.bus.add_rom(BOOT_ROM_BASE, vec![0x73, 0x00, 0x10, 0x00]);
+            for i in 1..32 {
+                core.regs[i] = i as i32 * 0x10;
+            }
+            let dump = core_dump(&core, signal(causes::BREAKPOINT), START_ADDR + 4);
+
+            let elf = ElfFile::parse(&dump).unwrap();
+            assert_eq!(ET_CORE, elf.header.e_type);
+            let types: Vec<u32> = elf.phdrs.iter().map(|ph| ph.p_type).collect();
+            assert_eq!(vec![PT_NOTE, PT_LOAD, PT_LOAD, PT_LOAD], types);
+            let loads: Vec<(u32, u32)> = elf.phdrs[1..].iter().map(|ph| (ph.p_vaddr, ph.p_filesz)).collect();
+            assert_eq!(vec![(BOOT_ROM_BASE, 0x1000), (START_ADDR, 0x2000), (START_ADDR + 0x8000, 0x1000)], loads);
+            let ram = elf.segment_data(&elf.phdrs[2]).unwrap();
+            assert_eq!(&[0x93, 0x00, 0xa0, 0x00], &ram[..4]);
+            assert_eq!(1, ram[0x1000]);
+            assert_eq!(0, elf.phdrs[2].p_offset & 0xfff);
+
+            let note = elf.segment_data(&elf.phdrs[0]).unwrap();
+            let word = |i: usize| u32::from_le_bytes([note[i], note[i+1], note[i+2], note[i+3]]);
+            assert_eq!((5, PRSTATUS_SIZE as u32, NT_PRSTATUS), (word(0), word(4), word(8)));
+            assert_eq!(b"CORE\0", &note[12..17]);
+            let desc = &note[20..];
+            let desc_word = |i: usize| word(20 + i);
+            assert_eq!(PRSTATUS_SIZE, desc.len());
+            assert_eq!(SIGTRAP, desc_word(0));
+            assert_eq!(START_ADDR + 4, desc_word(72));
+            assert_eq!(0x10, desc_word(76));
+            assert_eq!(0x1f0, desc_word(72 + 31*4));
+        }
+
+        #[test]
+        fn fault_in_zeroed_vector() {
+            let mut core = init_with_memory(START_ADDR, 64 * 1024);
+            core.regs[32] = START_ADDR as i32;
+            core.csrs[csrs::MTVEC] = (START_ADDR + 0x100) as i32;
+            core.bus.memory.load(START_ADDR, &ebreak().to_le_bytes());
+            assert_eq!(1, run(&mut core));
+            let (cause, pc) = core.fault.unwrap();
+            // the core shows the original trap, not the one in the vector
+            assert_eq!((causes::BREAKPOINT, START_ADDR), (cause, pc));
+
+            let dump = core_dump(&core, signal(cause), pc);
+            let elf = ElfFile::parse(&dump).unwrap();
+            let note = elf.segment_data(&elf.phdrs[0]).unwrap();
+            let word = |i: usize| u32::from_le_bytes([note[i], note[i+1], note[i+2], note[i+3]]);
+            assert_eq!((SIGTRAP, START_ADDR), (word(20), word(92)));
+        }
+    }
+
+    mod elfwriter_tests {
+        use crate::{init_with_memory, step};
+        use crate::constants::*;
+        use crate::elf::*;
+        use crate::elfwriter::*;
+        use crate::ins::*;
+        use crate::loader::*;
+        use crate::symbols::*;
+
+        #[test]
+        fn write_and_load() {
+            let data_addr = START_ADDR + 0x2000;
+            let mut writer = ElfWriter::new(START_ADDR);
+            writer.text(&[addi(1, 0, 10)]);
+            let start = writer.here();
+            writer.text(&[lui(2, (data_addr >> 12) as i32), lw(3, 0, 2), add(4, 3, 1), ebreak()])
+                .data(data_addr, &[5, 0, 0, 0])
+                .entry(start)
+                .symbol("_start", start, 16, STT_FUNC)
+                .symbol("value", data_addr, 4, STT_OBJECT);
+            let bytes = writer.to_bytes();
+
+            let elf = ElfFile::parse(&bytes).unwrap();
+            assert_eq!(ET_EXEC, elf.header.e_type);
+            assert_eq!(START_ADDR + 4, elf.header.e_entry);
+            let loads: Vec<(u32, u32, u32)> = elf.phdrs.iter().map(|ph| (ph.p_vaddr, ph.p_filesz, ph.p_flags)).collect();
+            assert_eq!(vec![(START_ADDR, 20, PF_R | PF_X), (data_addr, 4, PF_R | PF_W)], loads);
+            assert_eq!(Some(SHT_PROGBITS), elf.section(".text").map(|sh| sh.sh_type));
+            assert_eq!(Some(start), get_symbol(&elf, "_start"));
+            let symbols = elf.symbols().unwrap();
+            let value = symbols.lookup("value").unwrap();
+            assert_eq!((STT_OBJECT, 2), (value.kind(), value.shndx));
+
+            let mut core = init_with_memory(START_ADDR, MEMSIZE);
+            assert_eq!(Ok(start), load_program(&mut core, &bytes, Format::Elf, START_ADDR));
+            core.regs[32] = start as i32;
+            for _ in 0..3 {
+                step(&mut core);
+            }
+            // the first instruction was skipped
+            assert_eq!(0, core.regs[1]);
+            assert_eq!(5, core.regs[4]);
+            assert_eq!(Some("_start+0x8".to_string()), core.symbols.describe(start + 8));
+        }
+
+        #[test]
+        fn text_only() {
+            let mut writer = ElfWriter::new(START_ADDR + 0x10);
+            writer.text(&[nop(), ecall()])
+                .symbol("_start", START_ADDR + 0x10, 8, STT_FUNC);
+            let bytes = writer.to_bytes();
+            let elf = ElfFile::parse(&bytes).unwrap();
+            assert_eq!(1, elf.phdrs.len());
+            assert_eq!(0x10, elf.phdrs[0].p_offset & 0xfff);
+            assert_eq!(None, elf.section(".data"));
+            let names: Vec<&str> = elf.shdrs.iter().map(|sh| sh.name.as_str()).collect();
+            assert_eq!(vec!["", ".text", ".symtab", ".strtab", ".shstrtab"], names);
+            assert_eq!(Ok(&[0x13, 0, 0, 0, 0x73, 0, 0, 0][..]), elf.segment_data(&elf.phdrs[0]));
+            // .symtab links to .strtab at its new index
+            assert_eq!(Some(START_ADDR + 0x10), get_symbol(&elf, "_start"));
+        }
+    }
+
+    mod syscall_tests {
+        use std::env;
+        use std::fs;
+        use std::io;
+        use std::process;
+        use super::device_tests::Sink;
+        use crate::{init_with_memory, step, Core};
+        use crate::constants::*;
+        use crate::devices::rtc::*;
+        use crate::elf::Segment;
+        use crate::ins::*;
+        use crate::rng::Rng;
+        use crate::syscalls::*;
+
+        const BUF: u32 = START_ADDR + 0x1000;
+
+        // a core with the program taking its first page and sp at the top
+        fn user_core(input: &[u8], output: &Sink) -> Core {
+            let mut core = init_with_memory(START_ADDR, 1 << 20);
+            core.segments.push(Segment { base: START_ADDR, size: 0x100, perms: 0 });
+            core.regs[2] = (START_ADDR + (1 << 20) - 16) as i32;
+            core.regs[32] = START_ADDR as i32;
+            let input = Box::new(io::Cursor::new(input.to_vec()));
+            let syscalls = Syscalls::with_streams(&mut core, Rng::new(1), input, Box::new(output.clone()), Box::new(io::sink()));
+            core.syscalls = Some(syscalls);
+            return core;
+        }
+
+        // run one ECALL at the pc, returns a0
+        fn syscall(core: &mut Core, number: u32, args: &[u32]) -> i32 {
+            for (i, arg) in args.iter().enumerate() {
+                core.regs[10 + i] = *arg as i32;
+            }
+            core.regs[17] = number as i32;
+            let pc = core.regs[32] as u32;
+            core.bus.memory.load(pc, &ecall().to_le_bytes());
+            step(core);
+            assert_eq!(pc + 4, core.regs[32] as u32);
+            return core.regs[10];
+        }
+
+        fn cstr(core: &mut Core, addr: u32, s: &str) {
+            core.bus.memory.load(addr, s.as_bytes());
+            core.bus.memory.load(addr + s.len() as u32, &[0]);
+        }
+
+        fn bytes(core: &Core, addr: u32, len: usize) -> Vec<u8> {
+            let mut data = vec![0; len];
+            core.bus.memory.read_slice(addr, &mut data).unwrap();
+            return data;
+        }
+
+        #[test]
+        fn console_io() {
+            let out = Sink::new();
+            let mut core = user_core(b"abc", &out);
+            core.bus.memory.load(BUF, b"hello");
+            assert_eq!(5, syscall(&mut core, SYS_WRITE, &[1, BUF, 5]));
+            assert_eq!(3, syscall(&mut core, SYS_READ, &[0, BUF, 8]));
+            assert_eq!(b"abclo".to_vec(), bytes(&core, BUF, 5));
+            assert_eq!(0, syscall(&mut core, SYS_READ, &[0, BUF, 8]));
+
+            // writev of "ab" and "lo"
+            for (i, v) in [BUF, 2, BUF + 3, 2].iter().enumerate() {
+                core.bus.memory.load(BUF + 0x100 + 4*i as u32, &v.to_le_bytes());
+            }
+            assert_eq!(4, syscall(&mut core, SYS_WRITEV, &[1, BUF + 0x100, 2]));
+            assert_eq!(b"helloablo".to_vec(), *out.0.borrow());
+            assert_eq!(-EINVAL, syscall(&mut core, SYS_WRITEV, &[1, BUF + 0x100, 1025]));
+
+            assert_eq!(-EBADF, syscall(&mut core, SYS_WRITE, &[7, BUF, 1]));
+            assert_eq!(-EBADF, syscall(&mut core, SYS_READ, &[1, BUF, 1]));
+            assert_eq!(-EFAULT, syscall(&mut core, SYS_WRITE, &[1, 0x1000, 1]));
+            assert_eq!(-ENOTTY, syscall(&mut core, SYS_IOCTL, &[1, 0x5413, BUF]));
+            assert_eq!(-ENOSYS, syscall(&mut core, 1000, &[]));
+        }
+
+        #[test]
+        fn host_files() {
+            let out = Sink::new();
+            let mut core = user_core(b"", &out);
+            let path = env::temp_dir().join(format!("rustv-syscall-file-{}", process::id()));
+            let path = path.to_str().unwrap();
+            let _ = fs::remove_file(path);
+            cstr(&mut core, BUF, path);
+            let data = BUF + 0x200;
+            core.bus.memory.load(data, b"hello world");
+
+            // O_WRONLY | O_CREAT | O_TRUNC
+            let fd = syscall(&mut core, SYS_OPENAT, &[AT_FDCWD as u32, BUF, 0o1101, 0o644]);
+            assert_eq!(3, fd);
+            assert_eq!(11, syscall(&mut core, SYS_WRITE, &[3, data, 11]));
+            assert_eq!(0, syscall(&mut core, SYS_CLOSE, &[3]));
+            assert_eq!(-EBADF, syscall(&mut core, SYS_CLOSE, &[3]));
+            assert_eq!(b"hello world".to_vec(), fs::read(path).unwrap());
+            // O_CREAT | O_EXCL
+            assert_eq!(-EEXIST, syscall(&mut core, SYS_OPENAT, &[AT_FDCWD as u32, BUF, 0o301, 0o644]));
+
+            assert_eq!(3, syscall(&mut core, SYS_OPENAT, &[AT_FDCWD as u32, BUF, 0, 0]));
+            let result = BUF + 0x300;
+            assert_eq!(0, syscall(&mut core, SYS_LSEEK, &[3, 0, 6, result, 0]));
+            assert_eq!(6u64.to_le_bytes().to_vec(), bytes(&core, result, 8));
+            assert_eq!(5, syscall(&mut core, SYS_READ, &[3, data, 16]));
+            assert_eq!(b"world".to_vec(), bytes(&core, data, 5));
+            assert_eq!(-ESPIPE, syscall(&mut core, SYS_LSEEK, &[1, 0, 0, result, 0]));
+
+            let stat = BUF + 0x400;
+            assert_eq!(0, syscall(&mut core, SYS_FSTAT, &[3, stat]));
+            let word = |core: &Core, at: u32| core.bus.memory.read(at, 4).unwrap();
+            assert_eq!(0o100644, word(&core, stat + 16));
+            assert_eq!(11, word(&core, stat + 48));
+            assert_eq!(0, syscall(&mut core, SYS_FSTAT, &[1, stat]));
+            assert_eq!(0o020620, word(&core, stat + 16));
+            // musl's fstat
+            cstr(&mut core, BUF + 0x3f0, "");
+            assert_eq!(0, syscall(&mut core, SYS_STATX, &[3, BUF + 0x3f0, AT_EMPTY_PATH, 0x7ff, stat]));
+            assert_eq!(0o100644, word(&core, stat + 28) & 0xffff);
+            assert_eq!(11, word(&core, stat + 40));
+
+            // the file's pages, zero past its end
+            let map = syscall(&mut core, SYS_MMAP, &[0, 0x1000, 1, 2, 3, 0]) as u32;
+            assert_eq!(b"hello world\0".to_vec(), bytes(&core, map, 12));
+            assert_eq!(-EACCES, syscall(&mut core, SYS_MMAP, &[0, 0x1000, 1, 2, 1, 0]));
+
+            fs::remove_file(path).unwrap();
+            assert_eq!(-ENOENT, syscall(&mut core, SYS_OPENAT, &[AT_FDCWD as u32, BUF, 0, 0]));
+            assert_eq!(-EFAULT, syscall(&mut core, SYS_OPENAT, &[AT_FDCWD as u32, 0x1000, 0, 0]));
+        }
+
+        #[test]
+        fn heap_and_mappings() {
+            let out = Sink::new();
+            let mut core = user_core(b"", &out);
+            let brk = syscall(&mut core, SYS_BRK, &[0]) as u32;
+            assert_eq!(START_ADDR + 0x1000, brk);
+            core.bus.memory.load(brk, &[0xff; 16]);
+            assert_eq!(brk + 0x2000, syscall(&mut core, SYS_BRK, &[brk + 0x2000]) as u32);
+            assert_eq!(vec![0; 16], bytes(&core, brk, 16));
+            // RAM is 1M, the top 128K is left to the stack
+            let top = START_ADDR + (1 << 20) - (128 << 10);
+            let anon = 0x22;
+            let a = syscall(&mut core, SYS_MMAP, &[0, 5000, 3, anon, u32::MAX, 0]) as u32;
+            assert_eq!(top - 0x2000, a);
+            let b = syscall(&mut core, SYS_MMAP, &[0, 0x1000, 3, anon, u32::MAX, 0]) as u32;
+            assert_eq!(a - 0x1000, b);
+            assert_eq!(0, syscall(&mut core, SYS_MUNMAP, &[a, 5000]));
+            assert_eq!(a, syscall(&mut core, SYS_MMAP, &[0, 0x2000, 3, anon, u32::MAX, 0]) as u32);
+            assert_eq!(-EINVAL, syscall(&mut core, SYS_MMAP, &[0, 0, 3, anon, u32::MAX, 0]));
+            assert_eq!(-ENOMEM, syscall(&mut core, SYS_MMAP, &[0, 1 << 20, 3, anon, u32::MAX, 0]));
+            // MAP_FIXED over part of the heap's way, zeroed
+            core.bus.memory.load(b - 0x1000, &[0xff; 16]);
+            assert_eq!(b - 0x1000, syscall(&mut core, SYS_MMAP, &[b - 0x1000, 0x1000, 3, anon | MAP_FIXED, u32::MAX, 0]) as u32);
+            assert_eq!(vec![0; 16], bytes(&core, b - 0x1000, 16));
+            // a failing MAP_FIXED keeps what was mapped there
+            core.bus.memory.load(b - 0x1000, &[0xff; 16]);
+            assert_eq!(-EBADF, syscall(&mut core, SYS_MMAP, &[b - 0x1000, 0x1000, 3, MAP_FIXED | 2, 7, 0]));
+            assert_eq!(vec![0xff; 16], bytes(&core, b - 0x1000, 16));
+            // the break stops at the lowest mapping
+            assert_eq!(brk + 0x2000, syscall(&mut core, SYS_BRK, &[b]) as u32);
+            assert_eq!(b - 0x1000, syscall(&mut core, SYS_BRK, &[b - 0x1000]) as u32);
+        }
+
+        #[test]
+        fn time_and_system() {
+            let out = Sink::new();
+            let mut core = user_core(b"", &out);
+            core.bus.add_device(RTC_BASE, RTC_SIZE, RTC_IRQ, Box::new(Rtc::new(Clock::Virtual)));
+            // the clock starts when the system calls are set up
+            core.syscalls = Some(Syscalls::with_streams(&mut core, Rng::new(1), Box::new(io::empty()), Box::new(io::sink()), Box::new(io::sink())));
+            assert_eq!(0, syscall(&mut core, SYS_CLOCK_GETTIME64, &[0, BUF]));
+            let secs = core.bus.memory.read(BUF, 4).unwrap() as u64 | (core.bus.memory.read(BUF + 4, 4).unwrap() as u64) << 32;
+            assert_eq!(VIRTUAL_EPOCH / 1_000_000_000, secs);
+            assert_eq!(0, syscall(&mut core, SYS_CLOCK_GETTIME, &[1, BUF]));
+            assert_eq!(0, core.bus.memory.read(BUF, 4).unwrap());
+            assert_eq!(-EINVAL, syscall(&mut core, SYS_CLOCK_GETTIME64, &[99, BUF]));
+
+            assert_eq!(0, syscall(&mut core, SYS_UNAME, &[BUF]));
+            assert_eq!(b"Linux\0".to_vec(), bytes(&core, BUF, 6));
+            assert_eq!(b"riscv32\0".to_vec(), bytes(&core, BUF + 4*65, 8));
+
+            assert_eq!(16, syscall(&mut core, SYS_GETRANDOM, &[BUF, 16, 0]));
+            let mut expected = [0; 16];
+            Rng::new(1).fill(&mut expected);
+            assert_eq!(expected.to_vec(), bytes(&core, BUF, 16));
+        }
+
+        #[test]
+        fn exit_group() {
+            let out = Sink::new();
+            let mut core = user_core(b"", &out);
+            core.regs[10] = 0x107;
+            core.regs[17] = SYS_EXIT_GROUP as i32;
+            core.bus.memory.load(START_ADDR, &ecall().to_le_bytes());
+            assert!(step(&mut core));
+            assert_eq!(Some(7), core.exit_code);
+
+            // without system calls ECALL traps as before
+            core.syscalls = None;
+            core.exit_code = None;
+            core.regs[32] = START_ADDR as i32;
+            step(&mut core);
+            assert_eq!(causes::ECALL_M as i32, core.csrs[csrs::MCAUSE]);
+        }
+    }
+
+    mod semihosting_tests {
+        use std::env;
+        use std::fs;
+        use std::io;
+        use std::process;
+        use super::device_tests::Sink;
+        use crate::{init_with_memory, step, Core};
+        use crate::constants::*;
+        use crate::elf::Segment;
+        use crate::ins::*;
+        use crate::semihosting::*;
+        use crate::syscalls::{EBADF, ENAMETOOLONG, ENOENT};
+
+        const BLOCK: u32 = START_ADDR + 0x1000;
+        const BUF: u32 = START_ADDR + 0x2000;
+
+        fn semihosting_core(root: &str, input: &[u8], output: &Sink) -> Core {
+            let mut core = init_with_memory(START_ADDR, 1 << 20);
+            core.segments.push(Segment { base: START_ADDR, size: 0x100, perms: 0 });
+            let input = Box::new(io::Cursor::new(input.to_vec()));
+            let semihosting = Semihosting::with_streams(&mut core, root, "prog a b".to_string(), input, Box::new(output.clone()), Box::new(io::sink()));
+            core.semihosting = Some(semihosting);
+            let call = [slli(0, 0, 0x1f), ebreak(), srai(0, 0, 7)];
+            for (i, ins) in call.iter().enumerate() {
+                core.bus.memory.load(START_ADDR + 4*i as u32, &ins.to_le_bytes());
+            }
+            return core;
+        }
+
+        // run the EBREAK between the markers with a parameter block, returns a0
+        fn call(core: &mut Core, op: u32, block: &[u32]) -> i32 {
+            for (i, word) in block.iter().enumerate() {
+                core.bus.memory.load(BLOCK + 4*i as u32, &word.to_le_bytes());
+            }
+            core.regs[10] = op as i32;
+            core.regs[11] = BLOCK as i32;
+            core.regs[32] = START_ADDR as i32 + 4;
+            step(core);
+            assert_eq!(START_ADDR + 8, core.regs[32] as u32);
+            return core.regs[10];
+        }
+
+        fn open(core: &mut Core, name: &str, mode: u32) -> i32 {
+            core.bus.memory.load(BUF + 0x800, name.as_bytes());
+            return call(core, SYS_OPEN, &[BUF + 0x800, mode, name.len() as u32]);
+        }
+
+        fn bytes(core: &Core, addr: u32, len: usize) -> Vec<u8> {
+            let mut data = vec![0; len];
+            core.bus.memory.read_slice(addr, &mut data).unwrap();
+            return data;
+        }
+
+        #[test]
+        fn console() {
+            let out = Sink::new();
+            let mut core = semihosting_core(".", b"abc", &out);
+            let stdin = open(&mut core, ":tt", 0);
+            let stdout = open(&mut core, ":tt", 4);
+            assert_eq!((1, 2), (stdin, stdout));
+            core.bus.memory.load(BUF, b"hello");
+            assert_eq!(0, call(&mut core, SYS_WRITE, &[2, BUF, 5]));
+            assert_eq!(5, call(&mut core, SYS_READ, &[1, BUF, 8]));
+            assert_eq!(b"abclo".to_vec(), bytes(&core, BUF, 5));
+            assert_eq!(1, call(&mut core, SYS_ISTTY, &[2]));
+            assert_eq!(0, call(&mut core, SYS_CLOSE, &[1]));
+            assert_eq!(-1, call(&mut core, SYS_READ, &[1, BUF, 8]));
+            assert_eq!(EBADF, call(&mut core, SYS_ERRNO, &[]));
+
+            core.bus.memory.load(BUF, b"!\0");
+            core.regs[10] = SYS_WRITE0 as i32;
+            core.regs[11] = BUF as i32;
+            core.regs[32] = START_ADDR as i32 + 4;
+            step(&mut core);
+            assert_eq!(b"hello!".to_vec(), *out.0.borrow());
+        }
+
+        #[test]
+        fn host_files() {
+            let out = Sink::new();
+            let root = env::temp_dir().join(format!("rustv-semihosting-{}", process::id()));
+            let _ = fs::remove_dir_all(&root);
+            fs::create_dir_all(root.join("sub")).unwrap();
+            let mut core = semihosting_core(root.to_str().unwrap(), b"", &out);
+
+            // "wb"
+            let handle = open(&mut core, "sub/file", 5) as u32;
+            assert_eq!(1, handle);
+            core.bus.memory.load(BUF, b"hello world");
+            assert_eq!(0, call(&mut core, SYS_WRITE, &[handle, BUF, 11]));
+            assert_eq!(0, call(&mut core, SYS_CLOSE, &[handle]));
+            assert_eq!(b"hello world".to_vec(), fs::read(root.join("sub/file")).unwrap());
+
+            // "rb"
+            let handle = open(&mut core, "/sub/file", 1) as u32;
+            assert_eq!(1, handle);
+            assert_eq!(11, call(&mut core, SYS_FLEN, &[handle]));
+            assert_eq!(0, call(&mut core, SYS_ISTTY, &[handle]));
+            assert_eq!(0, call(&mut core, SYS_SEEK, &[handle, 6]));
+            assert_eq!(11, call(&mut core, SYS_READ, &[handle, BUF, 16]));
+            assert_eq!(b"world".to_vec(), bytes(&core, BUF, 5));
+            assert_eq!(16, call(&mut core, SYS_READ, &[handle, BUF, 16]));
+
+            // nothing outside the root
+            assert_eq!(-1, open(&mut core, &format!("../rustv-semihosting-{}/sub/file", process::id()), 1));
+            assert_eq!(ENOENT, call(&mut core, SYS_ERRNO, &[]));
+            assert_eq!(-1, open(&mut core, "missing", 1));
+            // not even through a dangling link, which "w" would create
+            let outside = env::temp_dir().join(format!("rustv-semihosting-outside-{}", process::id()));
+            let _ = fs::remove_file(&outside);
+            std::os::unix::fs::symlink(&outside, root.join("sub/link")).unwrap();
+            assert_eq!(-1, open(&mut core, "sub/link", 4));
+            assert!(!outside.exists());
+            assert_eq!(-1, call(&mut core, SYS_OPEN, &[BUF, 0, 0xffff_ffff]));
+            assert_eq!(ENAMETOOLONG, call(&mut core, SYS_ERRNO, &[]));
+            let features = open(&mut core, ":semihosting-features", 0) as u32;
+            assert_eq!(2, features);
+            assert_eq!(0, call(&mut core, SYS_READ, &[features, BUF, 5]));
+            assert_eq!(b"SHFB\x03".to_vec(), bytes(&core, BUF, 5));
+            fs::remove_dir_all(&root).unwrap();
+        }
+
+        #[test]
+        fn cmdline_heap_and_time() {
+            let out = Sink::new();
+            let mut core = semihosting_core(".", b"", &out);
+            assert_eq!(0, call(&mut core, SYS_GET_CMDLINE, &[BUF, 64]));
+            assert_eq!(b"prog a b\0".to_vec(), bytes(&core, BUF, 9));
+            assert_eq!(8, core.bus.memory.read(BLOCK + 4, 4).unwrap());
+            assert_eq!(-1, call(&mut core, SYS_GET_CMDLINE, &[BUF, 8]));
+
+            assert_eq!(0, call(&mut core, SYS_HEAPINFO, &[BUF]));
+            let word = |core: &Core, i: u32| core.bus.memory.read(BUF + 4*i, 4).unwrap();
+            let top = START_ADDR + (1 << 20);
+            assert_eq!(START_ADDR + 0x1000, word(&core, 0));
+            assert_eq!(top - (128 << 10), word(&core, 1));
+            assert_eq!(top, word(&core, 2));
+            assert_eq!(word(&core, 1), word(&core, 3));
+
+            assert!(call(&mut core, SYS_CLOCK, &[]) >= 0);
+            assert!(call(&mut core, SYS_TIME, &[]) > 0);
+        }
+
+        #[test]
+        fn exit() {
+            let out = Sink::new();
+            let mut core = semihosting_core(".", b"", &out);
+            core.regs[10] = SYS_EXIT_EXTENDED as i32;
+            core.regs[11] = BLOCK as i32;
+            core.regs[32] = START_ADDR as i32 + 4;
+            core.bus.memory.load(BLOCK, &ADP_STOPPED_APPLICATION_EXIT.to_le_bytes());
+            core.bus.memory.load(BLOCK + 4, &3u32.to_le_bytes());
+            assert!(step(&mut core));
+            assert_eq!(Some(3), core.exit_code);
+
+            core.exit_code = None;
+            core.regs[10] = SYS_EXIT as i32;
+            core.regs[11] = 0x20023;
+            core.regs[32] = START_ADDR as i32 + 4;
+            assert!(step(&mut core));
+            assert_eq!(Some(1), core.exit_code);
+
+            // an EBREAK without the markers still traps
+            core.exit_code = None;
+            core.bus.memory.load(START_ADDR + 8, &ebreak().to_le_bytes());
+            core.regs[32] = START_ADDR as i32 + 8;
+            step(&mut core);
+            assert_eq!(causes::BREAKPOINT as i32, core.csrs[csrs::MCAUSE]);
+        }
+    }
 }
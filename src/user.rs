@@ -0,0 +1,101 @@
+use crate::Core;
+use crate::elf::*;
+use crate::loader::LoadError;
+use crate::memory::PAGE_SIZE;
+
+/*
+ * Linux user programs
+ *
+ * A user program starts at its ELF entry point with the psABI initial
+ * process stack at the top of RAM. From sp upwards:
+ *
+ *   argc
+ *   argv[0] .. argv[argc-1], NULL
+ *   envp[0] .. envp[n-1], NULL
+ *   auxv pairs, ending with AT_NULL
+ *   the argument and environment strings and the AT_RANDOM bytes
+ *
+ * sp is 16-byte aligned.
+ */
+
+pub const AT_NULL: u32 = 0;
+pub const AT_PHDR: u32 = 3;
+pub const AT_PHENT: u32 = 4;
+pub const AT_PHNUM: u32 = 5;
+pub const AT_PAGESZ: u32 = 6;
+pub const AT_ENTRY: u32 = 9;
+pub const AT_RANDOM: u32 = 25;
+
+/*
+ * Where the program headers are in memory: the PT_PHDR segment if there
+ * is one, otherwise wherever the loadable segment covering them put them.
+ */
+pub fn phdr_addr(elf: &ElfFile) -> Option<u32> {
+    if let Some(ph) = elf.phdrs.iter().find(|ph| ph.p_type == PT_PHDR) {
+        return Some(ph.p_vaddr);
+    }
+    let phoff = elf.header.e_phoff;
+    return elf.phdrs.iter()
+        .filter(|ph| ph.p_type == PT_LOAD)
+        .find(|ph| phoff >= ph.p_offset && phoff - ph.p_offset < ph.p_filesz)
+        .map(|ph| ph.p_vaddr + (phoff - ph.p_offset));
+}
+
+// addresses moved by the load bias of a position-independent program
+pub fn auxv(elf: &ElfFile, bias: u32, random: u32) -> Vec<(u32, u32)> {
+    let mut auxv = Vec::new();
+    if let Some(phdr) = phdr_addr(elf) {
+        auxv.push((AT_PHDR, phdr.wrapping_add(bias)));
+        auxv.push((AT_PHENT, elf.header.e_phentsize as u32));
+        auxv.push((AT_PHNUM, elf.header.e_phnum as u32));
+    }
+    auxv.push((AT_PAGESZ, PAGE_SIZE as u32));
+    auxv.push((AT_ENTRY, elf.header.e_entry.wrapping_add(bias)));
+    auxv.push((AT_RANDOM, random));
+    auxv.push((AT_NULL, 0));
+    return auxv;
+}
+
+/*
+ * Build the initial stack below the top of RAM and point sp at it and the
+ * pc at the entry point. Returns sp.
+ */
+pub fn init_stack(core: &mut Core, elf: &ElfFile, bias: u32, argv: &[String], envp: &[String], random: [u8; 16]) -> Result<u32, LoadError> {
+    let top = core.bus.ram_base() as u64 + core.bus.ram_size() as u64;
+
+    // strings and random bytes at the top, in the order they are listed
+    let mut strings = random.to_vec();
+    let mut offsets = Vec::new();
+    for s in argv.iter().chain(envp.iter()) {
+        offsets.push(strings.len() as u32);
+        strings.extend_from_slice(s.as_bytes());
+        strings.push(0);
+    }
+    let strings_addr = ((top - strings.len() as u64) & !0xf) as u32;
+    let random_addr = strings_addr;
+
+    let auxv = auxv(elf, bias, random_addr);
+    let words = 1 + argv.len() + 1 + envp.len() + 1 + 2*auxv.len();
+    let sp = (strings_addr as u64).checked_sub(4 * words as u64)
+        .map(|sp| sp & !0xf)
+        .filter(|sp| *sp >= core.bus.ram_base() as u64)
+        .ok_or(LoadError::DoesNotFit(strings_addr))? as u32;
+
+    let mut stack = vec![argv.len() as u32];
+    let pointers: Vec<u32> = offsets.iter().map(|o| strings_addr + o).collect();
+    stack.extend_from_slice(&pointers[..argv.len()]);
+    stack.push(0);
+    stack.extend_from_slice(&pointers[argv.len()..]);
+    stack.push(0);
+    for (key, value) in auxv.iter() {
+        stack.push(*key);
+        stack.push(*value);
+    }
+    let stack: Vec<u8> = stack.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect();
+
+    core.bus.memory.load(sp, &stack);
+    core.bus.memory.load(strings_addr, &strings);
+    core.regs[2] = sp as i32;
+    core.regs[32] = elf.header.e_entry.wrapping_add(bias) as i32;
+    return Ok(sp);
+}